@@ -0,0 +1,478 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Rendering split across processes. A coordinator hands out tiles of
+// an image over TCP to worker processes, which build the same scene
+// by name, render the tiles, and send back the resulting films.
+//
+// Each request from the coordinator is a job: the scene name, shading
+// mode, and render settings as length-prefixed strings, a byte saying
+// whether to collect AOVs, then the image size, pass, and tile bounds
+// as u32 values, all little-endian. The worker replies with a status
+// byte followed by either the number of camera rays it traced as a u64
+// and the tile's film, or an error message.
+//
+// Workers configure scenes from their own environment, so the settings
+// are those of the coordinator's render, and a worker whose own
+// settings differ refuses the job rather than send back tiles that
+// don't match the rest of the image.
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::render::{
+    RenderOptions,
+    Scene,
+    ShadingMode,
+    assemble_tiles,
+    render_region,
+    render_settings,
+};
+
+use crate::render::film::Film;
+use crate::render::tiles::{Progress, Tile};
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+const MAX_STRING_LEN: usize = 1024;
+
+// How long the coordinator waits on a worker before giving up on it and
+// handing its tile to someone else.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(600);
+
+struct Job {
+    scene_name: String,
+    shading: ShadingMode,
+    settings: String,
+    aovs: bool,
+    imgx: u32,
+    imgy: u32,
+    pass: u32,
+    tile: Tile,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn write_string<W: Write>(out: &mut W, value: &str) -> io::Result<()> {
+    write_u32(out, value.len() as u32)?;
+    out.write_all(value.as_bytes())
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = read_u32(input)? as usize;
+
+    if len > MAX_STRING_LEN {
+        return Err(invalid_data("String too long."));
+    }
+
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+
+    String::from_utf8(bytes).map_err(| _ | invalid_data("String is not UTF-8."))
+}
+
+impl Job {
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_string(out, &self.scene_name)?;
+        write_string(out, self.shading.name())?;
+        write_string(out, &self.settings)?;
+        out.write_all(&[self.aovs as u8])?;
+
+        for value in [self.imgx, self.imgy, self.pass,
+                      self.tile.x0, self.tile.y0, self.tile.x1, self.tile.y1].iter() {
+            write_u32(out, *value)?;
+        }
+
+        Ok(())
+    }
+
+    fn read<R: Read>(input: &mut R) -> io::Result<Job> {
+        let scene_name = read_string(input)?;
+        let shading = ShadingMode::from_name(&read_string(input)?)
+            .ok_or_else(|| invalid_data("Unknown shading mode."))?;
+        let settings = read_string(input)?;
+
+        let mut aovs = [0];
+        input.read_exact(&mut aovs)?;
+
+        if aovs[0] > 1 {
+            return Err(invalid_data("AOV flag is invalid."));
+        }
+
+        let imgx = read_u32(input)?;
+        let imgy = read_u32(input)?;
+        let pass = read_u32(input)?;
+
+        let tile = Tile {
+            x0: read_u32(input)?,
+            y0: read_u32(input)?,
+            x1: read_u32(input)?,
+            y1: read_u32(input)?,
+        };
+
+        if tile.x0 > tile.x1 || tile.y0 > tile.y1 || tile.x1 > imgx || tile.y1 > imgy {
+            return Err(invalid_data("Tile is outside the image."));
+        }
+
+        Ok(Job {
+            scene_name,
+            shading,
+            settings,
+            aovs: aovs[0] == 1,
+            imgx,
+            imgy,
+            pass,
+            tile,
+        })
+    }
+}
+
+// Serves jobs from one coordinator connection until it hangs up. The
+// most recently used scene is kept, since every job for an image asks
+// for the same one.
+fn serve_connection<F>(
+    stream: TcpStream, load_scene: &F, options: &mut RenderOptions, scene: &mut Option<Scene>
+) -> io::Result<()>
+where F: Fn(&str) -> Option<Scene> {
+
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+
+    loop {
+        let job = match Job::read(&mut input) {
+            Ok(job) => job,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err)
+        };
+
+        if scene.as_ref().map(| s | s.name) != Some(job.scene_name.as_str()) {
+            *scene = load_scene(&job.scene_name);
+        }
+
+        options.shading = job.shading;
+        options.aovs = job.aovs;
+
+        match scene {
+            Some(scene) if render_settings(scene, options) != job.settings => {
+                out.write_all(&[STATUS_ERROR])?;
+                write_string(&mut out, &format!("Worker settings differ: {}", render_settings(scene, options)))?;
+            },
+            Some(scene) => {
+                let (film, rays) = render_region(scene, job.imgx, job.imgy, options, &job.tile, job.pass);
+
+                out.write_all(&[STATUS_OK])?;
+                out.write_all(&rays.to_le_bytes())?;
+                film.write(&mut out)?;
+            },
+            None => {
+                out.write_all(&[STATUS_ERROR])?;
+                write_string(&mut out, &format!("Unknown scene: {}", job.scene_name))?;
+            }
+        }
+
+        out.flush()?;
+    }
+}
+
+// Runs a worker, listening on the given address and rendering tiles
+// for whichever coordinator connects. Scenes are built by load_scene,
+// which needs to configure them the same way as the coordinator does.
+pub fn serve<F>(address: &str, load_scene: F, mut options: RenderOptions) -> io::Result<()>
+where F: Fn(&str) -> Option<Scene> {
+
+    let listener = TcpListener::bind(address)?;
+    let mut scene = None;
+
+    println!("Worker listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(| stream | {
+            println!("Coordinator connected from {}", stream.peer_addr()?);
+            serve_connection(stream, &load_scene, &mut options, &mut scene)
+        });
+
+        if let Err(err) = result {
+            println!("Connection failed: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// Sends one job to a worker and waits for the film that comes back,
+// along with the number of camera rays the worker traced. The film has
+// to cover the tile plus the filter's margin, as a film rendered
+// locally would.
+fn remote_tile(stream: &TcpStream, job: &Job, margin: i64) -> io::Result<(Film, u64)> {
+    let mut out = BufWriter::new(stream);
+    job.write(&mut out)?;
+    out.flush()?;
+
+    let mut input = BufReader::new(stream);
+
+    let mut status = [0; 1];
+    input.read_exact(&mut status)?;
+
+    match status[0] {
+        STATUS_OK => {
+            let rays = read_u64(&mut input)?;
+            let film = Film::read(&mut input)?;
+
+            let tile = &job.tile;
+            let expected = (tile.x0 as i64 - margin, tile.y0 as i64 - margin,
+                            (tile.x1 - tile.x0) as usize + 2 * margin as usize,
+                            (tile.y1 - tile.y0) as usize + 2 * margin as usize);
+
+            if (film.x0(), film.y0(), film.width(), film.height()) != expected {
+                return Err(invalid_data("Film doesn't cover the tile."));
+            }
+
+            if film.has_aovs() != job.aovs {
+                return Err(invalid_data("Film AOVs don't match the job."));
+            }
+
+            Ok((film, rays))
+        },
+        STATUS_ERROR => Err(io::Error::other(read_string(&mut input)?)),
+        _ => Err(invalid_data("Unknown worker status."))
+    }
+}
+
+// Tiles waiting to be rendered, and the number out with workers.
+// Tiles whose workers fail go back on the queue, so the coordinator
+// only finishes once nothing is left anywhere.
+struct Schedule {
+    queue: VecDeque<(usize, Tile)>,
+    in_flight: usize,
+}
+
+// Renders a pass of an image across the workers at the given
+// addresses, seeded for that pass as by render_pass, and returns the
+// assembled film. Workers that can't be reached, fail, time out, or
+// send back the wrong film are dropped and their tiles rendered by the
+// others. If every worker is lost, the remaining tiles are rendered
+// locally. Statistics only cover tiles rendered locally.
+pub fn render_distributed(
+    scene: &Scene, imgx: u32, imgy: u32, options: &RenderOptions, workers: &[String], pass: u32
+) -> Film {
+
+    let settings = render_settings(scene, options);
+    let margin = scene.filter.radius().ceil() as i64;
+
+    let tiles = options.tile_order.tiles(imgx, imgy, options.tile_size);
+    let pixels_total: u64 = tiles.iter().map(Tile::pixel_count).sum();
+
+    let start = Instant::now();
+
+    let schedule = Mutex::new(Schedule {
+        queue: tiles.iter().copied().enumerate().collect(),
+        in_flight: 0,
+    });
+    let changed = Condvar::new();
+
+    let tile_films: Vec<Mutex<Option<Film>>> = tiles.iter().map(| _ | Mutex::new(None)).collect();
+    let done = Mutex::new((0, 0, 0));
+
+    let is_stopped = || options.stop.as_ref().is_some_and(| stop | stop.load(SeqCst));
+
+    let finish_tile = | idx: usize, (film, rays): (Film, u64) | {
+        *tile_films[idx].lock().unwrap() = Some(film);
+
+        let mut done = done.lock().unwrap();
+        done.0 += 1;
+        done.1 += tiles[idx].pixel_count();
+        done.2 += rays;
+
+        if let Some(report) = &options.progress {
+            report(&Progress {
+                tiles_done: done.0,
+                tiles_total: tiles.len(),
+                pixels_done: done.1,
+                pixels_total,
+                rays: done.2,
+                elapsed: start.elapsed(),
+            });
+        }
+    };
+
+    let run_worker = | address: &String | {
+        let stream = match TcpStream::connect(address) {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Cannot connect to worker {}: {}", address, err);
+                return;
+            }
+        };
+
+        stream.set_read_timeout(Some(WORKER_TIMEOUT)).ok();
+
+        loop {
+            let next = {
+                let mut schedule = schedule.lock().unwrap();
+
+                loop {
+                    if is_stopped() {
+                        break None;
+                    }
+
+                    if let Some(next) = schedule.queue.pop_front() {
+                        schedule.in_flight += 1;
+                        break Some(next);
+                    }
+
+                    // Nothing queued, but tiles out with other workers
+                    // may yet come back if those workers fail.
+                    if schedule.in_flight == 0 {
+                        break None;
+                    }
+
+                    schedule = changed.wait(schedule).unwrap();
+                }
+            };
+
+            let (idx, tile) = match next {
+                Some(next) => next,
+                None => return
+            };
+
+            let job = Job {
+                scene_name: String::from(scene.name),
+                shading: options.shading,
+                settings: settings.clone(),
+                aovs: options.aovs,
+                imgx,
+                imgy,
+                pass,
+                tile,
+            };
+
+            let result = remote_tile(&stream, &job, margin);
+
+            let mut schedule = schedule.lock().unwrap();
+            schedule.in_flight -= 1;
+
+            match result {
+                Ok(tile_film) => {
+                    drop(schedule);
+                    finish_tile(idx, tile_film);
+                    changed.notify_all();
+                },
+                Err(err) => {
+                    println!("Worker {} failed, reassigning its tile: {}", address, err);
+                    schedule.queue.push_front((idx, tile));
+                    changed.notify_all();
+                    return;
+                }
+            }
+        }
+    };
+
+    let run_worker = &run_worker;
+
+    thread::scope(| s | {
+        for address in workers.iter() {
+            s.spawn(move || run_worker(address));
+        }
+    });
+
+    let remaining: Vec<(usize, Tile)> = schedule.into_inner().unwrap().queue.into_iter().collect();
+
+    if !remaining.is_empty() && !is_stopped() {
+        println!("No workers left, rendering {} tiles locally.", remaining.len());
+
+        for (idx, tile) in remaining {
+            finish_tile(idx, render_region(scene, imgx, imgy, options, &tile, pass));
+        }
+    }
+
+    options.record_phase("render", start.elapsed());
+
+    let assemble_start = Instant::now();
+    let (film, _) = assemble_tiles(imgx, imgy, &tiles, tile_films);
+
+    options.record_phase("assemble", assemble_start.elapsed());
+
+    film
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> Job {
+        Job {
+            scene_name: String::from("Ball on Plane"),
+            shading: ShadingMode::Normals,
+            settings: String::from("sampler Sobol, oversample 4"),
+            aovs: true,
+            imgx: 640,
+            imgy: 480,
+            pass: 3,
+            tile: Tile { x0: 32, y0: 16, x1: 64, y1: 48 },
+        }
+    }
+
+    #[test]
+    fn jobs_round_trip() {
+        let mut bytes = vec![];
+        job().write(&mut bytes).unwrap();
+
+        let read = Job::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(read.scene_name, "Ball on Plane");
+        assert!(read.shading == ShadingMode::Normals);
+        assert_eq!(read.settings, "sampler Sobol, oversample 4");
+        assert!(read.aovs);
+        assert_eq!((read.imgx, read.imgy, read.pass), (640, 480, 3));
+        assert_eq!(read.tile, Tile { x0: 32, y0: 16, x1: 64, y1: 48 });
+    }
+
+    #[test]
+    fn jobs_outside_the_image_are_rejected() {
+        let mut bytes = vec![];
+        Job { tile: Tile { x0: 600, y0: 0, x1: 700, y1: 32 }, .. job() }.write(&mut bytes).unwrap();
+
+        assert_eq!(Job::read(&mut bytes.as_slice()).err().map(| err | err.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn truncated_jobs_are_rejected() {
+        let mut bytes = vec![];
+        job().write(&mut bytes).unwrap();
+
+        assert!(Job::read(&mut &bytes[..bytes.len() - 4]).is_err());
+    }
+}
@@ -11,17 +11,45 @@
 extern crate image;
 
 use std::env;
-use std::time::Instant;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use crate::image::GenericImage;
 
 mod render;
 mod scenes;
+mod distributed;
 
-use render::{render, Scene};
+use render::{render, trace_pixel, AdaptiveSampling, RenderOptions, Scene, ShadingMode};
+use render::sampler::Sampler;
+use render::filter::Filter;
+use render::tiles::{Progress, TileOrder};
+use render::progressive::{render_progressive, ProgressiveOptions};
+use render::stats::RenderStats;
+use render::aov::{Aov, ALL_AOVS};
+use render::film::{AlphaMode, Film};
+use render::denoise::DEFAULT_DENOISER;
+use render::postprocess::PostEffect;
+use render::exr::ExrImage;
+use render::output::{quantize, save, BitDepth, DisplayImage, Dither};
+use render::colorspace::{ColorSpace, DisplayEncoding, Transfer};
+
+use distributed::{render_distributed, serve};
 
 use scenes::{
+    scene_by_name,
     //scene_sphere_occlusion_test,
+    //scene_area_lights,
+    //scene_light_types,
+    //scene_gradient_sky,
+    //scene_environment_map,
+    //scene_physical_sky,
+    //scene_light_shafts,
+    //scene_voxel_cloud,
     scene_sphere_surface_test,
     scene_axis_spheres,
     scene_one_sphere,
@@ -35,27 +63,579 @@ fn is_parallel() -> bool {
     }
 }
 
-fn render_into(output_imgbuf: &mut image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
-               scene: &Scene, sx: u32, sy: u32, x: u32, y: u32) {
+fn shading_mode() -> ShadingMode {
+    match env::var("SHADING") {
+        Ok(val) => ShadingMode::from_name(&val).unwrap_or(ShadingMode::Full),
+        Err(_) => ShadingMode::Full
+    }
+}
+
+// Overrides the sampler chosen by each scene.
+fn sampler() -> Option<Sampler> {
+    match env::var("SAMPLER") {
+        Ok(val) => {
+            let sampler = Sampler::from_name(&val);
+
+            if sampler.is_none() {
+                println!("Unknown sampler: {}", val);
+            }
+
+            sampler
+        },
+        Err(_) => None
+    }
+}
+
+// Turns on adaptive sampling, taking extra samples in each pixel
+// until its standard error drops below the given threshold, up to
+// twice the scene's oversample in each direction.
+fn adaptive(oversample: u32) -> Option<AdaptiveSampling> {
+    match env::var("ADAPTIVE") {
+        Ok(val) => {
+            let threshold = val.parse().ok().filter(| threshold: &f64 | *threshold > 0.0);
+
+            if threshold.is_none() {
+                println!("Invalid adaptive sampling threshold: {}", val);
+            }
+
+            threshold.map(| threshold | AdaptiveSampling {
+                max_oversample: oversample * 2,
+                threshold
+            })
+        },
+        Err(_) => None
+    }
+}
+
+// Post-processing effects replacing the scene's own, as a comma
+// separated list of effects with optional parameters, as in
+// POST=bloom:threshold=1.5,vignette:strength=0.3. POST=none turns off
+// the scene's effects.
+fn post_effects() -> Option<Vec<PostEffect>> {
+    let val = env::var("POST").ok()?;
+
+    if val.trim().is_empty() || val == "none" {
+        return Some(vec![]);
+    }
+
+    let mut effects = vec![];
+
+    for spec in val.split(',') {
+        match PostEffect::from_spec(spec) {
+            Some(effect) => effects.push(effect),
+            None => println!("Unknown post-processing effect: {}", spec)
+        }
+    }
+
+    Some(effects)
+}
+
+// ALPHA=premultiplied or ALPHA=straight renders without the background
+// and writes coverage as an alpha channel.
+fn alpha_mode() -> Option<AlphaMode> {
+    match env::var("ALPHA") {
+        Ok(val) => {
+            let mode = AlphaMode::from_name(&val);
+
+            if mode.is_none() {
+                println!("Unknown alpha mode: {}", val);
+            }
+
+            mode
+        },
+        Err(_) => None
+    }
+}
+
+// Where the final image goes, with the format following the extension,
+// as in OUTPUT=render.tiff.
+fn output_path() -> String {
+    env::var("OUTPUT").unwrap_or_else(| _ | String::from("render.png"))
+}
+
+// BIT_DEPTH=16 writes 16 bits per channel, for PNG or TIFF output.
+fn bit_depth() -> BitDepth {
+    match env::var("BIT_DEPTH") {
+        Ok(val) => BitDepth::from_name(&val).unwrap_or_else(|| {
+            println!("Unknown bit depth: {}", val);
+            BitDepth::Eight
+        }),
+        Err(_) => BitDepth::Eight
+    }
+}
+
+// DITHER=ordered or DITHER=blue dithers 8-bit output.
+fn dither() -> Option<Dither> {
+    match env::var("DITHER") {
+        Ok(val) => {
+            let dither = Dither::from_name(&val);
+
+            if dither.is_none() {
+                println!("Unknown dither: {}", val);
+            }
+
+            dither
+        },
+        Err(_) => None
+    }
+}
+
+// COLOR_SPACE= sets the working space scenes render in, and
+// OUTPUT_SPACE= the space of the image written out, each one of srgb,
+// rec2020, acescg, or displayp3. TRANSFER= overrides the output space's
+// usual transfer function with linear, srgb, or rec709.
+fn color_space(var: &str) -> Option<ColorSpace> {
+    match env::var(var) {
+        Ok(val) => {
+            let space = ColorSpace::from_name(&val);
+
+            if space.is_none() {
+                println!("Unknown color space: {}", val);
+            }
+
+            space
+        },
+        Err(_) => None
+    }
+}
+
+fn output_space() -> ColorSpace {
+    color_space("OUTPUT_SPACE").unwrap_or(ColorSpace::Srgb)
+}
+
+fn transfer() -> Transfer {
+    match env::var("TRANSFER") {
+        Ok(val) => Transfer::from_name(&val).unwrap_or_else(|| {
+            println!("Unknown transfer function: {}", val);
+            output_space().default_transfer()
+        }),
+        Err(_) => output_space().default_transfer()
+    }
+}
+
+// Overrides the reconstruction filter chosen by each scene.
+fn filter() -> Option<Filter> {
+    match env::var("FILTER") {
+        Ok(val) => {
+            let filter = Filter::from_name(&val);
+
+            if filter.is_none() {
+                println!("Unknown filter: {}", val);
+            }
+
+            filter
+        },
+        Err(_) => None
+    }
+}
+
+fn tile_size() -> u32 {
+    env::var("TILE_SIZE").ok()
+        .and_then(| val | val.parse().ok())
+        .filter(| size | *size > 0)
+        .unwrap_or(32)
+}
+
+fn tile_order() -> TileOrder {
+    match env::var("TILE_ORDER") {
+        Ok(val) => TileOrder::from_name(&val).unwrap_or_else(|| {
+            println!("Unknown tile order: {}", val);
+            TileOrder::Spiral
+        }),
+        Err(_) => TileOrder::Spiral
+    }
+}
+
+// Number of rendering threads, defaulting to one per CPU.
+fn threads() -> Option<usize> {
+    env::var("THREADS").ok()
+        .and_then(| val | val.parse().ok())
+        .filter(| threads | *threads > 0)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    format!("{}:{:02}:{:02}", seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
+
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+fn show_progress(progress: &Progress) {
+    let fraction = progress.fraction();
+    let filled = ((fraction * PROGRESS_BAR_WIDTH as f64) as usize).min(PROGRESS_BAR_WIDTH);
+
+    let eta = match progress.eta() {
+        Some(eta) => format_duration(eta),
+        None => String::from("-:--:--")
+    };
+
+    eprint!("\r[{}{}] {:5.1}% ETA {} {:.2} Mrays/s",
+            "=".repeat(filled), " ".repeat(PROGRESS_BAR_WIDTH - filled),
+            fraction * 100.0, eta, progress.rays_per_second() / 1e6);
+
+    if progress.is_done() {
+        eprintln!();
+    }
+
+    io::stderr().flush().ok();
+}
+
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(val) => val.to_lowercase() == "y",
+        Err(_) => false
+    }
+}
+
+// A name for a scene that's safe to use in file names.
+fn scene_slug(scene: &Scene) -> String {
+    scene.name
+        .to_lowercase()
+        .chars()
+        .map(| c | if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+// Progressive rendering is used when there's a pass target
+// (PASSES=), time budget in seconds (TIME_BUDGET=), or a directory
+// to keep checkpoints and previews in (CHECKPOINT_DIR=). RESUME=y
+// picks up from the checkpoints there.
+fn progressive_options(scene: &Scene) -> Option<ProgressiveOptions> {
+    let passes = env::var("PASSES").ok().and_then(| val | val.parse().ok());
+    let time_budget = env::var("TIME_BUDGET").ok()
+        .and_then(| val | val.parse().ok())
+        .map(Duration::from_secs_f64);
+    let checkpoint_dir = env::var("CHECKPOINT_DIR").ok().map(PathBuf::from);
+
+    if passes.is_none() && time_budget.is_none() && checkpoint_dir.is_none() {
+        return None;
+    }
+
+    let slug = scene_slug(scene);
+
+    Some(ProgressiveOptions {
+        passes,
+        time_budget,
+        checkpoint: checkpoint_dir.as_ref().map(| dir | dir.join(format!("{}.ckpt", slug))),
+        preview: checkpoint_dir.as_ref().map(| dir | dir.join(format!("{}.png", slug))),
+        output_space: output_space(),
+        transfer: transfer(),
+        save_interval: Duration::from_secs(30),
+        resume: env_flag("RESUME"),
+    })
+}
+
+// Addresses of worker processes to distribute rendering over, as a
+// comma separated list in WORKERS=.
+fn workers() -> Vec<String> {
+    match env::var("WORKERS") {
+        Ok(val) => val.split(',')
+            .map(| address | address.trim().to_string())
+            .filter(| address | !address.is_empty())
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+// AOVs are written to AOV_DIR, when it's set, as one EXR per scene with
+// a layer for each AOV, or with AOV_SEPARATE=y, an EXR per AOV. AOVS
+// picks which are written, defaulting to all of them.
+fn aov_dir() -> Option<PathBuf> {
+    env::var("AOV_DIR").ok().map(PathBuf::from)
+}
+
+fn aovs() -> Vec<Aov> {
+    match env::var("AOVS") {
+        Ok(val) => val.split(',')
+            .filter_map(| name | {
+                let aov = Aov::from_name(name.trim());
+
+                if aov.is_none() {
+                    println!("Unknown AOV: {}", name);
+                }
+
+                aov
+            })
+            .collect(),
+        Err(_) => ALL_AOVS.to_vec()
+    }
+}
+
+// The EXR files are tagged with the scene's working space, which is
+// what they're in.
+fn write_aovs(film: &Film, scene: &Scene, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let slug = scene_slug(scene);
+
+    let exr = | aovs: &[Aov], layers: bool | {
+        let mut image = film.to_exr(aovs, layers);
+        image.set_color_space(scene.color_space);
+        image
+    };
+
+    if env_flag("AOV_SEPARATE") {
+        exr(&[], false).save(dir.join(format!("{}.beauty.exr", slug)))?;
+
+        for aov in aovs() {
+            exr(&[aov], false).save(dir.join(format!("{}.{}.exr", slug, aov.name())))?;
+        }
+
+        Ok(())
+    } else {
+        exr(&aovs(), true).save(dir.join(format!("{}.exr", slug)))
+    }
+}
+
+// Applies the overrides from the environment. Workers do this too, so
+// they need to be run with the same settings as the coordinator.
+fn configure_scene(scene: &mut Scene) {
+    if let Some(sampler) = sampler() {
+        scene.sampler = sampler;
+    }
+
+    if let Some(adaptive) = adaptive(scene.oversample) {
+        scene.adaptive = Some(adaptive);
+    }
+
+    if let Some(filter) = filter() {
+        scene.filter = filter;
+    }
+
+    if let Some(post_effects) = post_effects() {
+        scene.post_effects = post_effects;
+    }
+
+    if let Some(space) = color_space("COLOR_SPACE") {
+        scene.color_space = space;
+    }
+}
+
+fn render_options(stop: Option<Arc<AtomicBool>>, stats: Option<Arc<Mutex<RenderStats>>>) -> RenderOptions {
+    RenderOptions {
+        parallel: is_parallel(),
+        shading: shading_mode(),
+        tile_size: tile_size(),
+        tile_order: tile_order(),
+        threads: threads(),
+        progress: Some(Box::new(show_progress)),
+        stop,
+        stats,
+        aovs: aov_dir().is_some() || env_flag("DENOISE"),
+        alpha: alpha_mode().is_some(),
+        spectral: env_flag("SPECTRAL"),
+    }
+}
+
+// Statistics are collected when they're to be shown (STATS=y) or
+// written out as JSON (STATS_JSON=path).
+fn stats_json_path() -> Option<String> {
+    env::var("STATS_JSON").ok()
+}
+
+fn collect_stats() -> bool {
+    env_flag("STATS") || stats_json_path().is_some()
+}
 
-    let parallel = is_parallel();
+fn render_into(output_imgbuf: &mut DisplayImage,
+               scene: &Scene, sx: u32, sy: u32, x: u32, y: u32,
+               stop: &Arc<AtomicBool>) -> Option<RenderStats> {
+
+    let stats = if collect_stats() {
+        Some(Arc::new(Mutex::new(RenderStats::default())))
+    } else {
+        None
+    };
+
+    let options = render_options(Some(stop.clone()), stats.clone());
+    let workers = workers();
 
     let start = Instant::now();
 
-    output_imgbuf.copy_from(&render(scene, sx, sy, parallel), x, y)
+    let mut film = if !workers.is_empty() {
+        render_distributed(scene, sx, sy, &options, &workers, 0)
+    } else if let Some(progressive) = progressive_options(scene) {
+        match render_progressive(scene, sx, sy, &options, &progressive) {
+            Ok(checkpoint) => {
+                println!("{} passes ({:.1} samples per pixel) rendered in {}",
+                         checkpoint.passes,
+                         checkpoint.film.total_samples() as f64 / (sx as f64 * sy as f64),
+                         scene.name);
+                checkpoint.film
+            },
+            Err(err) => {
+                println!("Progressive render of {} failed: {}", scene.name, err);
+                return None;
+            }
+        }
+    } else {
+        render(scene, sx, sy, &options)
+    };
+
+    let output_start = Instant::now();
+
+    // AOVs keep the noisy color, so they can be denoised again later
+    // with different settings.
+    if let Some(dir) = aov_dir() {
+        if let Err(err) = write_aovs(&film, scene, &dir) {
+            println!("Cannot write AOVs for {}: {}", scene.name, err);
+        }
+    }
+
+    if env_flag("DENOISE") {
+        let denoise_start = Instant::now();
+
+        film.denoise(&DEFAULT_DENOISER);
+
+        options.record_phase("denoise", denoise_start.elapsed());
+    }
+
+    if !scene.post_effects.is_empty() {
+        let post_start = Instant::now();
+
+        film.post_process(&scene.post_effects);
+
+        options.record_phase("post", post_start.elapsed());
+    }
+
+    let encoding = DisplayEncoding::new(scene.color_space, output_space(), transfer());
+
+    output_imgbuf.copy_from(&film.to_display_image(&encoding, alpha_mode()), x, y)
         .map_err(|err| println!("{:?}", err)).ok();
 
+    options.record_phase("output", output_start.elapsed());
+
     let duration = start.elapsed();
-    println!("Time elapsed in {} is: {:?} (parallel: {})", scene.name, duration, parallel);
+    println!("Time elapsed in {} is: {:?} (parallel: {})", scene.name, duration, options.parallel);
+
+    let stats = stats.map(| stats | stats.lock().unwrap().clone());
+
+    if let Some(stats) = &stats {
+        if env_flag("STATS") {
+            print!("{}", stats.summary(scene.name));
+        }
+    }
+
+    stats
+}
+
+// Renders tiles for a coordinator elsewhere, listening on the given
+// address.
+fn run_worker(address: &str) {
+    let options = RenderOptions {
+        progress: None,
+        .. render_options(None, None)
+    };
+
+    let load_scene = | name: &str | scene_by_name(name).map(| mut scene | {
+        configure_scene(&mut scene);
+        scene
+    });
+
+    if let Err(err) = serve(address, load_scene, options) {
+        println!("Worker failed: {}", err);
+    }
+}
+
+// Prints every ray traced for one pixel of a scene, and writes the
+// same as JSON to TRACE_JSON, if set.
+fn run_trace(args: &[String]) {
+    let usage = "Usage: raytracer trace <scene name> <x> <y> [image size]";
+
+    let (name, x, y) = match args {
+        [name, x, y, ..] => match (x.parse::<u32>(), y.parse::<u32>()) {
+            (Ok(x), Ok(y)) => (name, x, y),
+            _ => {
+                println!("{}", usage);
+                return;
+            }
+        },
+        _ => {
+            println!("{}", usage);
+            return;
+        }
+    };
+
+    let size = args.get(3).and_then(| size | size.parse::<u32>().ok()).unwrap_or(1024);
+
+    if x >= size || y >= size {
+        println!("Pixel ({}, {}) is outside the {}x{} image.", x, y, size, size);
+        return;
+    }
+
+    let mut scene = match scene_by_name(name) {
+        Some(scene) => scene,
+        None => {
+            println!("Unknown scene: {}", name);
+            return;
+        }
+    };
+
+    configure_scene(&mut scene);
+
+    let options = RenderOptions {
+        progress: None,
+        .. render_options(None, None)
+    };
+
+    let trace = trace_pixel(&scene, size, size, &options, x, y);
+
+    print!("{}", trace.to_text());
+
+    if let Ok(path) = env::var("TRACE_JSON") {
+        fs::write(&path, format!("{}\n", trace.to_json()))
+            .map_err(|err| println!("Cannot write trace to {}: {}", path, err)).ok();
+    }
+}
+
+// Denoises an EXR written with the AOVs, using its albedo and normal
+// layers as guides, or those in separately written AOV images.
+fn denoise_file(input: &str, output: &str, albedo: Option<&String>, normal: Option<&String>) -> io::Result<()> {
+    let image = ExrImage::load(input)?;
+    let albedo = albedo.map(ExrImage::load).transpose()?;
+    let normal = normal.map(ExrImage::load).transpose()?;
+
+    DEFAULT_DENOISER.denoise_exr(&image, albedo.as_ref(), normal.as_ref())?.save(output)
+}
+
+fn run_denoise(args: &[String]) {
+    match args {
+        [input, output, ..] => match denoise_file(input, output, args.get(2), args.get(3)) {
+            Ok(()) => println!("Denoised {} into {}", input, output),
+            Err(err) => println!("Cannot denoise {}: {}", input, err)
+        },
+        _ => println!("Usage: raytracer denoise <input.exr> <output.exr> [albedo.exr] [normal.exr]")
+    }
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // raytracer worker [address]
+    if args.get(1).map(String::as_str) == Some("worker") {
+        run_worker(args.get(2).map_or("127.0.0.1:7878", String::as_str));
+        return;
+    }
+
+    // raytracer denoise <input.exr> <output.exr> [albedo.exr] [normal.exr]
+    if args.get(1).map(String::as_str) == Some("denoise") {
+        run_denoise(&args[2..]);
+        return;
+    }
+
+    // raytracer trace <scene name> <x> <y> [image size]
+    if args.get(1).map(String::as_str) == Some("trace") {
+        run_trace(&args[2..]);
+        return;
+    }
+
     let imgdim = 2048;
     let half = imgdim / 2;
 
     let mut output_imgbuf = image::ImageBuffer::new(imgdim, imgdim);
 
-    let scene = [
+    let mut scene = [
         //scene_sphere_occlusion_test(),
         scene_sphere_surface_test(),
         scene_axis_spheres(),
@@ -63,15 +643,51 @@ fn main() {
         scene_ball_on_plane()
     ];
 
-    render_into(&mut output_imgbuf, &scene[0], half, half, 0, 0);
-    render_into(&mut output_imgbuf, &scene[1], half, half, half, 0);
-    render_into(&mut output_imgbuf, &scene[2], half, half, 0, half);
-    render_into(&mut output_imgbuf, &scene[3], half, half, half, half);
+    for s in scene.iter_mut() {
+        configure_scene(s);
+    }
+
+    // Ctrl-C stops rendering, keeping whatever's finished.
+    let stop = Arc::new(AtomicBool::new(false));
+
+    {
+        let stop = stop.clone();
+
+        ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst))
+            .map_err(|err| println!("{:?}", err)).ok();
+    }
+
+    let positions = [(0, 0), (half, 0), (0, half), (half, half)];
+
+    let mut scene_stats = vec![];
+
+    for (s, (x, y)) in scene.iter().zip(positions.iter()) {
+        if stop.load(Ordering::SeqCst) {
+            println!("Render stopped.");
+            break;
+        }
+
+        if let Some(stats) = render_into(&mut output_imgbuf, s, half, half, *x, *y, &stop) {
+            scene_stats.push(stats.to_json(s.name));
+        }
+    }
+
+    if let Some(path) = stats_json_path() {
+        fs::write(&path, format!("[\n  {}\n]\n", scene_stats.join(",\n  ")))
+            .map_err(|err| println!("Cannot write statistics to {}: {}", path, err)).ok();
+    }
 
     for ii in 0..imgdim - 1 {
-        *output_imgbuf.get_pixel_mut(ii, imgdim / 2) = image::Rgb([255, 255, 255]);
-        *output_imgbuf.get_pixel_mut(imgdim / 2, ii) = image::Rgb([255, 255, 255]);
+        *output_imgbuf.get_pixel_mut(ii, imgdim / 2) = image::Rgba([1.0, 1.0, 1.0, 1.0]);
+        *output_imgbuf.get_pixel_mut(imgdim / 2, ii) = image::Rgba([1.0, 1.0, 1.0, 1.0]);
     }
 
-    output_imgbuf.save("render.png").unwrap();
+    // Without alpha, every pixel is opaque, so the alpha channel is
+    // left out of the file.
+    let path = output_path();
+
+    let image = quantize(&output_imgbuf, bit_depth(), dither(), alpha_mode().is_some());
+
+    save(&image, &path, output_space(), transfer())
+        .map_err(|err| println!("Cannot write {}: {}", path, err)).ok();
 }
@@ -12,31 +12,81 @@ extern crate image;
 
 pub mod geometry;
 pub mod color;
+pub mod colorspace;
 pub mod shapes;
-
-use rayon::prelude::*;
+pub mod random;
+pub mod environment;
+pub mod sky;
+pub mod volume;
+pub mod voxel;
+pub mod sampler;
+pub mod filter;
+pub mod film;
+pub mod tiles;
+pub mod progressive;
+pub mod stats;
+pub mod debug;
+pub mod trace;
+pub mod exr;
+pub mod aov;
+pub mod denoise;
+pub mod postprocess;
+pub mod output;
+pub mod spectral;
 
 use geometry::{
     EPSILON,
+    Basis,
+    Normal,
     Point,
+    Ray,
     Vector,
-    addp,
-    dotp,
-    lenp,
-    negp,
-    normalizep,
-    scalep,
-    subp,
+    reflect,
+    refract,
+};
+
+use random::Rng;
+
+use sampler::Sampler;
+
+use filter::Filter;
+
+use film::Film;
+
+use tiles::{
+    Progress,
+    ProgressCallback,
+    Tile,
+    TileOrder,
 };
 
 use color::{
     LinearColor,
-    scale_linear_color,
-    add_linear_color,
-    to_png_color,
+    heatmap_color,
+};
+
+use environment::Environment;
+
+use stats::RenderStats;
+
+use trace::{PixelTrace, RayKind, RayResult};
+
+use aov::AovSample;
+use postprocess::PostEffect;
+use colorspace::ColorSpace;
+use spectral::Ior;
+
+use volume::{
+    Fog,
+    Volume,
 };
 
 use std::cmp::Ordering;
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone)]
 pub struct Surface {
@@ -45,19 +95,252 @@ pub struct Surface {
     pub specular: f64,
     pub light: f64,
     pub checked: bool,
-    pub reflection: f64
+    pub reflection: f64,
+
+    // Filters reflected light, for metals that color what they reflect.
+    pub reflection_color: LinearColor,
+
+    pub emission: LinearColor,
+    pub emission_strength: f64,
+
+    // Fraction of light that passes through the surface, bending by
+    // the refractive index on the way in and out. Surfaces that
+    // transmit light should enclose a volume.
+    pub transmission: f64,
+    pub ior: Ior,
 }
 
-pub struct Light {
-    pub location: Point
+impl Surface {
+    pub fn is_emissive(&self) -> bool {
+        self.emission_strength > EPSILON
+    }
+}
+
+// Distance falloff for positional lights, as the reciprocal of
+// constant + linear * d + quadratic * d^2.
+#[derive(Copy, Clone)]
+pub struct Attenuation {
+    pub constant: f64,
+    pub linear: f64,
+    pub quadratic: f64
+}
+
+pub const NO_ATTENUATION: Attenuation = Attenuation {
+    constant: 1.0,
+    linear: 0.0,
+    quadratic: 0.0
+};
+
+pub const INVERSE_SQUARE: Attenuation = Attenuation {
+    constant: 0.0,
+    linear: 0.0,
+    quadratic: 1.0
+};
+
+impl Attenuation {
+    fn factor(&self, distance: f64) -> f64 {
+        let denom = self.constant + self.linear * distance + self.quadratic * distance * distance;
+
+        if denom < EPSILON { 1.0 / EPSILON } else { 1.0 / denom }
+    }
+}
+
+// Lights give off their color, as linear sRGB, scaled by their
+// intensity.
+pub enum Light {
+    Point {
+        location: Point,
+        color: LinearColor,
+        intensity: f64,
+        attenuation: Attenuation
+    },
+
+    // Parallel rays traveling in the given direction from a source
+    // infinitely far away. A non-zero angular diameter (in radians)
+    // spreads the rays over a cone, giving soft shadows.
+    Directional {
+        direction: Vector,
+        color: LinearColor,
+        intensity: f64,
+        angular_diameter: f64
+    },
+
+    // A point light restricted to a cone around its direction. Light
+    // falls off smoothly over the outermost falloff_angle radians of
+    // the cone_angle half-angle.
+    Spot {
+        location: Point,
+        direction: Vector,
+        color: LinearColor,
+        intensity: f64,
+        attenuation: Attenuation,
+        cone_angle: f64,
+        falloff_angle: f64
+    }
+}
+
+impl Light {
+    pub fn color(&self) -> LinearColor {
+        match self {
+            Light::Point { color, .. } | Light::Directional { color, .. } | Light::Spot { color, .. } => *color
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
 pub struct Camera {
     pub location: Point,
     pub point_at: Point,
-    pub u: Point,
-    pub v: Point
+    pub u: Vector,
+    pub v: Vector
+}
+
+// Settings for ambient occlusion: the ambient term is scaled by the
+// fraction of rays fired over the hemisphere around each hit that
+// travel at least distance without hitting anything.
+#[derive(Copy, Clone)]
+pub struct AmbientOcclusion {
+    pub samples: u32,
+    pub distance: f64,
+}
+
+// Used for the ambient occlusion pass of scenes that don't set their
+// own ambient occlusion.
+pub const DEFAULT_AMBIENT_OCCLUSION: AmbientOcclusion = AmbientOcclusion {
+    samples: 16,
+    distance: 2.0,
+};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ShadingMode {
+    Full,
+
+    // Ambient occlusion alone, as shades of gray, for checking how
+    // it's contributing to lighting.
+    AmbientOcclusion,
+
+    // The number of samples taken for each pixel, as a heatmap running
+    // from blue (fewest) to red (most).
+    SampleHeatmap,
+
+    // Debugging views that replace shading with one property of the
+    // surface each ray hits: its normal, distance from the camera,
+    // which object it is, or its surface coordinates. Misses are black.
+    Normals,
+    Depth,
+    ObjectId,
+    Uv,
+
+    // The number of intersection tests done for each pixel, as a
+    // heatmap.
+    IntersectionHeatmap,
+
+    // How many reflections each ray takes before it reaches a surface
+    // that doesn't reflect, as a heatmap up to the reflection limit.
+    ReflectionDepth,
+}
+
+impl ShadingMode {
+    pub fn from_name(name: &str) -> Option<ShadingMode> {
+        match name.to_lowercase().as_str() {
+            "full" => Some(ShadingMode::Full),
+            "ao" => Some(ShadingMode::AmbientOcclusion),
+            "samples" => Some(ShadingMode::SampleHeatmap),
+            "normals" => Some(ShadingMode::Normals),
+            "depth" => Some(ShadingMode::Depth),
+            "id" => Some(ShadingMode::ObjectId),
+            "uv" => Some(ShadingMode::Uv),
+            "tests" => Some(ShadingMode::IntersectionHeatmap),
+            "reflections" => Some(ShadingMode::ReflectionDepth),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShadingMode::Full => "full",
+            ShadingMode::AmbientOcclusion => "ao",
+            ShadingMode::SampleHeatmap => "samples",
+            ShadingMode::Normals => "normals",
+            ShadingMode::Depth => "depth",
+            ShadingMode::ObjectId => "id",
+            ShadingMode::Uv => "uv",
+            ShadingMode::IntersectionHeatmap => "tests",
+            ShadingMode::ReflectionDepth => "reflections"
+        }
+    }
+
+    // Whether surfaces are shown by a debugging view rather than
+    // shade_pixel.
+    fn is_debug_view(&self) -> bool {
+        matches!(self, ShadingMode::Normals | ShadingMode::Depth | ShadingMode::ObjectId
+                 | ShadingMode::Uv | ShadingMode::ReflectionDepth)
+    }
+}
+
+// Settings for adaptive sampling. Pixels start with the scene's
+// oversample squared samples, and take more in batches of that size,
+// up to max_oversample squared, until the standard error of the
+// pixel's luminance drops below threshold.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AdaptiveSampling {
+    pub max_oversample: u32,
+    pub threshold: f64,
+}
+
+pub struct RenderOptions {
+    pub parallel: bool,
+    pub shading: ShadingMode,
+
+    // Edge length of the square tiles the image is split into, in
+    // pixels, and the order they're rendered in.
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+
+    // Size of the thread pool used for parallel rendering, or None for
+    // one thread per CPU.
+    pub threads: Option<usize>,
+
+    // Called after each tile finishes. With parallel rendering, this
+    // can be called from several threads at once.
+    pub progress: Option<ProgressCallback>,
+
+    // When set, rendering winds down as soon as the tiles already
+    // underway are finished.
+    pub stop: Option<Arc<AtomicBool>>,
+
+    // When set, statistics from rendering are added in here.
+    pub stats: Option<Arc<Mutex<RenderStats>>>,
+
+    // Collects AOVs in the film alongside the final color.
+    pub aovs: bool,
+
+    // Leaves the background out, so camera rays that miss everything
+    // are black and count as uncovered in the film's alpha.
+    pub alpha: bool,
+
+    // Traces a single wavelength along each camera ray, rather than
+    // red, green, and blue, so that refraction can vary with
+    // wavelength. Only applies to full shading.
+    pub spectral: bool,
+}
+
+impl RenderOptions {
+    pub fn record_phase(&self, name: &'static str, duration: Duration) {
+        if let Some(collected) = &self.stats {
+            collected.lock().unwrap().add_phase(name, duration);
+        }
+    }
+}
+
+// Every setting that changes what a pass of a scene renders, as text,
+// so that checkpoints and workers can be checked against the settings
+// of the render they're joining.
+pub fn render_settings(scene: &Scene, options: &RenderOptions) -> String {
+    format!("sampler {:?}, filter {:?}, oversample {}, adaptive {:?}, shading {}, spectral {}, \
+             color space {:?}, alpha {}, aovs {}",
+            scene.sampler, scene.filter, scene.oversample, scene.adaptive, options.shading.name(),
+            options.spectral, scene.color_space, options.alpha, options.aovs)
 }
 
 struct CameraDetails {
@@ -70,39 +353,102 @@ struct CameraDetails {
 pub struct Scene {
     pub name: &'static str,
     pub camera: Camera,
-    pub light: Light,
+    pub lights: Vec<Light>,
     pub objects: Vec<Box<dyn Hittable + Sync + Send>>,
-    pub background: LinearColor,
+    pub environment: Environment,
+    pub fog: Option<Fog>,
+    pub volumes: Vec<Volume>,
 
     pub reflect_limit: u32,
     pub oversample: u32,
+    pub sampler: Sampler,
+    pub filter: Filter,
+    pub adaptive: Option<AdaptiveSampling>,
+    pub light_samples: u32,
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+
+    // Applied in order to the finished image, before it's converted
+    // for display.
+    pub post_effects: Vec<PostEffect>,
+
+    // The working color space. Colors in the scene are given in linear
+    // sRGB, and converted into this space as they're read.
+    pub color_space: ColorSpace,
+}
+
+impl Scene {
+    // A color given by the scene, as the renderer works with it:
+    // converted into the working space, or when tracing a single
+    // wavelength, the value of its spectrum at that wavelength.
+    pub fn working_color(&self, color: &LinearColor) -> LinearColor {
+        match spectral::wavelength() {
+            Some(wavelength) => LinearColor::gray(spectral::rgb_to_spectrum(color, wavelength)),
+            None => self.color_space.convert_srgb(color)
+        }
+    }
 }
 
 pub trait Hittable {
-    fn hit_test(&self, ray: &Vector) -> Option<RayHit>;
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit>;
+
+    // Coordinates of a hit across the surface, each from 0 to 1. Only
+    // the debugging views use these, so they're worked out from the hit
+    // when asked for rather than on every hit test.
+    fn uv(&self, _hit: &RayHit) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+
+    // Shapes that know how to sample points on their own surface can
+    // act as area lights when they're given an emissive surface.
+    fn emitter(&self) -> Option<&dyn Emitter> {
+        None
+    }
 }
 
-fn camera_ray(c: &Camera, xt: f64, yt: f64) -> Vector {
+pub trait Emitter {
+    fn surface(&self) -> &Surface;
 
-    let ray_point_at = addp(addp(c.point_at, scalep(c.u, xt - 0.5)), scalep(c.v, yt - 0.5));
+    fn area(&self) -> f64;
 
-    Vector {
-        start: c.location,
-        delta: normalizep(subp(ray_point_at, c.location))
+    // Maps a pair of uniform random numbers in [0, 1) to a point
+    // uniformly distributed over the surface, along with the surface
+    // normal at that point.
+    fn sample_point(&self, u: f64, v: f64) -> (Point, Normal);
+
+    // Samples a point on the surface to light the point from with,
+    // along with the surface normal and the area the sample stands for,
+    // which is one over the sampling density per unit area. Shapes can
+    // override this to skip the parts of their surface that from can't
+    // see.
+    fn sample_point_from(&self, _from: &Point, u: f64, v: f64) -> (Point, Normal, f64) {
+        let (point, normal) = self.sample_point(u, v);
+
+        (point, normal, self.area())
     }
 }
 
-fn ray_location(ray: &Vector, t: f64) -> Point {
-    let [x, y, z] = ray.start;
-    let [dx, dy, dz] = ray.delta;
+// There's no ray through points on the image plane that sit right on
+// the camera, since they give no direction to look in.
+fn camera_ray(c: &Camera, xt: f64, yt: f64) -> Option<Ray> {
 
-    [x + dx * t, y + dy * t, z + dz * t]
+    let ray_point_at = c.point_at + c.u * (xt - 0.5) + c.v * (yt - 0.5);
+
+    Some(Ray {
+        start: c.location,
+        delta: (ray_point_at - c.location).normalize()?
+    })
 }
 
 pub struct RayHit {
     pub distance: f64,
     pub hit_point: Point,
-    pub normal: Point,
+    pub normal: Normal,
+
+    // Whether the ray hit the outside of the surface. For shapes that
+    // don't enclose anything, the outside is the side the normal points
+    // to, before any flipping towards the ray.
+    pub front_face: bool,
+
     pub surface: Surface,
 }
 
@@ -126,154 +472,985 @@ impl PartialEq for RayHit {
     }
 }
 
-fn nearest_hit(ray: &Vector, objects: &Vec<Box<dyn Hittable + Send + Sync>>) -> Option<RayHit> {
+// The nearest hit along with the index of the object that was hit.
+fn nearest_hit_object(ray: &Ray, objects: &Vec<Box<dyn Hittable + Send + Sync>>) -> Option<(usize, RayHit)> {
 
     objects
         .iter()
-        .fold(None, | last_hit, obj | {
-            let hit = obj.hit_test(ray);
-
-            if hit > last_hit {
-                hit
-            } else {
-                last_hit
+        .enumerate()
+        .fold(None, | last_hit: Option<(usize, RayHit)>, (idx, obj) | {
+            match obj.hit_test(ray) {
+                Some(hit) if last_hit.as_ref().is_none_or(| (_, last) | hit > *last) => Some((idx, hit)),
+                _ => last_hit
             }
         })
 }
 
 
-fn light_vector(point: &Point, scene: &Scene) -> Option<Vector> {
-    let light_direction = subp(*point, scene.light.location);
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 - edge0 < EPSILON {
+        return if x < edge0 { 0.0 } else { 1.0 };
+    }
 
-    let light_distance = lenp(light_direction);
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
 
-    let ray = Vector {
-        start: scene.light.location,
-        delta: normalizep(light_direction)
-    };
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Uniformly samples a direction within half_angle radians of axis.
+fn sample_cone(axis: Normal, half_angle: f64, rng: &mut Rng) -> Vector {
+    let cos_theta = 1.0 - rng.next_f64() * (1.0 - half_angle.cos());
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+
+    Basis::from_w(axis).to_world(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
 
-    match nearest_hit(&ray, &scene.objects) {
-        Some(hit) =>
-            if hit.distance > light_distance - EPSILON {
-                Some(ray)
+// Samples the hemisphere around normal, favoring directions close to
+// the normal in proportion to the cosine of the angle between them.
+fn sample_cosine_hemisphere(normal: Normal, rng: &mut Rng) -> Vector {
+    let r = rng.next_f64().sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+
+    Basis::from_w(normal).to_world(r * phi.cos(), r * phi.sin(), (1.0 - r * r).max(0.0).sqrt())
+}
+
+// Lights sitting right on the point shine in no particular direction,
+// so they're skipped.
+fn positional_light_vector(point: &Point, location: &Point, scene: &Scene, rng: &mut Rng) -> Option<(Vector, f64, f64)> {
+    let light_direction = *point - *location;
+
+    let light_distance = light_direction.length();
+
+    let delta = light_direction.normalize()?;
+
+    let transmittance = shadow_transmittance(point, -delta, light_distance, scene, rng);
+
+    if transmittance > 0.0 {
+        Some((delta, light_distance, transmittance))
+    } else {
+        None
+    }
+}
+
+// Returns the direction light travels from the light to the given
+// point, along with its strength on arrival (after passing through
+// any volumes on the way), or None if the point is in shadow.
+fn light_vector(point: &Point, light: &Light, scene: &Scene, rng: &mut Rng) -> Option<(Vector, f64)> {
+    match light {
+        Light::Point { location, intensity, attenuation, .. } => {
+            positional_light_vector(point, location, scene, rng)
+                .map(| (delta, distance, transmittance) | {
+                    (delta, intensity * attenuation.factor(distance) * transmittance)
+                })
+        },
+
+        Light::Directional { direction, intensity, angular_diameter, .. } => {
+            let axis = direction.to_normal()?;
+
+            let delta = if *angular_diameter > EPSILON {
+                sample_cone(axis, angular_diameter / 2.0, rng)
+            } else {
+                axis.to_vector()
+            };
+
+            let transmittance = shadow_transmittance(point, -delta, f64::INFINITY, scene, rng);
+
+            if transmittance > 0.0 {
+                Some((delta, intensity * transmittance))
             } else {
                 None
             }
-        None => None
+        },
+
+        Light::Spot { location, direction, intensity, attenuation, cone_angle, falloff_angle, .. } => {
+            positional_light_vector(point, location, scene, rng)
+                .and_then(| (delta, distance, transmittance) | {
+                    let cos_angle = delta.dot(direction.normalize()?);
+                    let spot = smoothstep(cone_angle.cos(),
+                                          (cone_angle - falloff_angle).max(0.0).cos(),
+                                          cos_angle);
+
+                    if spot > 0.0 {
+                        Some((delta, intensity * attenuation.factor(distance) * spot * transmittance))
+                    } else {
+                        None
+                    }
+                })
+        }
+    }
+}
+
+fn is_visible(point: &Point, delta: Vector, distance: f64, scene: &Scene) -> bool {
+    let ray = Ray {
+        start: *point,
+        delta
+    };
+
+    match nearest_hit_object(&ray, &scene.objects) {
+        Some((object, hit)) => {
+            trace::record_hit(object, scene.objects[object].as_ref(), &hit);
+
+            hit.distance > distance - EPSILON
+        },
+        None => true
+    }
+}
+
+// The fraction of light that travels distance along delta from the
+// point, taking both surfaces and volumes in the way into account.
+fn shadow_transmittance(point: &Point, delta: Vector, distance: f64, scene: &Scene, rng: &mut Rng) -> f64 {
+    stats::record(| s | s.shadow_rays += 1);
+
+    let ray = Ray { start: *point, delta };
+
+    trace::begin_ray(RayKind::Shadow, 0, &ray, Some(distance));
+
+    let transmittance = if is_visible(point, delta, distance, scene) {
+        volume::transmittance(&ray, distance, scene, rng)
+    } else {
+        0.0
+    };
+
+    trace::end_ray(RayResult::Transmittance(transmittance));
+    aov::record_shadow(transmittance);
+
+    transmittance
+}
+
+// Light from an area light or the environment, along with the part of
+// it that's specular highlights.
+type LightColor = (LinearColor, LinearColor);
+
+fn area_light_color(ray: &Ray, scene: &Scene, hit: &RayHit, scolor: &LinearColor,
+                    emitter: &dyn Emitter, rng: &mut Rng) -> LightColor {
+    // Monte Carlo estimate of the light arriving from an emissive
+    // shape, sampled by area and converted to solid angle.
+
+    let strength = emitter.surface().emission_strength;
+    let emission = scene.working_color(&emitter.surface().emission);
+
+    let mut lc = LinearColor::BLACK;
+    let mut specular = LinearColor::BLACK;
+
+    for _ in 0..scene.light_samples {
+        let (lpoint, lnormal, area) = emitter.sample_point_from(&hit.hit_point, rng.next_f64(), rng.next_f64());
+
+        let to_light = lpoint - hit.hit_point;
+        let distance = to_light.length();
+
+        if distance < EPSILON {
+            continue;
+        }
+
+        let ldir = to_light * (1.0 / distance);
+
+        let cos_surface = hit.normal.dot(ldir);
+        let cos_light = -lnormal.dot(ldir);
+
+        if cos_surface <= 0.0 || cos_light <= 0.0 {
+            continue;
+        }
+
+        let transmittance = shadow_transmittance(&hit.hit_point, ldir, distance, scene, rng);
+
+        if transmittance <= 0.0 {
+            continue;
+        }
+
+        let weight = strength * transmittance * cos_light * area / (PI * distance * distance);
+
+        let kspecular = (ldir - ray.delta).normalize().map_or(0.0, | half | hit.normal.dot(half).max(0.0).powf(50.0));
+        let scolor_specular = emission * (kspecular * hit.surface.specular * weight);
+
+        specular += scolor_specular;
+
+        lc += scolor_specular + *scolor * emission * (hit.surface.light * cos_surface * weight);
+    }
+
+    let scale = 1.0 / scene.light_samples.max(1) as f64;
+
+    (lc * scale, specular * scale)
+}
+
+fn environment_light_color(ray: &Ray, scene: &Scene, hit: &RayHit, scolor: &LinearColor,
+                           rng: &mut Rng) -> LightColor {
+    // Monte Carlo estimate of the light arriving from the environment,
+    // using the directions the environment thinks are most important.
+
+    let mut lc = LinearColor::BLACK;
+    let mut specular = LinearColor::BLACK;
+
+    for _ in 0..scene.light_samples {
+        let (ldir, pdf) = scene.environment.sample(rng);
+
+        let cos_surface = hit.normal.dot(ldir);
+
+        if cos_surface <= 0.0 || pdf <= 0.0 {
+            continue;
+        }
+
+        let transmittance = shadow_transmittance(&hit.hit_point, ldir, f64::INFINITY, scene, rng);
+
+        if transmittance <= 0.0 {
+            continue;
+        }
+
+        let radiance = scene.working_color(&scene.environment.color(&ldir));
+        let weight = transmittance / (PI * pdf);
+
+        let kspecular = (ldir - ray.delta).normalize().map_or(0.0, | half | hit.normal.dot(half).max(0.0).powf(50.0));
+        let scolor_specular = radiance * (kspecular * hit.surface.specular * weight);
+
+        specular += scolor_specular;
+
+        lc += scolor_specular + *scolor * radiance * (hit.surface.light * cos_surface * weight);
     }
+
+    let scale = 1.0 / scene.light_samples.max(1) as f64;
+
+    (lc * scale, specular * scale)
 }
 
-fn shade_pixel(ray: &Vector, scene: &Scene, hit: &RayHit, reflect_count: u32) -> LinearColor {
+fn ambient_occlusion(hit: &RayHit, settings: &AmbientOcclusion, scene: &Scene, rng: &mut Rng) -> f64 {
+    if settings.samples == 0 {
+        return 1.0;
+    }
+
+    stats::record(| s | s.occlusion_rays += settings.samples as u64);
+
+    let unoccluded = (0..settings.samples)
+        .filter(| _ | {
+            let delta = sample_cosine_hemisphere(hit.normal, rng);
+
+            trace::begin_ray(RayKind::Occlusion, 0, &Ray { start: hit.hit_point, delta }, Some(settings.distance));
+
+            let visible = is_visible(&hit.hit_point, delta, settings.distance, scene);
+
+            trace::end_ray(RayResult::Visible(visible));
+
+            visible
+        })
+        .count();
+
+    unoccluded as f64 / settings.samples as f64
+}
+
+fn reflected_ray(ray: &Ray, hit: &RayHit) -> Ray {
+    Ray {
+        start: hit.hit_point,
+        delta: reflect(ray.delta, hit.normal)
+    }
+}
+
+// The ray that carries on through a surface, bent by Snell's law, or
+// None if the light is totally reflected instead.
+fn refracted_ray(ray: &Ray, hit: &RayHit, ior: f64) -> Option<Ray> {
+    let eta = if hit.front_face { 1.0 / ior } else { ior };
+
+    refract(ray.delta, hit.normal, eta).map(| delta | Ray { start: hit.hit_point, delta })
+}
+
+// The parts of a surface's shaded color, kept apart for the AOV
+// passes.
+pub struct ShadingTerms {
+    pub albedo: LinearColor,
+    pub ambient: LinearColor,
+    pub reflected: LinearColor,
+
+    // Light that came through the surface from behind.
+    pub transmitted: LinearColor,
+
+    // Light arriving straight from lights, area lights, and the
+    // environment, and the part of that in specular highlights.
+    pub direct: LinearColor,
+    pub specular: LinearColor,
+
+    // Average fraction of light blocked along the shadow rays cast from
+    // the surface, or zero if there weren't any.
+    pub shadow: f64,
+
+    // Everything put together.
+    pub color: LinearColor,
+}
+
+impl ShadingTerms {
+    fn map_colors<F: Fn(&LinearColor) -> LinearColor>(&self, convert: F) -> ShadingTerms {
+        ShadingTerms {
+            albedo: convert(&self.albedo),
+            ambient: convert(&self.ambient),
+            reflected: convert(&self.reflected),
+            transmitted: convert(&self.transmitted),
+            direct: convert(&self.direct),
+            specular: convert(&self.specular),
+            shadow: self.shadow,
+            color: convert(&self.color),
+        }
+    }
+}
+
+// A surface seen along a ray, and how it was shaded.
+pub struct ShadedHit {
+    pub object: usize,
+    pub hit: RayHit,
+    pub terms: ShadingTerms,
+}
+
+fn shade_pixel(ray: &Ray, scene: &Scene, hit: &RayHit, reflect_count: u32, rng: &mut Rng) -> ShadingTerms {
     // https://en.wikipedia.org/wiki/Lambertian_reflectance
 
     let scolor = if hit.surface.checked {
-        let checkidx = (((hit.hit_point[0] + EPSILON).floor() +
-                         (hit.hit_point[1] + EPSILON).floor() +
-                         (hit.hit_point[2] + EPSILON).floor()) as i64 % 2).abs();
+        let checkidx = (((hit.hit_point.x + EPSILON).floor() +
+                         (hit.hit_point.y + EPSILON).floor() +
+                         (hit.hit_point.z + EPSILON).floor()) as i64 % 2).abs();
 
-        scale_linear_color(&hit.surface.color, if checkidx == 0 { 1.0 } else { 0.5 })
+        hit.surface.color * if checkidx == 0 { 1.0 } else { 0.5 }
     } else {
         hit.surface.color
     };
 
-    let ambient: LinearColor = scale_linear_color(&scolor, hit.surface.ambient);
+    let scolor = scene.working_color(&scolor);
+
+    let occlusion = match &scene.ambient_occlusion {
+        Some(settings) if hit.surface.ambient > EPSILON => ambient_occlusion(hit, settings, scene, rng),
+        _ => 1.0
+    };
+
+    let ambient: LinearColor = scolor * (hit.surface.ambient * occlusion);
+
+    let reflected: LinearColor = if (hit.surface.reflection > EPSILON) && (reflect_count < scene.reflect_limit) {
+        stats::record(| s | s.reflection_rays += 1);
+
+        let rcolor = ray_color(&reflected_ray(ray, hit), scene, reflect_count + 1, ShadingMode::Full, rng);
+
+        rcolor * scene.working_color(&hit.surface.reflection_color) * hit.surface.reflection
+    } else {
+        LinearColor::BLACK
+    };
+
+    let transmitted: LinearColor = if (hit.surface.transmission > EPSILON) && (reflect_count < scene.reflect_limit) {
+        stats::record(| s | s.refraction_rays += 1);
+
+        let ior = hit.surface.ior.at(spectral::wavelength().unwrap_or(spectral::REFERENCE_WAVELENGTH));
+        let tray = refracted_ray(ray, hit, ior).unwrap_or_else(|| reflected_ray(ray, hit));
+
+        let tcolor = ray_color(&tray, scene, reflect_count + 1, ShadingMode::Full, rng);
+
+        tcolor * scolor * hit.surface.transmission
+    } else {
+        LinearColor::BLACK
+    };
+
+    let shadows_before = aov::shadow_tally();
+
+    let (light, light_specular): LightColor = scene.lights
+        .iter()
+        .filter_map(| light | {
+            light_vector(&hit.hit_point, light, scene, rng)
+                .map(| (delta, intensity) | (delta, intensity, scene.working_color(&light.color())))
+        })
+        .fold((LinearColor::BLACK, LinearColor::BLACK), | (lc, specular), (delta, intensity, lcolor) | {
+            let kspecular = (ray.delta + delta).normalize().map_or(0.0, | half | hit.normal.dot(half).powf(50.0));
+            let kdiffuse = hit.normal.dot(-delta).max(0.0);
+
+            let scolor_specular = lcolor * (kspecular * hit.surface.specular * intensity);
+
+            (lc + (scolor_specular + scolor * lcolor * (hit.surface.light * kdiffuse * intensity)),
+             specular + scolor_specular)
+        });
+
+    let (area_light, area_light_specular): LightColor = scene.objects
+        .iter()
+        .filter_map(| obj | obj.emitter())
+        .fold((LinearColor::BLACK, LinearColor::BLACK), | (lc, specular), emitter | {
+            let (color, emitter_specular) = area_light_color(ray, scene, hit, &scolor, emitter, rng);
+
+            (lc + color, specular + emitter_specular)
+        });
+
+    let (environment_light, environment_specular): LightColor = if scene.environment.is_black() {
+        (LinearColor::BLACK, LinearColor::BLACK)
+    } else {
+        environment_light_color(ray, scene, hit, &scolor, rng)
+    };
+
+    let shadow = aov::shadow_tally().blocked_since(&shadows_before);
+
+    let emitted: LinearColor = scene.working_color(&hit.surface.emission) * hit.surface.emission_strength;
+
+    trace::record_contribution("ambient", &ambient);
+    trace::record_contribution("reflected", &reflected);
+    trace::record_contribution("transmitted", &transmitted);
+    trace::record_contribution("light", &light);
+    trace::record_contribution("area_light", &area_light);
+    trace::record_contribution("environment_light", &environment_light);
+    trace::record_contribution("emitted", &emitted);
+
+    let direct = light + (area_light + environment_light);
+
+    ShadingTerms {
+        albedo: scolor,
+        ambient,
+        reflected,
+        transmitted,
+        direct,
+        specular: light_specular + (area_light_specular + environment_specular),
+        shadow,
+        color: reflected + emitted + (ambient + direct) + transmitted,
+    }
+}
+
+// Stands in for shade_pixel in the debugging views.
+fn debug_color(
+    ray: &Ray, scene: &Scene, object: usize, hit: &RayHit, reflect_count: u32, mode: ShadingMode, rng: &mut Rng
+) -> LinearColor {
+    match mode {
+        ShadingMode::Normals => debug::normal_color(&hit.normal),
+        ShadingMode::Depth => debug::depth_color(hit.distance),
+        ShadingMode::ObjectId => debug::object_color(object),
+        ShadingMode::Uv => debug::uv_color(scene.objects[object].uv(hit)),
+        ShadingMode::ReflectionDepth if hit.surface.reflection > EPSILON && reflect_count < scene.reflect_limit => {
+            stats::record(| s | s.reflection_rays += 1);
+
+            ray_color(&reflected_ray(ray, hit), scene, reflect_count + 1, mode, rng)
+        },
+        _ => debug::reflection_depth_color(reflect_count, scene.reflect_limit)
+    }
+}
+
+fn ray_color(ray: &Ray, scene: &Scene, reflect_count: u32, mode: ShadingMode, rng: &mut Rng) -> LinearColor {
+    trace_ray(ray, scene, reflect_count, mode, rng).color
+}
+
+// What was seen along a ray: its color, whether it hit anything, and
+// the surface it hit, if that surface was shaded. Debugging views don't
+// shade surfaces.
+struct TracedRay {
+    color: LinearColor,
+    hit: bool,
+    shaded: Option<ShadedHit>,
+}
+
+impl TracedRay {
+    // Converts what was seen at a single wavelength into color in the
+    // working space. Everything traced at one wavelength has the same
+    // value in all three channels.
+    fn spectrum_to_rgb(self, wavelength: f64, space: ColorSpace) -> TracedRay {
+        let convert = | color: &LinearColor | spectral::spectrum_to_rgb(color[1], wavelength, space);
+
+        TracedRay {
+            color: convert(&self.color),
+            shaded: self.shaded.map(| shaded | ShadedHit { terms: shaded.terms.map_colors(convert), .. shaded }),
+            .. self
+        }
+    }
+}
+
+fn trace_ray(
+    ray: &Ray, scene: &Scene, reflect_count: u32, mode: ShadingMode, rng: &mut Rng
+) -> TracedRay {
+    stats::record(| s | s.max_depth = s.max_depth.max(reflect_count));
+
+    let kind = if reflect_count == 0 { RayKind::Camera } else { RayKind::Reflection };
 
-    let reflected: LinearColor = if (hit.surface.reflection > EPSILON) && (reflect_count >= scene.reflect_limit) {
-        let rvec = subp(negp(ray.delta), scalep(hit.normal, 2.0 * dotp(negp(ray.delta), hit.normal)));
+    trace::begin_ray(kind, reflect_count, ray, None);
+
+    let hit = nearest_hit_object(ray, &scene.objects);
+
+    if let Some((object, hit)) = &hit {
+        trace::record_hit(*object, scene.objects[*object].as_ref(), hit);
+    }
 
-        let rcolor = ray_color(&Vector {
-            start: hit.hit_point,
-            delta: normalizep(rvec)
-        }, scene, reflect_count + 1);
+    let is_hit = hit.is_some();
 
-        scale_linear_color(&rcolor, hit.surface.reflection)
+    let (color, shaded) = if mode.is_debug_view() {
+        let color = match hit {
+            Some((object, hit)) => debug_color(ray, scene, object, &hit, reflect_count, mode, rng),
+            None if mode == ShadingMode::ReflectionDepth && reflect_count > 0 => {
+                debug::reflection_depth_color(reflect_count, scene.reflect_limit)
+            },
+            None => LinearColor::BLACK
+        };
+
+        (color, None)
     } else {
-        [0.0, 0.0, 0.0]
+        match hit {
+            Some((object, hit)) => {
+                let terms = shade_pixel(ray, scene, &hit, reflect_count, rng);
+                let color = volume::attenuate(ray, hit.distance, terms.color, scene, rng);
+
+                (color, Some(ShadedHit { object, hit, terms }))
+            },
+            None => {
+                let background = scene.working_color(&scene.environment.color(&ray.delta));
+                let color = volume::attenuate(ray, f64::INFINITY, background, scene, rng);
+
+                (color, None)
+            }
+        }
     };
 
-    let light: LinearColor = match light_vector(&hit.hit_point, scene) {
-        Some(lv) => {
-            let kspecular = f64::powf(dotp(hit.normal, normalizep(addp(ray.delta, lv.delta))), 50.0) as f64;
+    trace::end_ray(RayResult::Color(color));
 
-            add_linear_color(&scale_linear_color(&[1.0, 1.0, 1.0], kspecular * hit.surface.specular),
-                             &scale_linear_color(&scolor, hit.surface.light * dotp(hit.normal, negp(lv.delta)) as f64))
+    TracedRay {
+        color,
+        hit: is_hit,
+        shaded,
+    }
+}
+
+fn ambient_occlusion_color(ray: &Ray, scene: &Scene, rng: &mut Rng) -> TracedRay {
+    let settings = scene.ambient_occlusion.unwrap_or(DEFAULT_AMBIENT_OCCLUSION);
+
+    trace::begin_ray(RayKind::Camera, 0, ray, None);
 
+    let hit = nearest_hit_object(ray, &scene.objects);
+
+    let occlusion = match &hit {
+        Some((object, hit)) => {
+            trace::record_hit(*object, scene.objects[*object].as_ref(), hit);
+
+            ambient_occlusion(hit, &settings, scene, rng)
         },
-        None => [0.0, 0.0, 0.0]
+        None => 1.0
     };
 
-    add_linear_color(&reflected, &add_linear_color(&ambient, &light))
+    let color = LinearColor::gray(occlusion);
+
+    trace::end_ray(RayResult::Color(color));
+
+    TracedRay {
+        color,
+        hit: hit.is_some(),
+        shaded: None,
+    }
 }
 
-fn ray_color(ray: &Vector, scene: &Scene, reflect_count: u32) -> LinearColor {
-    match nearest_hit(ray, &scene.objects) {
-        Some(hit) => shade_pixel(ray, scene, &hit, reflect_count),
-        None => scene.background
+fn max_oversample(camera: &CameraDetails, scene: &Scene) -> u32 {
+    match scene.adaptive {
+        Some(adaptive) => adaptive.max_oversample.max(camera.oversample),
+        None => camera.oversample
     }
 }
 
-fn pixel_color(
+// A sample taken for a pixel: its position within the pixel, the color
+// seen there, whether the camera ray hit anything, and its AOVs, if
+// they're being collected.
+struct PixelSample {
+    position: (f64, f64),
+    color: LinearColor,
+    coverage: f64,
+    aov: Option<AovSample>,
+}
+
+const GOLDEN_RATIO_CONJUGATE: f64 = 0.618033988749895;
+
+fn pixel_samples(
     camera: &CameraDetails,
+    options: &RenderOptions,
     scene: &Scene,
     x: u32,
     y: u32,
-) -> LinearColor {
-    let subdx = camera.dx / (camera.oversample as f64 * 2.0);
-    let subdy = camera.dy / (camera.oversample as f64 * 2.0);
-
+    pass: u32,
+) -> Vec<PixelSample> {
     let xc = x as f64 * camera.dx - camera.dx / 2.0;
     let yc = y as f64 * camera.dy - camera.dy / 2.0;
 
-    let mut pc = [0.0, 0.0, 0.0];
-    for iix in 0..scene.oversample {
-        for iiy in 0..scene.oversample {
+    let mut rng = Rng::for_pixel(x, y, pass);
+
+    let positions = match scene.adaptive {
+        Some(_) => scene.sampler.progressive_samples(max_oversample(camera, scene), &mut rng.split()),
+        None => scene.sampler.pixel_samples(camera.oversample, &mut rng.split())
+    };
+
+    let batch = (camera.oversample * camera.oversample).max(1) as usize;
 
-            let xt = xc + subdx * (1 + 2 * iix) as f64;
-            let yt = yc + subdy * (1 + 2 * iiy) as f64;
+    // Each sample in spectral mode takes a wavelength a golden ratio
+    // further along the spectrum than the last, from a random start,
+    // which spreads them evenly over however many samples are taken.
+    let spectral = options.spectral && options.shading == ShadingMode::Full;
+    let wavelength_start = if spectral { rng.next_f64() } else { 0.0 };
 
-            let rc = ray_color(&camera_ray(&camera.camera, xt, yt), scene, 0);
+    let mut samples = Vec::with_capacity(positions.len());
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
 
-            pc = add_linear_color(&pc, &rc)
+    for (sx, sy) in positions.iter() {
+        let count = samples.len();
+
+        if let Some(adaptive) = scene.adaptive {
+            if count >= 2 && count % batch == 0 {
+                let n = count as f64;
+                let variance = (sum_squares - sum * sum / n).max(0.0) / (n - 1.0);
+
+                if (variance / n).sqrt() < adaptive.threshold {
+                    break;
+                }
+            }
         }
+
+        let xt = xc + camera.dx * sx;
+        let yt = yc + camera.dy * sy;
+
+        let ray = match camera_ray(&camera.camera, xt, yt) {
+            Some(ray) => ray,
+            None => continue
+        };
+
+        stats::record(| s | s.primary_rays += 1);
+
+        let wavelength = if spectral {
+            Some(spectral::sample_wavelength((wavelength_start + count as f64 * GOLDEN_RATIO_CONJUGATE).fract()))
+        } else {
+            None
+        };
+
+        spectral::set_wavelength(wavelength);
+
+        let traced = match options.shading {
+            ShadingMode::AmbientOcclusion => ambient_occlusion_color(&ray, scene, &mut rng),
+            ShadingMode::IntersectionHeatmap => {
+                let tests_before = stats::read(RenderStats::total_tests);
+
+                let traced = trace_ray(&ray, scene, 0, ShadingMode::Full, &mut rng);
+
+                TracedRay {
+                    color: debug::tests_color(stats::read(RenderStats::total_tests) - tests_before),
+                    shaded: None,
+                    .. traced
+                }
+            },
+            mode => trace_ray(&ray, scene, 0, mode, &mut rng)
+        };
+
+        spectral::set_wavelength(None);
+
+        let traced = match wavelength {
+            Some(wavelength) => traced.spectrum_to_rgb(wavelength, scene.color_space),
+            None => traced
+        };
+
+        // A NaN or infinite sample would spread through the filter and
+        // spoil every pixel it touches, so it's counted as black.
+        let rc = if (options.alpha && !traced.hit) || !traced.color.is_finite() {
+            LinearColor::BLACK
+        } else {
+            traced.color
+        };
+
+        let l = rc.luminance();
+        sum += l;
+        sum_squares += l * l;
+
+        samples.push(PixelSample {
+            position: (*sx, *sy),
+            color: rc,
+            coverage: if traced.hit { 1.0 } else { 0.0 },
+            aov: if options.aovs { Some(AovSample::new(traced.shaded.as_ref())) } else { None },
+        });
     }
 
-    scale_linear_color(&pc, 1.0 / (scene.oversample * scene.oversample) as f64)
+    if options.shading == ShadingMode::SampleHeatmap {
+        let max_samples = max_oversample(camera, scene).pow(2);
+        let heat = heatmap_color(samples.len() as f64 / max_samples as f64);
+
+        for sample in samples.iter_mut() {
+            sample.color = heat;
+        }
+    }
+
+    samples
 }
 
-fn render_into_line(
+// Renders a tile into a film with a margin wide enough to catch
+// everything the filter spreads outside of the tile. Also returns the
+// number of camera rays traced.
+fn render_tile(
     camera: &CameraDetails,
+    options: &RenderOptions,
     scene: &Scene,
-    row: image::buffer::EnumeratePixelsMut<image::Rgb<u8>>
-) {
-    for (x, y, pixel) in row {
-        let pc = pixel_color(camera, scene, x, y);
-        *pixel = image::Rgb(to_png_color(&pc))
+    tile: &Tile,
+    pass: u32,
+) -> (Film, u64) {
+    let margin = scene.filter.radius().ceil() as i64;
+
+    let mut film = Film::window(tile.x0 as i64 - margin, tile.y0 as i64 - margin,
+                                (tile.x1 - tile.x0) as usize + 2 * margin as usize,
+                                (tile.y1 - tile.y0) as usize + 2 * margin as usize);
+
+    if options.aovs {
+        film.enable_aovs();
     }
-}
 
-pub fn render(
-    scene: &Scene, imgx: u32, imgy: u32, parallel: bool
-) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+    let mut rays = 0;
+
+    // Drop anything counted on this thread outside of rendering tiles.
+    stats::take();
+
+    for y in tile.y0..tile.y1 {
+        for x in tile.x0..tile.x1 {
+            let samples = pixel_samples(camera, options, scene, x, y, pass);
+
+            rays += samples.len() as u64;
+
+            for sample in samples {
+                let (sx, sy) = (x as f64 + sample.position.0, y as f64 + sample.position.1);
 
-    let mut imgbuf = image::ImageBuffer::new(imgx, imgy);
+                film.add_sample(sx, sy, &sample.color, sample.coverage, &scene.filter);
 
-    let camera = CameraDetails {
+                if let Some(aov) = &sample.aov {
+                    film.add_aov_sample(sx, sy, aov);
+                }
+            }
+        }
+    }
+
+    let tile_stats = stats::take();
+
+    if let Some(collected) = &options.stats {
+        collected.lock().unwrap().add(&tile_stats);
+    }
+
+    (film, rays)
+}
+
+fn camera_details(scene: &Scene, imgx: u32, imgy: u32) -> CameraDetails {
+    CameraDetails {
         camera: scene.camera,
         dx: 1.0 / imgx as f64,
         dy: 1.0 / imgy as f64,
         oversample: scene.oversample
-    };
+    }
+}
+
+const MIN_REGION_TILE_SIZE: u32 = 8;
 
-    if parallel {
-        imgbuf.enumerate_rows_mut()
-            .par_bridge()
-            .for_each(| (_, row ) | render_into_line(&camera, scene, row));
+// Renders one region of an image, for when regions are farmed out
+// somewhere other than render_pass. The region is split into tiles and
+// rendered with the same threads as a whole image would be. The film
+// covers the region plus the filter's margin, ready to be merged into
+// the full image, and comes back with the number of camera rays traced.
+pub fn render_region(
+    scene: &Scene, imgx: u32, imgy: u32, options: &RenderOptions, region: &Tile, pass: u32
+) -> (Film, u64) {
+
+    let camera = camera_details(scene, imgx, imgy);
+
+    let (width, height) = (region.x1 - region.x0, region.y1 - region.y0);
+
+    // Regions are often a single tile of the full image, so split them
+    // finer until every thread has a tile to work on.
+    let threads = if options.parallel {
+        options.threads.unwrap_or_else(rayon::current_num_threads)
     } else {
-        for (_, row) in imgbuf.enumerate_rows_mut() {
-            render_into_line(&camera, scene, row)
+        1
+    };
+
+    let mut tile_size = options.tile_size.max(1);
+
+    while tile_size > MIN_REGION_TILE_SIZE
+        && (width.div_ceil(tile_size) * height.div_ceil(tile_size)) < threads as u32 {
+        tile_size /= 2;
+    }
+
+    let tiles: Vec<Tile> = options.tile_order
+        .tiles(width, height, tile_size)
+        .iter()
+        .map(| tile | Tile {
+            x0: region.x0 + tile.x0,
+            y0: region.y0 + tile.y0,
+            x1: region.x0 + tile.x1,
+            y1: region.y0 + tile.y1,
+        })
+        .collect();
+
+    let (tile_films, rays) = render_tiles(&camera, options, scene, &tiles, pass);
+
+    let margin = scene.filter.radius().ceil() as i64;
+
+    let mut film = Film::window(region.x0 as i64 - margin, region.y0 as i64 - margin,
+                                (region.x1 - region.x0) as usize + 2 * margin as usize,
+                                (region.y1 - region.y0) as usize + 2 * margin as usize);
+
+    if options.aovs {
+        film.enable_aovs();
+    }
+
+    for tile_film in tile_films {
+        if let Some(tile_film) = tile_film.into_inner().unwrap() {
+            film.merge(&tile_film);
         }
     }
 
-    imgbuf
+    (film, rays)
 }
 
+// Traces every ray behind one pixel of an image, through the same code
+// as a normal render. The pixel's color comes from rendering it along
+// with the neighbors whose samples the filter spreads into it.
+pub fn trace_pixel(scene: &Scene, imgx: u32, imgy: u32, options: &RenderOptions, x: u32, y: u32) -> PixelTrace {
+    let camera = camera_details(scene, imgx, imgy);
+
+    trace::start();
+
+    let samples = pixel_samples(&camera, options, scene, x, y, 0);
+
+    let rays = trace::finish();
+
+    let margin = scene.filter.radius().ceil() as u32;
+
+    let neighborhood = Tile {
+        x0: x.saturating_sub(margin),
+        y0: y.saturating_sub(margin),
+        x1: (x + margin + 1).min(imgx),
+        y1: (y + margin + 1).min(imgy),
+    };
+
+    let (film, _) = render_tile(&camera, options, scene, &neighborhood, 0);
+
+    PixelTrace {
+        x,
+        y,
+        samples: samples.iter().map(| sample | sample.position).zip(rays).collect(),
+        color: film.pixel(x as i64, y as i64),
+    }
+}
+
+// Renders one pass of samples over the whole image, with each pass
+// seeding its random numbers differently. If the render is stopped
+// part way through, this returns what was finished, and false.
+pub fn render_pass(
+    scene: &Scene, imgx: u32, imgy: u32, options: &RenderOptions, pass: u32
+) -> (Film, bool) {
+
+    let camera = camera_details(scene, imgx, imgy);
+
+    let tiles = options.tile_order.tiles(imgx, imgy, options.tile_size);
+
+    let start = Instant::now();
+
+    let (tile_films, _) = render_tiles(&camera, options, scene, &tiles, pass);
+
+    options.record_phase("render", start.elapsed());
+
+    let assemble_start = Instant::now();
+    let assembled = assemble_tiles(imgx, imgy, &tiles, tile_films);
+
+    options.record_phase("assemble", assemble_start.elapsed());
+
+    assembled
+}
+
+// Renders tiles on the threads asked for in the options, reporting
+// progress as each one finishes. Tiles that weren't rendered because
+// the render was stopped are left empty. Also returns the number of
+// camera rays traced.
+fn render_tiles(
+    camera: &CameraDetails, options: &RenderOptions, scene: &Scene, tiles: &[Tile], pass: u32
+) -> (Vec<Mutex<Option<Film>>>, u64) {
+
+    let pixels_total: u64 = tiles.iter().map(Tile::pixel_count).sum();
+
+    let start = Instant::now();
+
+    let next_tile = AtomicUsize::new(0);
+
+    // Tiles, pixels, and rays finished so far.
+    let done = Mutex::new((0, 0, 0));
+
+    let is_stopped = || options.stop.as_ref().is_some_and(| stop | stop.load(SeqCst));
+
+    let tile_films: Vec<Mutex<Option<Film>>> = tiles.iter().map(| _ | Mutex::new(None)).collect();
+
+    // Each worker repeatedly takes the next tile in order until there
+    // are none left, so the image fills in in the chosen order and
+    // faster workers pick up more tiles.
+    let work = || loop {
+        if is_stopped() {
+            break;
+        }
+
+        let idx = next_tile.fetch_add(1, SeqCst);
+
+        if idx >= tiles.len() {
+            break;
+        }
+
+        let (film, tile_rays) = render_tile(camera, options, scene, &tiles[idx], pass);
+
+        *tile_films[idx].lock().unwrap() = Some(film);
+
+        // Report while holding the lock, so reports arrive in order and
+        // the last one is always for the finished render.
+        let mut done = done.lock().unwrap();
+        done.0 += 1;
+        done.1 += tiles[idx].pixel_count();
+        done.2 += tile_rays;
+
+        if let Some(report) = &options.progress {
+            report(&Progress {
+                tiles_done: done.0,
+                tiles_total: tiles.len(),
+                pixels_done: done.1,
+                pixels_total,
+                rays: done.2,
+                elapsed: start.elapsed(),
+            });
+        }
+    };
+
+    if options.parallel {
+        let run = || rayon::scope(| s | {
+            for _ in 0..rayon::current_num_threads() {
+                s.spawn(| _ | work());
+            }
+        });
+
+        // If a pool of the requested size can't be built, fall back on
+        // the default one.
+        match options.threads.map(| threads | rayon::ThreadPoolBuilder::new().num_threads(threads).build()) {
+            Some(Ok(pool)) => pool.install(run),
+            _ => run()
+        }
+    } else {
+        work();
+    }
+
+    let rays = done.into_inner().unwrap().2;
+
+    (tile_films, rays)
+}
+
+// Merges rendered tiles into a film for the whole image, returning it
+// along with whether every tile was there. Tiles are merged in
+// scanline order, whatever order they were rendered in, so that every
+// schedule gives identical results.
+pub fn assemble_tiles(
+    imgx: u32, imgy: u32, tiles: &[Tile], tile_films: Vec<Mutex<Option<Film>>>
+) -> (Film, bool) {
+
+    let mut tile_films: Vec<(Tile, Film)> = tiles.iter()
+        .zip(tile_films)
+        .filter_map(| (tile, film) | film.into_inner().unwrap().map(| film | (*tile, film)))
+        .collect();
+
+    let complete = tile_films.len() == tiles.len();
+
+    tile_films.sort_by_key(| (tile, _) | (tile.y0, tile.x0));
+
+    let mut film = Film::new(imgx, imgy);
+
+    for (_, tile_film) in tile_films.iter() {
+        film.merge(tile_film);
+    }
+
+    (film, complete)
+}
+
+// Renders an image into a film, which holds the final color and any
+// AOVs asked for in the options.
+pub fn render(scene: &Scene, imgx: u32, imgy: u32, options: &RenderOptions) -> Film {
+    let (film, _) = render_pass(scene, imgx, imgy, options, 0);
+
+    film
+}
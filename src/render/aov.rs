@@ -0,0 +1,428 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Arbitrary output variables: buffers of what the camera saw at each
+// pixel besides its final color, for relighting and compositing
+// without rendering again.
+//
+// AOVs aren't spread by the reconstruction filter, since blending depths
+// or IDs across neighboring pixels makes no sense. Instead, each pixel
+// averages the samples taken inside it. Surface properties are averaged
+// over the samples that hit something, and lighting over all samples.
+// IDs come from the sample nearest the pixel center.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+
+use crate::render::{ShadedHit, Surface};
+use crate::render::spectral::REFERENCE_WAVELENGTH;
+use crate::render::geometry::Vector;
+
+use crate::render::color::{
+    LinearColor,
+};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Aov {
+    // Distance along the camera ray.
+    Depth,
+
+    // World space surface normal and position.
+    Normal,
+    Position,
+
+    // Surface color, before any lighting.
+    Albedo,
+
+    // Light straight from lights, area lights, and the environment,
+    // and the specular highlights within that.
+    Direct,
+    Specular,
+
+    // Ambient light plus reflections and light through transparent
+    // surfaces.
+    Indirect,
+
+    Reflection,
+
+    // Fraction of the light sampled at the surface that was blocked.
+    Shadow,
+
+    // Object index, counting from one, and a hash of the surface
+    // properties. Zero where nothing was hit.
+    ObjectId,
+    MaterialId,
+}
+
+pub const ALL_AOVS: [Aov; 11] = [
+    Aov::Depth,
+    Aov::Normal,
+    Aov::Position,
+    Aov::Albedo,
+    Aov::Direct,
+    Aov::Specular,
+    Aov::Indirect,
+    Aov::Reflection,
+    Aov::Shadow,
+    Aov::ObjectId,
+    Aov::MaterialId,
+];
+
+impl Aov {
+    pub fn from_name(name: &str) -> Option<Aov> {
+        ALL_AOVS.iter().copied().find(| aov | aov.name() == name.to_lowercase())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Position => "position",
+            Aov::Albedo => "albedo",
+            Aov::Direct => "direct",
+            Aov::Specular => "specular",
+            Aov::Indirect => "indirect",
+            Aov::Reflection => "reflection",
+            Aov::Shadow => "shadow",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id"
+        }
+    }
+
+    // Names of the channels the AOV is stored in, following the usual
+    // conventions for compositing.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Albedo | Aov::Direct | Aov::Specular | Aov::Indirect | Aov::Reflection => &["R", "G", "B"],
+            Aov::Shadow => &["A"],
+            Aov::ObjectId | Aov::MaterialId => &["id"]
+        }
+    }
+}
+
+// Shadow rays cast on the current thread, and the total light that got
+// through them, for working out how much of a surface is in shadow.
+#[derive(Copy, Clone, Default)]
+pub struct ShadowTally {
+    rays: u64,
+    transmittance: f64,
+}
+
+thread_local! {
+    static SHADOW_TALLY: Cell<ShadowTally> = Cell::new(ShadowTally::default());
+}
+
+pub fn record_shadow(transmittance: f64) {
+    SHADOW_TALLY.with(| tally | {
+        let ShadowTally { rays, transmittance: total } = tally.get();
+
+        tally.set(ShadowTally {
+            rays: rays + 1,
+            transmittance: total + transmittance,
+        });
+    });
+}
+
+pub fn shadow_tally() -> ShadowTally {
+    SHADOW_TALLY.with(Cell::get)
+}
+
+impl ShadowTally {
+    // The average fraction of light blocked along the shadow rays cast
+    // since an earlier tally.
+    pub fn blocked_since(&self, earlier: &ShadowTally) -> f64 {
+        let rays = self.rays - earlier.rays;
+
+        if rays == 0 {
+            0.0
+        } else {
+            1.0 - (self.transmittance - earlier.transmittance) / rays as f64
+        }
+    }
+}
+
+// A hash of everything about a surface, so that objects sharing a
+// surface share an ID. Kept to 24 bits, which are stored exactly as
+// 32-bit floats.
+fn material_id(surface: &Surface) -> u32 {
+    let values = [
+        surface.color.r, surface.color.g, surface.color.b,
+        surface.ambient, surface.specular, surface.light,
+        if surface.checked { 1.0 } else { 0.0 },
+        surface.reflection,
+        surface.reflection_color.r, surface.reflection_color.g, surface.reflection_color.b,
+        surface.emission.r, surface.emission.g, surface.emission.b,
+        surface.emission_strength,
+        surface.transmission,
+        surface.ior.at(REFERENCE_WAVELENGTH),
+    ];
+
+    // FNV-1a
+    let hash = values.iter()
+        .flat_map(| value | value.to_bits().to_le_bytes())
+        .fold(0xcbf29ce484222325u64, | hash, byte | (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+
+    ((hash ^ (hash >> 24) ^ (hash >> 48)) as u32 & 0xffffff).max(1)
+}
+
+// What one camera ray saw, in the terms of the AOVs.
+#[derive(Copy, Clone)]
+pub struct AovSample {
+    hit: bool,
+    depth: f64,
+
+    // Normals and positions are summed over samples to average them, so
+    // they're kept as vectors.
+    normal: Vector,
+    position: Vector,
+    albedo: LinearColor,
+    direct: LinearColor,
+    specular: LinearColor,
+    indirect: LinearColor,
+    reflection: LinearColor,
+    shadow: f64,
+    object_id: u32,
+    material_id: u32,
+}
+
+impl AovSample {
+    pub fn new(shaded: Option<&ShadedHit>) -> AovSample {
+        match shaded {
+            Some(ShadedHit { object, hit, terms }) => AovSample {
+                hit: true,
+                depth: hit.distance,
+                normal: hit.normal.to_vector(),
+                position: hit.hit_point.to_vector(),
+                albedo: terms.albedo,
+                direct: terms.direct,
+                specular: terms.specular,
+                indirect: terms.ambient + terms.reflected + terms.transmitted,
+                reflection: terms.reflected,
+                shadow: terms.shadow,
+                object_id: *object as u32 + 1,
+                material_id: material_id(&hit.surface),
+            },
+            None => AovSample {
+                hit: false,
+                depth: 0.0,
+                normal: Vector::ZERO,
+                position: Vector::ZERO,
+                albedo: LinearColor::BLACK,
+                direct: LinearColor::BLACK,
+                specular: LinearColor::BLACK,
+                indirect: LinearColor::BLACK,
+                reflection: LinearColor::BLACK,
+                shadow: 0.0,
+                object_id: 0,
+                material_id: 0,
+            }
+        }
+    }
+}
+
+// Running totals for one pixel.
+#[derive(Copy, Clone)]
+struct AovPixel {
+    samples: u32,
+    hits: u32,
+    sum: AovSample,
+
+    // The IDs of the sample nearest the pixel center, and its squared
+    // distance from there.
+    id_distance: f64,
+    object_id: u32,
+    material_id: u32,
+}
+
+fn read_f64<R: Read>(input: &mut R) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+
+    Ok(f64::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_vector<R: Read>(input: &mut R) -> io::Result<Vector> {
+    Ok(Vector::new(read_f64(input)?, read_f64(input)?, read_f64(input)?))
+}
+
+fn read_color<R: Read>(input: &mut R) -> io::Result<LinearColor> {
+    Ok(LinearColor::new(read_f64(input)?, read_f64(input)?, read_f64(input)?))
+}
+
+// AOVs for every pixel of a film, indexed the same way as the film.
+pub struct AovBuffer {
+    pixels: Vec<AovPixel>,
+}
+
+impl AovBuffer {
+    pub fn new(len: usize) -> AovBuffer {
+        AovBuffer {
+            pixels: vec![AovPixel {
+                samples: 0,
+                hits: 0,
+                sum: AovSample::new(None),
+                id_distance: f64::INFINITY,
+                object_id: 0,
+                material_id: 0,
+            }; len],
+        }
+    }
+
+    // Adds a sample, given its squared distance from the pixel center.
+    pub fn add(&mut self, idx: usize, center_distance: f64, sample: &AovSample) {
+        let pixel = &mut self.pixels[idx];
+        let sum = &mut pixel.sum;
+
+        pixel.samples += 1;
+
+        if sample.hit {
+            pixel.hits += 1;
+
+            sum.depth += sample.depth;
+            sum.normal += sample.normal;
+            sum.position += sample.position;
+            sum.shadow += sample.shadow;
+        }
+
+        sum.albedo += sample.albedo;
+        sum.direct += sample.direct;
+        sum.specular += sample.specular;
+        sum.indirect += sample.indirect;
+        sum.reflection += sample.reflection;
+
+        if center_distance < pixel.id_distance {
+            pixel.id_distance = center_distance;
+            pixel.object_id = sample.object_id;
+            pixel.material_id = sample.material_id;
+        }
+    }
+
+    // Adds everything from a pixel of another buffer.
+    pub fn merge(&mut self, idx: usize, other: &AovBuffer, oidx: usize) {
+        let from = &other.pixels[oidx];
+        let pixel = &mut self.pixels[idx];
+        let sum = &mut pixel.sum;
+
+        pixel.samples += from.samples;
+        pixel.hits += from.hits;
+
+        sum.depth += from.sum.depth;
+        sum.normal += from.sum.normal;
+        sum.position += from.sum.position;
+        sum.shadow += from.sum.shadow;
+
+        sum.albedo += from.sum.albedo;
+        sum.direct += from.sum.direct;
+        sum.specular += from.sum.specular;
+        sum.indirect += from.sum.indirect;
+        sum.reflection += from.sum.reflection;
+
+        if from.id_distance < pixel.id_distance {
+            pixel.id_distance = from.id_distance;
+            pixel.object_id = from.object_id;
+            pixel.material_id = from.material_id;
+        }
+    }
+
+    // The running totals of every pixel, in the order of the pixels.
+    // The IDs summed into a pixel are never used, so only those of the
+    // sample nearest its center are kept.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for pixel in self.pixels.iter() {
+            let sum = &pixel.sum;
+
+            out.write_all(&pixel.samples.to_le_bytes())?;
+            out.write_all(&pixel.hits.to_le_bytes())?;
+
+            let mut values = vec![sum.depth];
+
+            for vector in [sum.normal, sum.position].iter() {
+                values.extend_from_slice(&vector.to_array());
+            }
+
+            for color in [sum.albedo, sum.direct, sum.specular, sum.indirect, sum.reflection].iter() {
+                values.extend_from_slice(&color.to_array());
+            }
+
+            values.push(sum.shadow);
+            values.push(pixel.id_distance);
+
+            for value in values.iter() {
+                out.write_all(&value.to_le_bytes())?;
+            }
+
+            out.write_all(&pixel.object_id.to_le_bytes())?;
+            out.write_all(&pixel.material_id.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R, len: usize) -> io::Result<AovBuffer> {
+        let mut buffer = AovBuffer::new(len);
+
+        for pixel in buffer.pixels.iter_mut() {
+            pixel.samples = read_u32(input)?;
+            pixel.hits = read_u32(input)?;
+
+            let sum = &mut pixel.sum;
+
+            sum.depth = read_f64(input)?;
+            sum.normal = read_vector(input)?;
+            sum.position = read_vector(input)?;
+            sum.albedo = read_color(input)?;
+            sum.direct = read_color(input)?;
+            sum.specular = read_color(input)?;
+            sum.indirect = read_color(input)?;
+            sum.reflection = read_color(input)?;
+            sum.shadow = read_f64(input)?;
+
+            pixel.id_distance = read_f64(input)?;
+            pixel.object_id = read_u32(input)?;
+            pixel.material_id = read_u32(input)?;
+        }
+
+        Ok(buffer)
+    }
+
+    // The value of an AOV at a pixel, one number per channel. Pixels
+    // where nothing was hit are infinitely deep.
+    pub fn value(&self, idx: usize, aov: Aov) -> Vec<f64> {
+        let pixel = &self.pixels[idx];
+        let sum = &pixel.sum;
+
+        let per_sample = 1.0 / pixel.samples.max(1) as f64;
+        let per_hit = 1.0 / pixel.hits.max(1) as f64;
+
+        match aov {
+            Aov::Depth if pixel.hits == 0 => vec![f64::INFINITY],
+            Aov::Depth => vec![sum.depth * per_hit],
+            Aov::Normal => (sum.normal * per_hit).to_array().to_vec(),
+            Aov::Position => (sum.position * per_hit).to_array().to_vec(),
+            Aov::Albedo => (sum.albedo * per_hit).to_array().to_vec(),
+            Aov::Direct => (sum.direct * per_sample).to_array().to_vec(),
+            Aov::Specular => (sum.specular * per_sample).to_array().to_vec(),
+            Aov::Indirect => (sum.indirect * per_sample).to_array().to_vec(),
+            Aov::Reflection => (sum.reflection * per_sample).to_array().to_vec(),
+            Aov::Shadow => vec![sum.shadow * per_hit],
+            Aov::ObjectId => vec![pixel.object_id as f64],
+            Aov::MaterialId => vec![pixel.material_id as f64]
+        }
+    }
+}
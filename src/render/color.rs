@@ -8,25 +8,229 @@
 //
 // You must not remove this notice, or any other, from this software.
 
-pub type LinearColor = [f64; 3];
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Index, IndexMut, Mul, MulAssign, Sub};
 
-pub fn scale_linear_color(color: &LinearColor, s: f64) -> LinearColor {
-    [
-        color[0] * s,
-        color[1] * s,
-        color[2] * s
-    ]
+// Linear RGB, with no limit on how bright a channel can be. Colors
+// multiply component by component, which is how a surface filters the
+// light that falls on it.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct LinearColor {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
 }
 
-pub fn add_linear_color(colora: &LinearColor, colorb: &LinearColor) -> LinearColor {
-    [
-        colora[0] + colorb[0],
-        colora[1] + colorb[1],
-        colora[2] + colorb[2],
-    ]
+impl LinearColor {
+    pub const BLACK: LinearColor = LinearColor::gray(0.0);
+    pub const WHITE: LinearColor = LinearColor::gray(1.0);
+
+    pub const fn new(r: f64, g: f64, b: f64) -> LinearColor {
+        LinearColor { r, g, b }
+    }
+
+    pub const fn gray(value: f64) -> LinearColor {
+        LinearColor::new(value, value, value)
+    }
+
+    pub const fn from_array(rgb: [f64; 3]) -> LinearColor {
+        LinearColor::new(rgb[0], rgb[1], rgb[2])
+    }
+
+    pub const fn to_array(self) -> [f64; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn map<F: Fn(f64) -> f64>(self, f: F) -> LinearColor {
+        LinearColor::new(f(self.r), f(self.g), f(self.b))
+    }
+
+    // Relative luminance, taking the channels to have Rec. 709
+    // primaries.
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    // Linear interpolation, giving this color at t = 0 and other at
+    // t = 1.
+    pub fn lerp(self, other: LinearColor, t: f64) -> LinearColor {
+        self * (1.0 - t) + other * t
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.r.is_finite() && self.g.is_finite() && self.b.is_finite()
+    }
+
+    // Parses an sRGB hex color, as in "#ff8000" or "f80", into linear
+    // color.
+    pub fn from_hex(hex: &str) -> Option<LinearColor> {
+        let digits = hex.trim().trim_start_matches('#');
+
+        if !digits.chars().all(| c | c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        // Single digits stand for both digits of a byte, as in CSS, so
+        // "f" is 0xff.
+        let channel = | start: usize, len: usize | {
+            let value = u32::from_str_radix(&digits[start..start + len], 16).ok()?;
+            let value = if len == 1 { value * 0x11 } else { value };
+
+            Some(srgb_to_linear(value as f64 / 255.0))
+        };
+
+        match digits.len() {
+            3 => Some(LinearColor::new(channel(0, 1)?, channel(1, 1)?, channel(2, 1)?)),
+            6 => Some(LinearColor::new(channel(0, 2)?, channel(2, 2)?, channel(4, 2)?)),
+            _ => None
+        }
+    }
+
+    // The color as sRGB hex, with channels clamped to [0, 1].
+    pub fn to_hex(self) -> String {
+        let [r, g, b] = to_png_color(&self);
+
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
 }
 
-fn linear_to_srgb(x: f64) -> f64 {
+impl Index<usize> for LinearColor {
+    type Output = f64;
+
+    fn index(&self, channel: usize) -> &f64 {
+        match channel {
+            0 => &self.r,
+            1 => &self.g,
+            2 => &self.b,
+            _ => panic!("Color channel {} out of range", channel)
+        }
+    }
+}
+
+impl IndexMut<usize> for LinearColor {
+    fn index_mut(&mut self, channel: usize) -> &mut f64 {
+        match channel {
+            0 => &mut self.r,
+            1 => &mut self.g,
+            2 => &mut self.b,
+            _ => panic!("Color channel {} out of range", channel)
+        }
+    }
+}
+
+impl Add for LinearColor {
+    type Output = LinearColor;
+
+    fn add(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(self.r + other.r, self.g + other.g, self.b + other.b)
+    }
+}
+
+impl AddAssign for LinearColor {
+    fn add_assign(&mut self, other: LinearColor) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for LinearColor {
+    type Output = LinearColor;
+
+    fn sub(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(self.r - other.r, self.g - other.g, self.b - other.b)
+    }
+}
+
+impl Mul for LinearColor {
+    type Output = LinearColor;
+
+    fn mul(self, other: LinearColor) -> LinearColor {
+        LinearColor::new(self.r * other.r, self.g * other.g, self.b * other.b)
+    }
+}
+
+impl Mul<f64> for LinearColor {
+    type Output = LinearColor;
+
+    fn mul(self, s: f64) -> LinearColor {
+        LinearColor::new(self.r * s, self.g * s, self.b * s)
+    }
+}
+
+impl Mul<LinearColor> for f64 {
+    type Output = LinearColor;
+
+    fn mul(self, color: LinearColor) -> LinearColor {
+        color * self
+    }
+}
+
+impl MulAssign<f64> for LinearColor {
+    fn mul_assign(&mut self, s: f64) {
+        *self = *self * s;
+    }
+}
+
+// Division by a color that's zero in some channel leaves those
+// channels at zero, rather than filling them with NaN or infinity.
+impl Div for LinearColor {
+    type Output = LinearColor;
+
+    fn div(self, other: LinearColor) -> LinearColor {
+        let divide = | a: f64, b: f64 | if b == 0.0 { 0.0 } else { a / b };
+
+        LinearColor::new(divide(self.r, other.r), divide(self.g, other.g), divide(self.b, other.b))
+    }
+}
+
+impl Div<f64> for LinearColor {
+    type Output = LinearColor;
+
+    fn div(self, s: f64) -> LinearColor {
+        LinearColor::new(self.r / s, self.g / s, self.b / s)
+    }
+}
+
+impl Sum for LinearColor {
+    fn sum<I: Iterator<Item = LinearColor>>(colors: I) -> LinearColor {
+        colors.fold(LinearColor::BLACK, | total, color | total + color)
+    }
+}
+
+// CIE XYZ (D65 white) to linear sRGB.
+pub fn xyz_to_linear_color(xyz: &[f64; 3]) -> LinearColor {
+    let [x, y, z] = *xyz;
+
+    LinearColor::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z
+    )
+}
+
+// Maps t in [0, 1] through blue, cyan, green, yellow, and red, for
+// visualizing quantities as false color.
+pub fn heatmap_color(t: f64) -> LinearColor {
+    let t = if t.is_finite() { t.clamp(0.0, 1.0) } else { 1.0 };
+
+    let ramp: [LinearColor; 5] = [
+        LinearColor::new(0.0, 0.0, 1.0),
+        LinearColor::new(0.0, 1.0, 1.0),
+        LinearColor::new(0.0, 1.0, 0.0),
+        LinearColor::new(1.0, 1.0, 0.0),
+        LinearColor::new(1.0, 0.0, 0.0),
+    ];
+
+    let pos = t * (ramp.len() - 1) as f64;
+    let idx = (pos as usize).min(ramp.len() - 2);
+
+    ramp[idx].lerp(ramp[idx + 1], pos - idx as f64)
+}
+
+pub fn linear_to_srgb(x: f64) -> f64 {
     if x < 0.0 {
         0.0
     } else if x < 0.0031308	{
@@ -38,10 +242,65 @@ fn linear_to_srgb(x: f64) -> f64 {
     }
 }
 
+pub fn srgb_to_linear(x: f64) -> f64 {
+    if x < 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Quantizes a display value in [0, 1] to 8 bits. A threshold of one
+// half rounds to the nearest level; dithering varies it over [0, 1)
+// from pixel to pixel.
+pub fn quantize_u8(value: f64, threshold: f64) -> u8 {
+    (value * 255.0 + threshold).floor().clamp(0.0, 255.0) as u8
+}
+
+pub fn quantize_u16(value: f64) -> u16 {
+    (value * 65535.0).round().clamp(0.0, 65535.0) as u16
+}
+
 pub fn to_png_color(color: &LinearColor) -> [u8; 3] {
-    [
-        (linear_to_srgb(color[0]) * 256.0) as u8,
-        (linear_to_srgb(color[1]) * 256.0) as u8,
-        (linear_to_srgb(color[2]) * 256.0) as u8
-    ]
+    color.to_array().map(| c | quantize_u8(linear_to_srgb(c), 0.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_colors_parse() {
+        assert_eq!(LinearColor::from_hex("#ffffff"), Some(LinearColor::WHITE));
+        assert_eq!(LinearColor::from_hex("000"), Some(LinearColor::BLACK));
+        assert_eq!(LinearColor::from_hex("#f80"), LinearColor::from_hex("#ff8800"));
+        assert_eq!(LinearColor::from_hex(" #FF8800 "), LinearColor::from_hex("ff8800"));
+
+        let orange = LinearColor::from_hex("#ff8000").unwrap();
+
+        assert_eq!(orange.r, 1.0);
+        assert!((orange.g - srgb_to_linear(128.0 / 255.0)).abs() < 1e-12);
+        assert_eq!(orange.b, 0.0);
+    }
+
+    #[test]
+    fn invalid_hex_colors_are_rejected() {
+        for hex in ["", "#", "#ff", "#ffff", "#fffffff", "#ggg", "#12345z", "#fé0"].iter() {
+            assert_eq!(LinearColor::from_hex(hex), None, "{:?}", hex);
+        }
+    }
+
+    #[test]
+    fn hex_colors_round_trip() {
+        for hex in ["#000000", "#ffffff", "#ff8000", "#1a2b3c", "#cbdaff", "#010203"].iter() {
+            assert_eq!(LinearColor::from_hex(hex).unwrap().to_hex(), *hex);
+        }
+
+        assert_eq!(LinearColor::from_hex("#abc").unwrap().to_hex(), "#aabbcc");
+    }
+
+    #[test]
+    fn to_hex_clamps() {
+        assert_eq!(LinearColor::new(2.0, -1.0, 0.5).to_hex(), LinearColor::new(1.0, 0.0, 0.5).to_hex());
+    }
 }
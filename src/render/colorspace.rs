@@ -0,0 +1,359 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// RGB color spaces, defined by the chromaticities of their primaries
+// and white point. Conversions go through CIE XYZ, with a Bradford
+// chromatic adaptation between spaces with different white points.
+// The matrices are worked out from the chromaticities at compile time.
+//
+// Scenes give their colors in linear sRGB, and render in a working
+// space that they're converted into as they're read. The finished image
+// is converted again into the output space, and encoded with that
+// space's transfer function.
+
+use crate::render::color::{LinearColor, linear_to_srgb};
+
+pub type Matrix3 = [[f64; 3]; 3];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ColorSpace {
+    // Rec. 709 primaries with a D65 white point.
+    Srgb,
+
+    // The wide gamut of UHDTV, Rec. 2020, also D65.
+    Rec2020,
+
+    // The ACES working space (AP1 primaries), with its own white point
+    // near D60.
+    AcesCg,
+
+    // DCI-P3 primaries with a D65 white point, as on recent displays.
+    DisplayP3,
+}
+
+// Chromaticities (x, y) of the red, green, and blue primaries, and
+// the white point.
+pub struct Primaries {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white: (f64, f64),
+}
+
+const D65: (f64, f64) = (0.3127, 0.3290);
+
+const BRADFORD: Matrix3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+impl ColorSpace {
+    pub fn from_name(name: &str) -> Option<ColorSpace> {
+        match name.to_lowercase().as_str() {
+            "srgb" | "rec709" => Some(ColorSpace::Srgb),
+            "rec2020" => Some(ColorSpace::Rec2020),
+            "acescg" => Some(ColorSpace::AcesCg),
+            "p3" | "displayp3" => Some(ColorSpace::DisplayP3),
+            _ => None
+        }
+    }
+
+    pub const fn primaries(&self) -> Primaries {
+        match self {
+            ColorSpace::Srgb => Primaries {
+                red: (0.64, 0.33),
+                green: (0.30, 0.60),
+                blue: (0.15, 0.06),
+                white: D65,
+            },
+            ColorSpace::Rec2020 => Primaries {
+                red: (0.708, 0.292),
+                green: (0.170, 0.797),
+                blue: (0.131, 0.046),
+                white: D65,
+            },
+            ColorSpace::AcesCg => Primaries {
+                red: (0.713, 0.293),
+                green: (0.165, 0.830),
+                blue: (0.128, 0.044),
+                white: (0.32168, 0.33767),
+            },
+            ColorSpace::DisplayP3 => Primaries {
+                red: (0.680, 0.320),
+                green: (0.265, 0.690),
+                blue: (0.150, 0.060),
+                white: D65,
+            }
+        }
+    }
+
+    // The transfer function images in this space are usually encoded
+    // with.
+    pub fn default_transfer(&self) -> Transfer {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => Transfer::Srgb,
+            ColorSpace::Rec2020 => Transfer::Rec709,
+            ColorSpace::AcesCg => Transfer::Linear
+        }
+    }
+
+    // Converts a linear sRGB color, as scenes give them, into this
+    // space. Leaves sRGB colors exactly as they are.
+    pub fn convert_srgb(&self, color: &LinearColor) -> LinearColor {
+        match self {
+            ColorSpace::Srgb => *color,
+            ColorSpace::Rec2020 => transform_color(&SRGB_TO_REC2020, color),
+            ColorSpace::AcesCg => transform_color(&SRGB_TO_ACESCG, color),
+            ColorSpace::DisplayP3 => transform_color(&SRGB_TO_DISPLAY_P3, color)
+        }
+    }
+}
+
+const SRGB_TO_REC2020: Matrix3 = conversion_matrix(ColorSpace::Srgb, ColorSpace::Rec2020);
+const SRGB_TO_ACESCG: Matrix3 = conversion_matrix(ColorSpace::Srgb, ColorSpace::AcesCg);
+const SRGB_TO_DISPLAY_P3: Matrix3 = conversion_matrix(ColorSpace::Srgb, ColorSpace::DisplayP3);
+
+pub const fn transform(m: &Matrix3, v: &[f64; 3]) -> [f64; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+fn transform_color(m: &Matrix3, color: &LinearColor) -> LinearColor {
+    LinearColor::from_array(transform(m, &color.to_array()))
+}
+
+const fn multiply(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut result = [[0.0; 3]; 3];
+    let mut row = 0;
+
+    while row < 3 {
+        let mut col = 0;
+
+        while col < 3 {
+            result[row][col] = a[row][0] * b[0][col] + a[row][1] * b[1][col] + a[row][2] * b[2][col];
+            col += 1;
+        }
+
+        row += 1;
+    }
+
+    result
+}
+
+// Determinant of the 2x2 matrix from rows r0 and r1 and columns c0
+// and c1.
+const fn minor(m: &Matrix3, r0: usize, r1: usize, c0: usize, c1: usize) -> f64 {
+    m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+}
+
+const fn invert(m: &Matrix3) -> Matrix3 {
+    let det = m[0][0] * minor(m, 1, 2, 1, 2) - m[0][1] * minor(m, 1, 2, 0, 2) + m[0][2] * minor(m, 1, 2, 0, 1);
+
+    [
+        [minor(m, 1, 2, 1, 2) / det, -minor(m, 0, 2, 1, 2) / det, minor(m, 0, 1, 1, 2) / det],
+        [-minor(m, 1, 2, 0, 2) / det, minor(m, 0, 2, 0, 2) / det, -minor(m, 0, 1, 0, 2) / det],
+        [minor(m, 1, 2, 0, 1) / det, -minor(m, 0, 2, 0, 1) / det, minor(m, 0, 1, 0, 1) / det],
+    ]
+}
+
+const fn diagonal(d: [f64; 3]) -> Matrix3 {
+    [[d[0], 0.0, 0.0], [0.0, d[1], 0.0], [0.0, 0.0, d[2]]]
+}
+
+// XYZ of a chromaticity, scaled to a luminance of one.
+const fn xy_to_xyz(xy: (f64, f64)) -> [f64; 3] {
+    [xy.0 / xy.1, 1.0, (1.0 - xy.0 - xy.1) / xy.1]
+}
+
+// Linear RGB to XYZ: the primaries, each scaled so that they add up to
+// the white point.
+pub const fn rgb_to_xyz(space: ColorSpace) -> Matrix3 {
+    let p = space.primaries();
+    let (r, g, b) = (xy_to_xyz(p.red), xy_to_xyz(p.green), xy_to_xyz(p.blue));
+
+    let unscaled = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let s = transform(&invert(&unscaled), &xy_to_xyz(p.white));
+
+    multiply(&unscaled, &diagonal(s))
+}
+
+pub const fn xyz_to_rgb(space: ColorSpace) -> Matrix3 {
+    invert(&rgb_to_xyz(space))
+}
+
+// Adapts XYZ colors seen under one white point to how they'd look
+// under another, by scaling the Bradford cone responses.
+const fn bradford_adaptation(from: (f64, f64), to: (f64, f64)) -> Matrix3 {
+    let source = transform(&BRADFORD, &xy_to_xyz(from));
+    let dest = transform(&BRADFORD, &xy_to_xyz(to));
+
+    let scale = diagonal([dest[0] / source[0], dest[1] / source[1], dest[2] / source[2]]);
+
+    multiply(&invert(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+pub const fn conversion_matrix(from: ColorSpace, to: ColorSpace) -> Matrix3 {
+    let adaptation = bradford_adaptation(from.primaries().white, to.primaries().white);
+
+    multiply(&xyz_to_rgb(to), &multiply(&adaptation, &rgb_to_xyz(from)))
+}
+
+// How linear values are encoded for storage in an integer image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Transfer {
+    Linear,
+
+    // The piecewise sRGB curve, also used by Display P3.
+    Srgb,
+
+    // The Rec. 709 camera curve, also used by Rec. 2020.
+    Rec709,
+}
+
+impl Transfer {
+    pub fn from_name(name: &str) -> Option<Transfer> {
+        match name.to_lowercase().as_str() {
+            "linear" => Some(Transfer::Linear),
+            "srgb" => Some(Transfer::Srgb),
+            "rec709" | "rec2020" => Some(Transfer::Rec709),
+            _ => None
+        }
+    }
+
+    // Encodes a linear value, clamped to [0, 1].
+    pub fn encode(&self, x: f64) -> f64 {
+        match self {
+            Transfer::Linear => x.clamp(0.0, 1.0),
+            Transfer::Srgb => linear_to_srgb(x),
+            Transfer::Rec709 => {
+                let x = x.clamp(0.0, 1.0);
+
+                if x < 0.018 {
+                    4.5 * x
+                } else {
+                    1.099 * x.powf(0.45) - 0.099
+                }
+            }
+        }
+    }
+}
+
+// How colors in the working space become values in an output image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DisplayEncoding {
+    transfer: Transfer,
+
+    // Working space to output space, when they differ.
+    matrix: Option<Matrix3>,
+}
+
+impl DisplayEncoding {
+    pub fn new(working: ColorSpace, output: ColorSpace, transfer: Transfer) -> DisplayEncoding {
+        DisplayEncoding {
+            transfer,
+            matrix: if working == output { None } else { Some(conversion_matrix(working, output)) },
+        }
+    }
+
+    pub fn encode(&self, color: &LinearColor) -> [f64; 3] {
+        let color = match &self.matrix {
+            Some(matrix) => transform_color(matrix, color),
+            None => *color
+        };
+
+        color.to_array().map(| c | self.transfer.encode(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_SPACES: [ColorSpace; 4] = [
+        ColorSpace::Srgb,
+        ColorSpace::Rec2020,
+        ColorSpace::AcesCg,
+        ColorSpace::DisplayP3,
+    ];
+
+    const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    fn assert_close(a: &Matrix3, b: &Matrix3, tolerance: f64) {
+        for row in 0..3 {
+            for col in 0..3 {
+                assert!((a[row][col] - b[row][col]).abs() < tolerance, "{:?} is not {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_matches_its_published_matrices() {
+        assert_close(&rgb_to_xyz(ColorSpace::Srgb), &[
+            [0.4124, 0.3576, 0.1805],
+            [0.2126, 0.7152, 0.0722],
+            [0.0193, 0.1192, 0.9505],
+        ], 1e-4);
+
+        assert_close(&conversion_matrix(ColorSpace::Srgb, ColorSpace::Rec2020), &[
+            [0.6274, 0.3293, 0.0433],
+            [0.0691, 0.9195, 0.0114],
+            [0.0164, 0.0880, 0.8956],
+        ], 1e-4);
+    }
+
+    #[test]
+    fn white_stays_white_with_the_same_white_point() {
+        let white = transform(&conversion_matrix(ColorSpace::Srgb, ColorSpace::DisplayP3), &[1.0, 1.0, 1.0]);
+
+        assert!(white.iter().all(| c | (c - 1.0).abs() < 1e-12), "{:?}", white);
+
+        // Bradford adaptation carries D65 white over to the ACES white.
+        let white = transform(&conversion_matrix(ColorSpace::Srgb, ColorSpace::AcesCg), &[1.0, 1.0, 1.0]);
+
+        assert!(white.iter().all(| c | (c - 1.0).abs() < 1e-12), "{:?}", white);
+    }
+
+    #[test]
+    fn conversions_invert_each_other() {
+        for from in ALL_SPACES.iter() {
+            assert_close(&conversion_matrix(*from, *from), &IDENTITY, 1e-12);
+
+            for to in ALL_SPACES.iter() {
+                let there_and_back = multiply(&conversion_matrix(*to, *from), &conversion_matrix(*from, *to));
+
+                assert_close(&there_and_back, &IDENTITY, 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn matching_spaces_leave_colors_alone() {
+        let color = LinearColor::new(0.2, 0.5, 0.9);
+        let encoding = DisplayEncoding::new(ColorSpace::AcesCg, ColorSpace::AcesCg, Transfer::Linear);
+
+        assert_eq!(encoding.encode(&color), [0.2, 0.5, 0.9]);
+        assert_eq!(ColorSpace::Srgb.convert_srgb(&color), color);
+    }
+
+    #[test]
+    fn transfers_keep_their_end_points() {
+        for transfer in [Transfer::Linear, Transfer::Srgb, Transfer::Rec709].iter() {
+            assert!(transfer.encode(0.0).abs() < 1e-12, "{:?}", transfer);
+            assert!((transfer.encode(1.0) - 1.0).abs() < 1e-12, "{:?}", transfer);
+            assert!(transfer.encode(0.5) >= 0.5, "{:?}", transfer);
+        }
+
+        assert_eq!(Transfer::Rec709.encode(0.01), 0.045);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// False colors for the debug shading modes, which show one property of
+// whatever a ray hits in place of its shaded color.
+
+use crate::render::geometry::Normal;
+
+use crate::render::color::{
+    LinearColor,
+    heatmap_color,
+};
+
+use crate::render::random::Rng;
+
+// Distance over which the depth view fades to a third of its
+// brightness.
+const DEPTH_SCALE: f64 = 10.0;
+
+// Intersection test count shown in red. The heatmap is logarithmic,
+// so that cheap parts of the image still show some variation.
+const MAX_TESTS: f64 = 10000.0;
+
+// Each component of the normal mapped from [-1, 1] to [0, 1].
+pub fn normal_color(normal: &Normal) -> LinearColor {
+    LinearColor::new(
+        normal.x * 0.5 + 0.5,
+        normal.y * 0.5 + 0.5,
+        normal.z * 0.5 + 0.5
+    )
+}
+
+// White up close, fading to black with distance.
+pub fn depth_color(distance: f64) -> LinearColor {
+    let shade = (-distance / DEPTH_SCALE).exp();
+
+    LinearColor::gray(shade)
+}
+
+// A distinct, arbitrary color for each object in the scene.
+pub fn object_color(index: usize) -> LinearColor {
+    let mut rng = Rng::new(index as u64);
+
+    LinearColor::new(
+        0.1 + 0.9 * rng.next_f64(),
+        0.1 + 0.9 * rng.next_f64(),
+        0.1 + 0.9 * rng.next_f64()
+    )
+}
+
+// Surface coordinates in the red and green channels.
+pub fn uv_color(uv: (f64, f64)) -> LinearColor {
+    LinearColor::new(uv.0, uv.1, 0.0)
+}
+
+pub fn tests_color(tests: u64) -> LinearColor {
+    heatmap_color((1.0 + tests as f64).ln() / (1.0 + MAX_TESTS).ln())
+}
+
+// How many reflections it took to get to a surface that doesn't
+// reflect (or to the edge of the scene), relative to the limit.
+pub fn reflection_depth_color(depth: u32, limit: u32) -> LinearColor {
+    heatmap_color(depth as f64 / limit.max(1) as f64)
+}
@@ -0,0 +1,199 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Edge-avoiding A-Trous wavelet denoising, after Dammertz et al. 2010,
+// "Edge-Avoiding A-Trous Wavelet Transform for fast Global Illumination
+// Filtering". Each pass blurs with a 5x5 B3 spline kernel whose taps
+// spread twice as far apart as the pass before, and weights each tap by
+// how alike it is to the center pixel in color, albedo, and normal.
+// Albedo and normal are noise free guides, so edges and texture survive
+// while the noise in the lighting is smoothed away.
+
+use std::io;
+
+use rayon::prelude::*;
+
+use crate::render::color::LinearColor;
+use crate::render::exr::ExrImage;
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+#[derive(Copy, Clone, Debug)]
+pub struct Denoiser {
+    pub passes: u32,
+
+    // How quickly weights fall off with differences in color, albedo,
+    // and normal. Smaller values keep more detail and remove less noise.
+    // The color sigma halves with each pass, since each pass leaves less
+    // noise behind.
+    pub color_sigma: f64,
+    pub albedo_sigma: f64,
+    pub normal_sigma: f64,
+}
+
+pub const DEFAULT_DENOISER: Denoiser = Denoiser {
+    passes: 5,
+    color_sigma: 0.15,
+    albedo_sigma: 0.1,
+    normal_sigma: 0.3,
+};
+
+// Optional guide buffers, in the same scanline order as the color.
+pub struct Guides<'a> {
+    pub albedo: Option<&'a [[f64; 3]]>,
+    pub normal: Option<&'a [[f64; 3]]>,
+}
+
+fn edge_weight(a: &[f64; 3], b: &[f64; 3], sigma: f64) -> f64 {
+    let distance_squared: f64 = a.iter().zip(b).map(| (a, b) | (a - b) * (a - b)).sum();
+
+    (-distance_squared / (sigma * sigma)).exp()
+}
+
+impl Denoiser {
+    pub fn denoise(&self, width: usize, height: usize, color: &[LinearColor], guides: &Guides) -> Vec<LinearColor> {
+        let mut current = color.to_vec();
+
+        for pass in 0..self.passes {
+            let step = 1i64 << pass;
+            let color_sigma = self.color_sigma / (1u64 << pass) as f64;
+
+            let mut next = vec![LinearColor::BLACK; current.len()];
+
+            next.par_chunks_mut(width.max(1)).enumerate().for_each(| (y, row) | {
+                for (x, out) in row.iter_mut().enumerate() {
+                    let idx = y * width + x;
+
+                    let mut sum = LinearColor::BLACK;
+                    let mut total_weight = 0.0;
+
+                    for (ky, kernel_y) in KERNEL.iter().enumerate() {
+                        let qy = y as i64 + (ky as i64 - 2) * step;
+
+                        if qy < 0 || qy >= height as i64 {
+                            continue;
+                        }
+
+                        for (kx, kernel_x) in KERNEL.iter().enumerate() {
+                            let qx = x as i64 + (kx as i64 - 2) * step;
+
+                            if qx < 0 || qx >= width as i64 {
+                                continue;
+                            }
+
+                            let qidx = qy as usize * width + qx as usize;
+
+                            let mut weight = kernel_x * kernel_y
+                                * edge_weight(&current[idx].to_array(), &current[qidx].to_array(), color_sigma);
+
+                            if let Some(albedo) = guides.albedo {
+                                weight *= edge_weight(&albedo[idx], &albedo[qidx], self.albedo_sigma);
+                            }
+
+                            if let Some(normal) = guides.normal {
+                                weight *= edge_weight(&normal[idx], &normal[qidx], self.normal_sigma);
+                            }
+
+                            sum += current[qidx] * weight;
+
+                            total_weight += weight;
+                        }
+                    }
+
+                    // The center tap always has a weight, so this is
+                    // never zero.
+                    *out = sum / total_weight;
+                }
+            });
+
+            current = next;
+        }
+
+        current
+    }
+
+    // Denoises the R, G, and B channels of an image, using the layers
+    // named albedo and normal as guides, as written with the AOVs. Guides
+    // can also come from separate images, with channels R, G, and B for
+    // albedo and X, Y, and Z for normals. Every other channel is copied
+    // through unchanged.
+    pub fn denoise_exr(
+        &self, image: &ExrImage, albedo_image: Option<&ExrImage>, normal_image: Option<&ExrImage>
+    ) -> io::Result<ExrImage> {
+
+        let pixels = image.width * image.height;
+
+        let read_triple = | image: &ExrImage, names: [&str; 3] | -> Option<Vec<[f64; 3]>> {
+            if image.width * image.height != pixels {
+                return None;
+            }
+
+            let channels = [image.channel(names[0])?, image.channel(names[1])?, image.channel(names[2])?];
+
+            Some((0..pixels)
+                 .map(| idx | [channels[0][idx] as f64, channels[1][idx] as f64, channels[2][idx] as f64])
+                 .collect())
+        };
+
+        let color = read_triple(image, ["R", "G", "B"]).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Image has no R, G, and B channels.")
+        })?;
+
+        let guide = | own: [&str; 3], separate_image: Option<&ExrImage>, separate: [&str; 3], name: &str | {
+            let values = match separate_image {
+                Some(separate_image) => read_triple(separate_image, separate),
+                None => read_triple(image, own)
+            };
+
+            if values.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("No {} guide, or it's a different size.", name)));
+            }
+
+            Ok(values)
+        };
+
+        let albedo = if albedo_image.is_some() || image.channel("albedo.R").is_some() {
+            guide(["albedo.R", "albedo.G", "albedo.B"], albedo_image, ["R", "G", "B"], "albedo")?
+        } else {
+            None
+        };
+
+        let normal = if normal_image.is_some() || image.channel("normal.X").is_some() {
+            guide(["normal.X", "normal.Y", "normal.Z"], normal_image, ["X", "Y", "Z"], "normal")?
+        } else {
+            None
+        };
+
+        let color: Vec<LinearColor> = color.into_iter().map(LinearColor::from_array).collect();
+
+        let denoised = self.denoise(image.width, image.height, &color, &Guides {
+            albedo: albedo.as_deref(),
+            normal: normal.as_deref(),
+        });
+
+        let mut result = ExrImage::new(image.width, image.height);
+
+        result.chromaticities = image.chromaticities;
+
+        for channel in image.channels.iter() {
+            let values = match channel.name.as_str() {
+                "R" => denoised.iter().map(| c | c[0] as f32).collect(),
+                "G" => denoised.iter().map(| c | c[1] as f32).collect(),
+                "B" => denoised.iter().map(| c | c[2] as f32).collect(),
+                _ => channel.values.clone()
+            };
+
+            result.add_channel(&channel.name, values);
+        }
+
+        Ok(result)
+    }
+}
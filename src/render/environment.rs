@@ -0,0 +1,224 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+extern crate image;
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::hdr::HdrDecoder;
+
+use crate::render::geometry::{
+    Vector,
+    EPSILON,
+};
+
+use crate::render::color::LinearColor;
+
+use crate::render::random::Rng;
+
+use crate::render::sky::PhysicalSky;
+
+// Everything surrounding the scene at infinite distance. It's what
+// rays see when they miss every object, and it also lights the scene.
+pub enum Environment {
+    Constant(LinearColor),
+
+    // Blends from horizon to zenith above the horizon, and from
+    // horizon to ground below it.
+    Gradient {
+        up: Vector,
+        zenith: LinearColor,
+        horizon: LinearColor,
+        ground: LinearColor,
+    },
+
+    Map(EnvironmentMap),
+
+    Sky(PhysicalSky),
+}
+
+// An equirectangular (latitude/longitude) radiance map, with +y up.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<LinearColor>,
+
+    // Distributions used to pick directions in proportion to how much
+    // light arrives from them. The row distribution is the marginal;
+    // each row then has its own distribution over columns.
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<Vec<f64>>,
+}
+
+fn lerp_linear_color(a: &LinearColor, b: &LinearColor, t: f64) -> LinearColor {
+    *a * (1.0 - t) + *b * t
+}
+
+fn cumulative(weights: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut total = 0.0;
+
+    weights.map(| w | {
+        total += w;
+        total
+    }).collect()
+}
+
+// Picks an index from a cumulative distribution, returning the index
+// and the probability of picking it.
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let total = *cdf.last().unwrap_or(&0.0);
+
+    if total <= 0.0 {
+        let idx = ((u * cdf.len() as f64) as usize).min(cdf.len() - 1);
+        return (idx, 1.0 / cdf.len() as f64);
+    }
+
+    let target = u * total;
+    let idx = cdf.partition_point(| c | *c <= target).min(cdf.len() - 1);
+    let start = if idx == 0 { 0.0 } else { cdf[idx - 1] };
+
+    (idx, (cdf[idx] - start) / total)
+}
+
+fn uniform_sphere(rng: &mut Rng) -> Vector {
+    let z = 1.0 - 2.0 * rng.next_f64();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+
+    Vector::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<LinearColor>) -> EnvironmentMap {
+        // Rows near the poles cover less of the sphere, so their
+        // weights are scaled by sin(theta).
+        let column_cdfs: Vec<Vec<f64>> = (0..height).map(| y | {
+            cumulative((0..width).map(| x | pixels[y * width + x].luminance()))
+        }).collect();
+
+        let row_cdf = cumulative(column_cdfs.iter().enumerate().map(| (y, cdf) | {
+            let theta = PI * (y as f64 + 0.5) / height as f64;
+
+            cdf.last().unwrap_or(&0.0) * theta.sin()
+        }));
+
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            row_cdf,
+            column_cdfs,
+        }
+    }
+
+    // Loads a Radiance .hdr file, scaling its values by intensity.
+    pub fn load<P: AsRef<Path>>(path: P, intensity: f64) -> image::ImageResult<EnvironmentMap> {
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+        let metadata = decoder.metadata();
+
+        let pixels = decoder.read_image_hdr()?
+            .iter()
+            .map(| p | LinearColor::new(p[0] as f64, p[1] as f64, p[2] as f64) * intensity)
+            .collect();
+
+        Ok(EnvironmentMap::new(metadata.width as usize, metadata.height as usize, pixels))
+    }
+
+    fn direction_to_uv(direction: &Vector) -> (f64, f64) {
+        let Vector { x, y, z } = direction.normalize().unwrap_or(*direction);
+
+        let u = 0.5 + z.atan2(x) / (2.0 * PI);
+        let v = y.clamp(-1.0, 1.0).acos() / PI;
+
+        (u, v)
+    }
+
+    fn uv_to_direction(u: f64, v: f64) -> Vector {
+        let phi = (u - 0.5) * 2.0 * PI;
+        let theta = v * PI;
+
+        Vector::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+
+    fn texel(&self, u: f64, v: f64) -> (usize, usize) {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+
+        (x, y)
+    }
+
+    pub fn color(&self, direction: &Vector) -> LinearColor {
+        let (u, v) = EnvironmentMap::direction_to_uv(direction);
+        let (x, y) = self.texel(u, v);
+
+        self.pixels[y * self.width + x]
+    }
+
+    fn sample(&self, rng: &mut Rng) -> (Vector, f64) {
+        let (y, row_pdf) = sample_cdf(&self.row_cdf, rng.next_f64());
+        let (x, column_pdf) = sample_cdf(&self.column_cdfs[y], rng.next_f64());
+
+        let u = (x as f64 + rng.next_f64()) / self.width as f64;
+        let v = (y as f64 + rng.next_f64()) / self.height as f64;
+
+        let direction = EnvironmentMap::uv_to_direction(u, v);
+
+        // Convert from a density over texels to one over solid angle.
+        let sin_theta = (v * PI).sin().max(EPSILON);
+        let pdf = row_pdf * column_pdf * (self.width * self.height) as f64 / (2.0 * PI * PI * sin_theta);
+
+        (direction, pdf)
+    }
+}
+
+impl Environment {
+    pub fn color(&self, direction: &Vector) -> LinearColor {
+        match self {
+            Environment::Constant(color) => *color,
+
+            Environment::Gradient { up, zenith, horizon, ground } => {
+                let t = match (direction.normalize(), up.normalize()) {
+                    (Some(direction), Some(up)) => direction.dot(up),
+                    _ => 0.0
+                };
+
+                if t >= 0.0 {
+                    lerp_linear_color(horizon, zenith, t)
+                } else {
+                    lerp_linear_color(horizon, ground, -t)
+                }
+            },
+
+            Environment::Map(map) => map.color(direction),
+
+            Environment::Sky(sky) => sky.color(direction)
+        }
+    }
+
+    pub fn is_black(&self) -> bool {
+        match self {
+            Environment::Constant(color) => color.luminance() < EPSILON,
+            _ => false
+        }
+    }
+
+    // Picks a direction to look for light from the environment,
+    // preferring the brighter parts of maps. Returns the direction and
+    // its probability density with respect to solid angle.
+    pub fn sample(&self, rng: &mut Rng) -> (Vector, f64) {
+        match self {
+            Environment::Map(map) => map.sample(rng),
+            _ => (uniform_sphere(rng), 1.0 / (4.0 * PI))
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// OpenEXR files as named channels of 32-bit float samples, in scanline
+// order. Channels of multi-layer images are named layer.channel, as in
+// "normal.X". The chromaticities of the RGB primaries and white point
+// are kept with the image, when it has them.
+
+extern crate exr;
+
+use std::io;
+use std::path::Path;
+
+use exr::prelude::{
+    AnyChannel,
+    AnyChannels,
+    FlatSamples,
+    Image,
+    SmallVec,
+    Vec2,
+    WritableImage,
+    read_first_flat_layer_from_file,
+};
+
+use exr::meta::attribute::Chromaticities;
+
+use crate::render::colorspace::ColorSpace;
+
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+pub struct ExrImage {
+    pub width: usize,
+    pub height: usize,
+    pub channels: Vec<Channel>,
+    pub chromaticities: Option<Chromaticities>,
+}
+
+impl ExrImage {
+    pub fn new(width: usize, height: usize) -> ExrImage {
+        ExrImage {
+            width,
+            height,
+            channels: vec![],
+            chromaticities: None,
+        }
+    }
+
+    pub fn add_channel(&mut self, name: &str, values: Vec<f32>) {
+        self.channels.push(Channel {
+            name: String::from(name),
+            values,
+        });
+    }
+
+    pub fn set_color_space(&mut self, space: ColorSpace) {
+        let p = space.primaries();
+        let xy = | (x, y): (f64, f64) | Vec2(x as f32, y as f32);
+
+        self.chromaticities = Some(Chromaticities {
+            red: xy(p.red),
+            green: xy(p.green),
+            blue: xy(p.blue),
+            white: xy(p.white),
+        });
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&[f32]> {
+        self.channels.iter()
+            .find(| channel | channel.name == name)
+            .map(| channel | channel.values.as_slice())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = self.channels.iter()
+            .map(| channel | AnyChannel::new(channel.name.as_str(), FlatSamples::F32(channel.values.clone())))
+            .collect();
+
+        let mut image = Image::from_channels((self.width, self.height), AnyChannels::sort(channels));
+
+        image.attributes.chromaticities = self.chromaticities;
+
+        image.write()
+            .to_file(path)
+            .map_err(io::Error::other)
+    }
+
+    // Loads the first layer of a file, converting half float and integer
+    // channels to 32-bit floats. Channels of a named layer get the layer
+    // name as a prefix.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ExrImage> {
+        let image = read_first_flat_layer_from_file(path).map_err(io::Error::other)?;
+        let layer = image.layer_data;
+
+        let mut exr_image = ExrImage::new(layer.size.width(), layer.size.height());
+
+        exr_image.chromaticities = image.attributes.chromaticities;
+
+        for channel in layer.channel_data.list.iter() {
+            let name = match &layer.attributes.layer_name {
+                Some(layer_name) => format!("{}.{}", layer_name, channel.name),
+                None => channel.name.to_string()
+            };
+
+            exr_image.add_channel(&name, channel.sample_data.values_as_f32().collect());
+        }
+
+        Ok(exr_image)
+    }
+}
@@ -0,0 +1,535 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+extern crate image;
+
+use std::io;
+use std::io::{Read, Write};
+
+use crate::render::geometry::EPSILON;
+
+use crate::render::color::LinearColor;
+
+use crate::render::filter::Filter;
+use crate::render::aov::{Aov, AovBuffer, AovSample};
+use crate::render::exr::ExrImage;
+use crate::render::denoise::{Denoiser, Guides};
+use crate::render::postprocess::{PostEffect, apply_effects};
+use crate::render::output::DisplayImage;
+use crate::render::colorspace::DisplayEncoding;
+
+// Accumulates samples over a rectangular window of the image, each
+// spread over nearby pixels according to a reconstruction filter.
+// Sample positions are in pixel units, with pixel (x, y) covering
+// [x, x + 1) by [y, y + 1).
+//
+// Windows can extend past the edges of the image, so that a tile of
+// the image can collect everything its samples contribute to its
+// neighbors, and then be merged into the full image without seams.
+pub struct Film {
+    x0: i64,
+    y0: i64,
+    width: usize,
+    height: usize,
+    colors: Vec<LinearColor>,
+    weights: Vec<f64>,
+
+    // Weighted sum of the fraction of each sample covered by an object,
+    // filtered the same way as the colors.
+    coverages: Vec<f64>,
+
+    // Number of samples taken within each pixel.
+    samples: Vec<u32>,
+
+    // AOVs, for films that collect them.
+    aovs: Option<AovBuffer>,
+}
+
+// How alpha is stored alongside color in images with coverage.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AlphaMode {
+    // Color scaled by alpha, ready to add over a background.
+    Premultiplied,
+
+    // Color as seen where there is coverage, unscaled.
+    Straight,
+}
+
+impl AlphaMode {
+    pub fn from_name(name: &str) -> Option<AlphaMode> {
+        match name.to_lowercase().as_str() {
+            "premultiplied" => Some(AlphaMode::Premultiplied),
+            "straight" => Some(AlphaMode::Straight),
+            _ => None
+        }
+    }
+}
+
+// Limit on the size of films read back in, so a corrupt header can't
+// ask for an enormous allocation.
+const MAX_PIXELS: usize = 1 << 28;
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64<R: Read>(input: &mut R) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(input)?))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film::window(0, 0, width as usize, height as usize)
+    }
+
+    pub fn window(x0: i64, y0: i64, width: usize, height: usize) -> Film {
+        Film {
+            x0,
+            y0,
+            width,
+            height,
+            colors: vec![LinearColor::BLACK; width * height],
+            weights: vec![0.0; width * height],
+            coverages: vec![0.0; width * height],
+            samples: vec![0; width * height],
+            aovs: None,
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        let (fx, fy) = (x - self.x0, y - self.y0);
+
+        if fx < 0 || fy < 0 || fx >= self.width as i64 || fy >= self.height as i64 {
+            None
+        } else {
+            Some(fy as usize * self.width + fx as usize)
+        }
+    }
+
+    pub fn x0(&self) -> i64 {
+        self.x0
+    }
+
+    pub fn y0(&self) -> i64 {
+        self.y0
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn add_sample(&mut self, x: f64, y: f64, color: &LinearColor, coverage: f64, filter: &Filter) {
+        let radius = filter.radius();
+
+        if let Some(idx) = self.index(x.floor() as i64, y.floor() as i64) {
+            self.samples[idx] += 1;
+        }
+
+        // Pixel centers are at half integers.
+        let (cx, cy) = (x - 0.5, y - 0.5);
+
+        for py in (cy - radius).ceil() as i64..=(cy + radius).floor() as i64 {
+            for px in (cx - radius).ceil() as i64..=(cx + radius).floor() as i64 {
+                if let Some(idx) = self.index(px, py) {
+                    let weight = filter.eval(px as f64 - cx, py as f64 - cy);
+
+                    if weight != 0.0 {
+                        self.colors[idx] += *color * weight;
+                        self.weights[idx] += weight;
+                        self.coverages[idx] += coverage * weight;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn enable_aovs(&mut self) {
+        if self.aovs.is_none() {
+            self.aovs = Some(AovBuffer::new(self.width * self.height));
+        }
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    // Adds a sample's AOVs to the pixel it falls in.
+    pub fn add_aov_sample(&mut self, x: f64, y: f64, sample: &AovSample) {
+        let (px, py) = (x.floor(), y.floor());
+
+        if let Some(idx) = self.index(px as i64, py as i64) {
+            let (dx, dy) = (x - px - 0.5, y - py - 0.5);
+
+            if let Some(aovs) = &mut self.aovs {
+                aovs.add(idx, dx * dx + dy * dy, sample);
+            }
+        }
+    }
+
+    // Adds everything accumulated in another film, over the part of
+    // its window that overlaps this one. AOVs are picked up if the
+    // other film has them.
+    pub fn merge(&mut self, other: &Film) {
+        if other.aovs.is_some() {
+            self.enable_aovs();
+        }
+
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                let oidx = oy * other.width + ox;
+
+                if let Some(idx) = self.index(other.x0 + ox as i64, other.y0 + oy as i64) {
+                    self.colors[idx] += other.colors[oidx];
+                    self.weights[idx] += other.weights[oidx];
+                    self.coverages[idx] += other.coverages[oidx];
+                    self.samples[idx] += other.samples[oidx];
+
+                    if let (Some(aovs), Some(other_aovs)) = (&mut self.aovs, &other.aovs) {
+                        aovs.merge(idx, other_aovs, oidx);
+                    }
+                }
+            }
+        }
+    }
+
+    // The filtered color of a pixel. Filters with negative lobes can
+    // leave a pixel with next to no total weight, so those are black.
+    pub fn pixel(&self, x: i64, y: i64) -> LinearColor {
+        match self.index(x, y) {
+            Some(idx) => self.color_at(idx),
+            None => LinearColor::BLACK
+        }
+    }
+
+    fn color_at(&self, idx: usize) -> LinearColor {
+        if self.weights[idx].abs() > EPSILON {
+            self.colors[idx] * (1.0 / self.weights[idx])
+        } else {
+            LinearColor::BLACK
+        }
+    }
+
+    // The fraction of a pixel covered by objects, clamped since
+    // negative filter lobes can overshoot either way.
+    fn alpha_at(&self, idx: usize) -> f64 {
+        if self.weights[idx].abs() > EPSILON {
+            (self.coverages[idx] / self.weights[idx]).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+
+    // Replaces the accumulated sums with final colors and coverage, each
+    // with a weight of one.
+    fn resolve(&mut self, colors: Vec<LinearColor>) {
+        let pixels = self.width * self.height;
+
+        self.coverages = (0..pixels).map(| idx | self.alpha_at(idx)).collect();
+        self.colors = colors;
+        self.weights = vec![1.0; pixels];
+    }
+
+    // Replaces the filtered colors with denoised ones, using the albedo
+    // and normal AOVs as guides when the film has them. Sample counts
+    // and AOVs are left alone.
+    pub fn denoise(&mut self, denoiser: &Denoiser) {
+        let pixels = self.width * self.height;
+
+        let color: Vec<LinearColor> = (0..pixels).map(| idx | self.color_at(idx)).collect();
+
+        let guide = | aov: Aov | self.aovs.as_ref().map(| buffer | {
+            (0..pixels)
+                .map(| idx | {
+                    let value = buffer.value(idx, aov);
+
+                    [value[0], value[1], value[2]]
+                })
+                .collect::<Vec<[f64; 3]>>()
+        });
+
+        let albedo = guide(Aov::Albedo);
+        let normal = guide(Aov::Normal);
+
+        let denoised = denoiser.denoise(self.width, self.height, &color, &Guides {
+            albedo: albedo.as_deref(),
+            normal: normal.as_deref(),
+        });
+
+        self.resolve(denoised);
+    }
+
+    // Applies a chain of effects to the filtered colors, leaving them
+    // with a weight of one like denoising does.
+    pub fn post_process(&mut self, effects: &[PostEffect]) {
+        let pixels = self.width * self.height;
+
+        let mut color: Vec<LinearColor> = (0..pixels).map(| idx | self.color_at(idx)).collect();
+
+        apply_effects(self.width, self.height, &mut color, effects);
+
+        self.resolve(color);
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().map(| count | *count as u64).sum()
+    }
+
+    // Writes out everything accumulated so far, little-endian: the
+    // window's origin and size, then the weighted color sum, total
+    // weight, coverage sum, and sample count of each pixel, and last a
+    // byte saying whether the AOV totals of each pixel follow.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.x0.to_le_bytes())?;
+        out.write_all(&self.y0.to_le_bytes())?;
+        out.write_all(&(self.width as u64).to_le_bytes())?;
+        out.write_all(&(self.height as u64).to_le_bytes())?;
+
+        for idx in 0..self.colors.len() {
+            for component in self.colors[idx].to_array().iter() {
+                out.write_all(&component.to_le_bytes())?;
+            }
+
+            out.write_all(&self.weights[idx].to_le_bytes())?;
+            out.write_all(&self.coverages[idx].to_le_bytes())?;
+            out.write_all(&self.samples[idx].to_le_bytes())?;
+        }
+
+        match &self.aovs {
+            Some(aovs) => {
+                out.write_all(&[1])?;
+                aovs.write(out)
+            },
+            None => out.write_all(&[0])
+        }
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> io::Result<Film> {
+        let x0 = read_u64(input)? as i64;
+        let y0 = read_u64(input)? as i64;
+        let width = read_u64(input)? as usize;
+        let height = read_u64(input)? as usize;
+
+        if width.checked_mul(height).filter(| pixels | *pixels <= MAX_PIXELS).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Film too large."));
+        }
+
+        let mut film = Film::window(x0, y0, width, height);
+
+        for idx in 0..width * height {
+            film.colors[idx] = LinearColor::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            film.weights[idx] = read_f64(input)?;
+            film.coverages[idx] = read_f64(input)?;
+            film.samples[idx] = read_u32(input)?;
+        }
+
+        let mut has_aovs = [0];
+        input.read_exact(&mut has_aovs)?;
+
+        film.aovs = match has_aovs[0] {
+            0 => None,
+            1 => Some(AovBuffer::read(input, width * height)?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Film AOV flag is invalid."))
+        };
+
+        Ok(film)
+    }
+
+    // The final color as linear RGB channels, followed by the requested
+    // AOVs. With layers, the AOVs are named layer.channel, as in
+    // "normal.X". Without, they're named by their channels alone.
+    pub fn to_exr(&self, aovs: &[Aov], layers: bool) -> ExrImage {
+        let mut image = ExrImage::new(self.width, self.height);
+        let pixels = self.width * self.height;
+
+        if layers || aovs.is_empty() {
+            for (channel, name) in ["R", "G", "B"].iter().enumerate() {
+                let values = (0..pixels)
+                    .map(| idx | self.pixel(self.x0 + (idx % self.width) as i64,
+                                            self.y0 + (idx / self.width) as i64)[channel] as f32)
+                    .collect();
+
+                image.add_channel(name, values);
+            }
+        }
+
+        if let Some(buffer) = &self.aovs {
+            for aov in aovs.iter() {
+                let values: Vec<Vec<f64>> = (0..pixels).map(| idx | buffer.value(idx, *aov)).collect();
+
+                for (channel, name) in aov.channels().iter().enumerate() {
+                    let name = if layers { format!("{}.{}", aov.name(), name) } else { String::from(*name) };
+
+                    image.add_channel(&name, values.iter().map(| value | value[channel] as f32).collect());
+                }
+            }
+        }
+
+        image
+    }
+
+    // The image as display encoded floats, for quantizing once the
+    // final image is put together. The encoding converts from the
+    // working space the film was rendered in. With an alpha mode, the
+    // film is one rendered without a background, and its coverage
+    // becomes the alpha. Without, every pixel is opaque.
+    //
+    // Uncovered samples are black, so filtered colors come out scaled
+    // by coverage, and dividing that back out gives the straight color.
+    // Premultiplying happens after encoding, as compositing software
+    // expects of integer images.
+    pub fn to_display_image(&self, encoding: &DisplayEncoding, alpha_mode: Option<AlphaMode>) -> DisplayImage {
+        image::ImageBuffer::from_fn(self.width as u32, self.height as u32, | x, y | {
+            let idx = y as usize * self.width + x as usize;
+
+            let color = self.color_at(idx);
+
+            let (color, alpha) = match alpha_mode {
+                None => (encoding.encode(&color), 1.0),
+                Some(mode) => {
+                    let alpha = self.alpha_at(idx);
+
+                    let straight = if alpha > EPSILON {
+                        encoding.encode(&(color * (1.0 / alpha)))
+                    } else {
+                        [0.0, 0.0, 0.0]
+                    };
+
+                    match mode {
+                        AlphaMode::Premultiplied => (straight.map(| c | c * alpha), alpha),
+                        AlphaMode::Straight => (straight, alpha)
+                    }
+                }
+            };
+
+            image::Rgba([color[0] as f32, color[1] as f32, color[2] as f32, alpha as f32])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::aov::ALL_AOVS;
+
+    fn splat_samples(film: &mut Film, filter: &Filter) {
+        for ii in 0..40 {
+            let x = (ii as f64 * 0.37) % 8.0;
+            let y = (ii as f64 * 0.61) % 6.0;
+
+            film.add_sample(x, y, &LinearColor::new(x / 8.0, y / 6.0, 0.5), if ii % 3 == 0 { 0.0 } else { 1.0 }, filter);
+        }
+    }
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let filter = Filter::Tent { radius: 1.0 };
+        let mut film = Film::window(-2, 3, 8, 6);
+        splat_samples(&mut film, &filter);
+
+        let mut bytes = vec![];
+        film.write(&mut bytes).unwrap();
+
+        let read = Film::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!((read.x0, read.y0, read.width, read.height), (-2, 3, 8, 6));
+        assert_eq!(read.colors, film.colors);
+        assert_eq!(read.weights, film.weights);
+        assert_eq!(read.coverages, film.coverages);
+        assert_eq!(read.samples, film.samples);
+    }
+
+    #[test]
+    fn aovs_round_trip_with_the_film() {
+        let mut film = Film::new(3, 2);
+        film.enable_aovs();
+
+        for ii in 0..12 {
+            film.add_aov_sample(ii as f64 * 0.25, (ii % 2) as f64 + 0.3, &AovSample::new(None));
+        }
+
+        let mut bytes = vec![];
+        film.write(&mut bytes).unwrap();
+
+        let read = Film::read(&mut bytes.as_slice()).unwrap();
+        let (aovs, read_aovs) = (film.aovs.as_ref().unwrap(), read.aovs.as_ref().unwrap());
+
+        for idx in 0..6 {
+            for aov in ALL_AOVS.iter() {
+                assert_eq!(aovs.value(idx, *aov), read_aovs.value(idx, *aov), "{:?} at {}", aov, idx);
+            }
+        }
+    }
+
+    #[test]
+    fn read_rejects_truncated_and_oversized_films() {
+        let mut bytes = vec![];
+        Film::window(0, 0, 4, 4).write(&mut bytes).unwrap();
+
+        assert!(Film::read(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        let mut huge = vec![];
+        huge.extend_from_slice(&0u64.to_le_bytes());
+        huge.extend_from_slice(&0u64.to_le_bytes());
+        huge.extend_from_slice(&(1u64 << 20).to_le_bytes());
+        huge.extend_from_slice(&(1u64 << 20).to_le_bytes());
+
+        assert_eq!(Film::read(&mut huge.as_slice()).err().map(| err | err.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn merged_tiles_match_a_single_film() {
+        let filter = Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 };
+
+        let mut whole = Film::new(8, 6);
+        splat_samples(&mut whole, &filter);
+
+        // Two halves, each with room for the filter's reach past them,
+        // holding only the samples that fall within them.
+        let mut left = Film::window(-2, -2, 8, 10);
+        let mut right = Film::window(2, -2, 8, 10);
+
+        for ii in 0..40 {
+            let x = (ii as f64 * 0.37) % 8.0;
+            let y = (ii as f64 * 0.61) % 6.0;
+            let half = if x < 4.0 { &mut left } else { &mut right };
+
+            half.add_sample(x, y, &LinearColor::new(x / 8.0, y / 6.0, 0.5), if ii % 3 == 0 { 0.0 } else { 1.0 }, &filter);
+        }
+
+        let mut merged = Film::new(8, 6);
+        merged.merge(&left);
+        merged.merge(&right);
+
+        for idx in 0..whole.colors.len() {
+            assert!((merged.weights[idx] - whole.weights[idx]).abs() < 1e-12);
+            assert!((merged.coverages[idx] - whole.coverages[idx]).abs() < 1e-12);
+            let difference = merged.color_at(idx) - whole.color_at(idx);
+
+            assert!(difference.to_array().iter().all(| c | c.abs() < 1e-12));
+        }
+
+        assert_eq!(merged.samples, whole.samples);
+        assert_eq!(merged.total_samples(), 40);
+    }
+}
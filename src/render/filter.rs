@@ -0,0 +1,191 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::f64::consts::PI;
+
+// Reconstruction filters, used to weight each sample's contribution to
+// the pixels around it. Radii are in pixels, and all of the filters are
+// separable, so the weight for an offset (x, y) is eval(x) * eval(y).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Filter {
+    // Equal weight out to the radius. With a radius of one half, each
+    // sample only counts towards the pixel it falls in.
+    Box { radius: f64 },
+
+    // Weight falling off linearly to zero at the radius.
+    Tent { radius: f64 },
+
+    // A Gaussian with the given falloff, shifted down so that it
+    // reaches zero at the radius.
+    Gaussian { radius: f64, alpha: f64 },
+
+    // Mitchell and Netravali's cubic, with their recommended
+    // B = C = 1/3 giving a good balance of blurring and ringing.
+    Mitchell { radius: f64, b: f64, c: f64 },
+
+    // Windowed sinc, with the window spanning the whole radius.
+    Lanczos { radius: f64 },
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// https://www.pbr-book.org/3ed-2018/Sampling_and_Reconstruction/Image_Reconstruction
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+
+    if x > 1.0 {
+        ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x
+         + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+         + (6.0 - 2.0 * b)) / 6.0
+    }
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name.to_lowercase().as_str() {
+            "box" => Some(Filter::Box { radius: 0.5 }),
+            "tent" => Some(Filter::Tent { radius: 1.0 }),
+            "gaussian" => Some(Filter::Gaussian { radius: 1.5, alpha: 2.0 }),
+            "mitchell" => Some(Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+            "lanczos" => Some(Filter::Lanczos { radius: 3.0 }),
+            _ => None
+        }
+    }
+
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius
+        }
+    }
+
+    // Weight along one axis, for a sample the given distance from a
+    // pixel center.
+    fn eval_1d(&self, x: f64) -> f64 {
+        let radius = self.radius();
+
+        if x.abs() > radius {
+            return 0.0;
+        }
+
+        match *self {
+            Filter::Box { .. } => 1.0,
+
+            Filter::Tent { .. } => radius - x.abs(),
+
+            Filter::Gaussian { alpha, .. } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            },
+
+            Filter::Mitchell { b, c, .. } => mitchell(2.0 * x / radius, b, c),
+
+            Filter::Lanczos { .. } => sinc(x) * sinc(x / radius)
+        }
+    }
+
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_FILTERS: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+    fn filters() -> Vec<Filter> {
+        ALL_FILTERS.iter().map(| name | Filter::from_name(name).unwrap()).collect()
+    }
+
+    #[test]
+    fn filters_are_symmetric_and_end_at_their_radius() {
+        for filter in filters() {
+            let radius = filter.radius();
+
+            for ii in 0..=40 {
+                let x = radius * ii as f64 / 40.0;
+
+                assert_eq!(filter.eval_1d(x), filter.eval_1d(-x), "{:?} at {}", filter, x);
+            }
+
+            assert_eq!(filter.eval_1d(radius + 0.01), 0.0, "{:?}", filter);
+            assert_eq!(filter.eval(0.0, radius + 0.01), 0.0, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn box_counts_only_its_own_pixel() {
+        let filter = Filter::Box { radius: 0.5 };
+
+        assert_eq!(filter.eval(0.49, -0.49), 1.0);
+        assert_eq!(filter.eval(0.51, 0.0), 0.0);
+    }
+
+    #[test]
+    fn tent_falls_off_linearly() {
+        let filter = Filter::Tent { radius: 1.0 };
+
+        assert_eq!(filter.eval_1d(0.0), 1.0);
+        assert_eq!(filter.eval_1d(0.25), 0.75);
+        assert_eq!(filter.eval_1d(1.0), 0.0);
+    }
+
+    #[test]
+    fn gaussian_reaches_zero_at_its_radius() {
+        let filter = Filter::Gaussian { radius: 1.5, alpha: 2.0 };
+
+        assert!(filter.eval_1d(0.0) > filter.eval_1d(0.5));
+        assert!(filter.eval_1d(1.49) > 0.0);
+        assert!(filter.eval_1d(1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mitchell_sums_to_one_over_whole_pixels() {
+        let filter = Filter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 };
+
+        assert!((filter.eval_1d(0.0) - 8.0 / 9.0).abs() < 1e-12);
+
+        for ii in 0..10 {
+            let t = ii as f64 / 10.0;
+            let sum: f64 = (-3..=3).map(| k | filter.eval_1d(t + k as f64)).sum();
+
+            assert!((sum - 1.0).abs() < 1e-12, "sum at offset {} is {}", t, sum);
+        }
+    }
+
+    #[test]
+    fn lanczos_is_zero_at_other_pixel_centers() {
+        let filter = Filter::Lanczos { radius: 3.0 };
+
+        assert_eq!(filter.eval_1d(0.0), 1.0);
+
+        for k in 1..=3 {
+            assert!(filter.eval_1d(k as f64).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn names_parse() {
+        assert_eq!(Filter::from_name("Box"), Some(Filter::Box { radius: 0.5 }));
+        assert_eq!(Filter::from_name("sinc"), None);
+    }
+}
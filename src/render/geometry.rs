@@ -8,62 +8,530 @@
 //
 // You must not remove this notice, or any other, from this software.
 
-pub type Point = [f64; 3];
+// Points, vectors, and normals are kept as separate types, since they
+// behave differently: the difference of two points is a vector, a point
+// moved along a vector is another point, and normals transform by the
+// inverse transpose of a matrix rather than the matrix itself.
 
+use std::ops::{Add, AddAssign, Div, Index, Mul, MulAssign, Neg, Sub, SubAssign};
+
+pub const EPSILON: f64 = 0.0001;
+
+// A location in space.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+// A direction, or the displacement between two points.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
 pub struct Vector {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+// A unit vector perpendicular to a surface.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Normal {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+pub struct Ray {
     pub start: Point,
-    pub delta: Point
+    pub delta: Vector
 }
 
-pub const EPSILON: f64 = 0.0001;
+impl Point {
+    pub const fn new(x: f64, y: f64, z: f64) -> Point {
+        Point { x, y, z }
+    }
+
+    pub const fn to_array(self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    // The displacement of this point from the origin.
+    pub const fn to_vector(self) -> Vector {
+        Vector::new(self.x, self.y, self.z)
+    }
+}
+
+impl Vector {
+    pub const ZERO: Vector = Vector::new(0.0, 0.0, 0.0);
+
+    pub const fn new(x: f64, y: f64, z: f64) -> Vector {
+        Vector { x, y, z }
+    }
+
+    pub const fn to_array(self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub fn dot<V: Into<Vector>>(self, other: V) -> f64 {
+        let other = other.into();
+
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross<V: Into<Vector>>(self, other: V) -> Vector {
+        let other = other.into();
+
+        Vector::new(self.y * other.z - self.z * other.y,
+                    self.z * other.x - self.x * other.z,
+                    self.x * other.y - self.y * other.x)
+    }
+
+    pub fn length_squared(self) -> f64 {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> f64 {
+        self.length_squared().sqrt()
+    }
+
+    // The unit vector in the same direction, or None for vectors too
+    // short to have a meaningful direction.
+    pub fn normalize(self) -> Option<Vector> {
+        let len = self.length();
+
+        if len < EPSILON {
+            None
+        } else {
+            Some(self / len)
+        }
+    }
+
+    pub fn to_normal(self) -> Option<Normal> {
+        self.normalize().map(| v | Normal::new(v.x, v.y, v.z))
+    }
+}
+
+impl Normal {
+    // Takes x, y, and z to already be a unit vector.
+    pub const fn new(x: f64, y: f64, z: f64) -> Normal {
+        Normal { x, y, z }
+    }
+
+    pub const fn to_array(self) -> [f64; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub const fn to_vector(self) -> Vector {
+        Vector::new(self.x, self.y, self.z)
+    }
+
+    pub fn dot<V: Into<Vector>>(self, other: V) -> f64 {
+        self.to_vector().dot(other)
+    }
+
+    // This normal, flipped if need be to face against direction.
+    pub fn face_forward(self, direction: Vector) -> Normal {
+        if self.dot(direction) > 0.0 { -self } else { self }
+    }
+}
+
+impl From<Normal> for Vector {
+    fn from(normal: Normal) -> Vector {
+        normal.to_vector()
+    }
+}
+
+impl Ray {
+    // The point t units of delta along the ray.
+    pub fn at(&self, t: f64) -> Point {
+        self.start + self.delta * t
+    }
+}
+
+macro_rules! impl_index {
+    ($t:ty) => {
+        impl Index<usize> for $t {
+            type Output = f64;
+
+            fn index(&self, axis: usize) -> &f64 {
+                match axis {
+                    0 => &self.x,
+                    1 => &self.y,
+                    2 => &self.z,
+                    _ => panic!("Axis {} out of range", axis)
+                }
+            }
+        }
+    }
+}
+
+impl_index!(Point);
+impl_index!(Vector);
+impl_index!(Normal);
+
+impl Add<Vector> for Point {
+    type Output = Point;
+
+    fn add(self, v: Vector) -> Point {
+        Point::new(self.x + v.x, self.y + v.y, self.z + v.z)
+    }
+}
 
-pub fn lenp(pt: Point) -> f64 {
-    let [x, y, z] = pt;
+impl AddAssign<Vector> for Point {
+    fn add_assign(&mut self, v: Vector) {
+        *self = *self + v;
+    }
+}
+
+impl Sub<Vector> for Point {
+    type Output = Point;
 
-    ((x * x) + (y * y) + (z * z)).sqrt()
+    fn sub(self, v: Vector) -> Point {
+        Point::new(self.x - v.x, self.y - v.y, self.z - v.z)
+    }
 }
 
-pub fn normalizep(pt: Point) -> Point {
-    let [x, y, z] = pt;
+impl Sub for Point {
+    type Output = Vector;
 
-    let len = lenp(pt);
+    fn sub(self, other: Point) -> Vector {
+        Vector::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
 
-    if len < EPSILON {
-        panic!("Cannot normalize point vector of length 0.");
+impl Add for Vector {
+    type Output = Vector;
+
+    fn add(self, other: Vector) -> Vector {
+        Vector::new(self.x + other.x, self.y + other.y, self.z + other.z)
     }
+}
 
-    [x / len, y / len, z / len]
+impl AddAssign for Vector {
+    fn add_assign(&mut self, other: Vector) {
+        *self = *self + other;
+    }
 }
 
-pub fn scalep(pt: Point, t: f64) -> Point {
-    let [x, y, z] = pt;
+impl Sub for Vector {
+    type Output = Vector;
 
-    [x * t, y * t, z * t]
+    fn sub(self, other: Vector) -> Vector {
+        Vector::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
 }
 
-pub fn addp(pt0: Point, pt1: Point) -> Point {
-    let [x0, y0, z0] = pt0;
-    let [x1, y1, z1] = pt1;
+impl SubAssign for Vector {
+    fn sub_assign(&mut self, other: Vector) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Vector {
+    type Output = Vector;
 
-    [x0 + x1, y0 + y1, z0 + z1]
+    fn neg(self) -> Vector {
+        Vector::new(-self.x, -self.y, -self.z)
+    }
 }
 
-pub fn subp(pt0: Point, pt1: Point) -> Point {
-    let [x0, y0, z0] = pt0;
-    let [x1, y1, z1] = pt1;
+impl Mul<f64> for Vector {
+    type Output = Vector;
 
-    [x0 - x1, y0 - y1, z0 - z1]
+    fn mul(self, t: f64) -> Vector {
+        Vector::new(self.x * t, self.y * t, self.z * t)
+    }
 }
 
-pub fn dotp(pt0: Point, pt1: Point) -> f64 {
-    let [x0, y0, z0] = pt0;
-    let [x1, y1, z1] = pt1;
+impl Mul<Vector> for f64 {
+    type Output = Vector;
+
+    fn mul(self, v: Vector) -> Vector {
+        v * self
+    }
+}
 
-    x0 * x1 + y0 * y1 + z0 * z1
+impl MulAssign<f64> for Vector {
+    fn mul_assign(&mut self, t: f64) {
+        *self = *self * t;
+    }
 }
 
-pub fn negp(pt: Point) -> Point {
-    let [x, y, z] = pt;
+impl Div<f64> for Vector {
+    type Output = Vector;
 
-    [-x, -y, -z]
+    fn div(self, t: f64) -> Vector {
+        Vector::new(self.x / t, self.y / t, self.z / t)
+    }
+}
+
+impl Neg for Normal {
+    type Output = Normal;
+
+    fn neg(self) -> Normal {
+        Normal::new(-self.x, -self.y, -self.z)
+    }
+}
+
+// Scaling a normal gives a vector, since the result is no longer unit
+// length.
+impl Mul<f64> for Normal {
+    type Output = Vector;
+
+    fn mul(self, t: f64) -> Vector {
+        self.to_vector() * t
+    }
+}
+
+// Mirrors direction about the surface with the given normal.
+pub fn reflect(direction: Vector, normal: Normal) -> Vector {
+    direction - normal * (2.0 * normal.dot(direction))
+}
+
+// Bends a unit direction by Snell's law as it crosses a surface, where
+// eta is the ratio of the refractive index on the incoming side to that
+// on the far side. Returns None when the light is totally reflected
+// instead.
+pub fn refract(direction: Vector, normal: Normal, eta: f64) -> Option<Vector> {
+    let normal = normal.face_forward(direction);
+
+    let cos_incident = -normal.dot(direction);
+    let k = 1.0 - eta * eta * (1.0 - cos_incident * cos_incident);
+
+    if k < 0.0 {
+        None
+    } else {
+        (direction * eta + normal * (eta * cos_incident - k.sqrt())).normalize()
+    }
+}
+
+// An orthonormal basis, with w along a given direction and u and v
+// across it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Basis {
+    pub u: Vector,
+    pub v: Vector,
+    pub w: Vector,
+}
+
+impl Basis {
+    pub fn from_w(w: Normal) -> Basis {
+        let w = w.to_vector();
+        let helper = if w.x.abs() > 0.9 { Vector::new(0.0, 1.0, 0.0) } else { Vector::new(1.0, 0.0, 0.0) };
+        let u = helper.cross(w).normalize().unwrap_or(Vector::new(0.0, 0.0, 1.0));
+
+        Basis { u, v: w.cross(u), w }
+    }
+
+    // The vector with coordinates (a, b, c) in this basis.
+    pub fn to_world(self, a: f64, b: f64, c: f64) -> Vector {
+        self.u * a + self.v * b + self.w * c
+    }
+}
+
+// An affine transform, as a 4x4 matrix in row major order that takes
+// points as column vectors.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Matrix4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4 {
+        m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]
+    };
+
+    pub fn translation(v: Vector) -> Matrix4 {
+        let mut result = Matrix4::IDENTITY;
+
+        result.m[0][3] = v.x;
+        result.m[1][3] = v.y;
+        result.m[2][3] = v.z;
+
+        result
+    }
+
+    pub fn scaling(v: Vector) -> Matrix4 {
+        let mut result = Matrix4::IDENTITY;
+
+        result.m[0][0] = v.x;
+        result.m[1][1] = v.y;
+        result.m[2][2] = v.z;
+
+        result
+    }
+
+    // A rotation by angle radians about axis, counterclockwise looking
+    // down the axis towards the origin.
+    pub fn rotation(axis: Vector, angle: f64) -> Matrix4 {
+        let Vector { x, y, z } = axis.normalize().unwrap_or(Vector::new(0.0, 1.0, 0.0));
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+
+        Matrix4 {
+            m: [
+                [t * x * x + c, t * x * y - s * z, t * x * z + s * y, 0.0],
+                [t * x * y + s * z, t * y * y + c, t * y * z - s * x, 0.0],
+                [t * x * z - s * y, t * y * z + s * x, t * z * z + c, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ]
+        }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut result = Matrix4::IDENTITY;
+
+        for (row, values) in result.m.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().enumerate() {
+                *value = self.m[col][row];
+            }
+        }
+
+        result
+    }
+
+    // The inverse by Gauss-Jordan elimination with partial pivoting, or
+    // None if the matrix is singular.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::IDENTITY.m;
+
+        for col in 0..4 {
+            let pivot = (col..4).max_by(| r0, r1 | a[*r0][col].abs().total_cmp(&a[*r1][col].abs()))?;
+
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+
+            for k in 0..4 {
+                a[col][k] *= scale;
+                inv[col][k] *= scale;
+            }
+
+            for row in 0..4 {
+                if row != col {
+                    let factor = a[row][col];
+
+                    for k in 0..4 {
+                        a[row][k] -= factor * a[col][k];
+                        inv[row][k] -= factor * inv[col][k];
+                    }
+                }
+            }
+        }
+
+        Some(Matrix4 { m: inv })
+    }
+
+    pub fn transform_point(&self, p: Point) -> Point {
+        let m = &self.m;
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+
+        Point::new((m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3]) / w,
+                   (m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3]) / w,
+                   (m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3]) / w)
+    }
+
+    // Vectors are displacements, so they're unaffected by translation.
+    pub fn transform_vector(&self, v: Vector) -> Vector {
+        let m = &self.m;
+
+        Vector::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                    m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                    m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+    }
+
+    // Transforms a normal, with this matrix being the inverse of the
+    // transform. Going by the inverse transpose keeps normals
+    // perpendicular to surfaces that are stretched.
+    pub fn transform_normal(&self, n: Normal) -> Normal {
+        self.transpose()
+            .transform_vector(n.to_vector())
+            .to_normal()
+            .unwrap_or(n)
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    // The transform that applies other, then self.
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        let mut result = Matrix4 { m: [[0.0; 4]; 4] };
+
+        for (row, values) in result.m.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().enumerate() {
+                *value = (0..4).map(| k | self.m[row][k] * other.m[k][col]).sum();
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!((a - b).length() < 1e-12, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn transforms_compose_right_to_left() {
+        let transform = Matrix4::translation(Vector::new(1.0, 2.0, 3.0))
+            * Matrix4::rotation(Vector::new(0.0, 0.0, 1.0), FRAC_PI_2)
+            * Matrix4::scaling(Vector::new(2.0, 1.0, 1.0));
+
+        // Stretched to (2, 0, 0), turned onto the y axis, then moved.
+        assert_close(transform.transform_point(Point::new(1.0, 0.0, 0.0)).to_vector(), Vector::new(1.0, 4.0, 3.0));
+
+        // Directions aren't moved.
+        assert_close(transform.transform_vector(Vector::new(1.0, 0.0, 0.0)), Vector::new(0.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let transform = Matrix4::translation(Vector::new(-3.0, 0.5, 2.0))
+            * Matrix4::rotation(Vector::new(1.0, 1.0, 0.0), 0.7)
+            * Matrix4::scaling(Vector::new(0.5, 3.0, 1.5));
+
+        let inverse = transform.inverse().unwrap();
+        let p = Point::new(0.3, -1.2, 4.0);
+
+        assert_close(inverse.transform_point(transform.transform_point(p)).to_vector(), p.to_vector());
+
+        for (row, values) in (transform * inverse).m.iter().enumerate() {
+            for (col, value) in values.iter().enumerate() {
+                assert!((value - Matrix4::IDENTITY.m[row][col]).abs() < 1e-12);
+            }
+        }
+
+        assert_eq!(Matrix4::scaling(Vector::new(1.0, 0.0, 1.0)).inverse(), None);
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_when_stretched() {
+        let transform = Matrix4::scaling(Vector::new(4.0, 1.0, 1.0));
+        let inverse = transform.inverse().unwrap();
+
+        // A surface sloping at 45 degrees, stretched along x, slopes
+        // less steeply.
+        let tangent = transform.transform_vector(Vector::new(1.0, -1.0, 0.0));
+        let normal = inverse.transform_normal(Vector::new(1.0, 1.0, 0.0).to_normal().unwrap());
+
+        assert!(normal.to_vector().dot(tangent).abs() < 1e-12);
+        assert!((normal.to_vector().length() - 1.0).abs() < 1e-12);
+    }
 }
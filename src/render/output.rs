@@ -0,0 +1,394 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Final images, held as display encoded floats until they're written
+// out, so they can be quantized to 16 bits, or to 8 bits with
+// dithering to break up the banding in smooth gradients. PNG files are
+// tagged with the color space they're encoded in.
+
+extern crate crc32fast;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::render::color::{quantize_u8, quantize_u16};
+use crate::render::colorspace::{ColorSpace, Transfer};
+use crate::render::random::Rng;
+
+// Display encoded RGBA, with every channel in [0, 1].
+pub type DisplayImage = image::ImageBuffer<image::Rgba<f32>, Vec<f32>>;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    pub fn from_name(name: &str) -> Option<BitDepth> {
+        match name {
+            "8" => Some(BitDepth::Eight),
+            "16" => Some(BitDepth::Sixteen),
+            _ => None
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Dither {
+    // A repeating 8x8 Bayer matrix.
+    Ordered,
+
+    // A 64x64 tile of blue noise, which has no visible pattern.
+    BlueNoise,
+}
+
+impl Dither {
+    pub fn from_name(name: &str) -> Option<Dither> {
+        match name.to_lowercase().as_str() {
+            "ordered" => Some(Dither::Ordered),
+            "blue" | "bluenoise" => Some(Dither::BlueNoise),
+            _ => None
+        }
+    }
+
+    fn threshold_map(&self) -> ThresholdMap {
+        match self {
+            Dither::Ordered => ThresholdMap::bayer(3),
+            Dither::BlueNoise => ThresholdMap::blue_noise(6)
+        }
+    }
+}
+
+// A square tile of thresholds in [0, 1), repeated over the image.
+struct ThresholdMap {
+    size: usize,
+    ranks: Vec<usize>,
+}
+
+impl ThresholdMap {
+    // The Bayer matrix with 2^bits pixels on a side, built up by
+    // repeatedly tiling the 2x2 matrix [[0, 2], [3, 1]] at finer scales.
+    fn bayer(bits: u32) -> ThresholdMap {
+        let size = 1 << bits;
+
+        let ranks = (0..size * size)
+            .map(| idx | {
+                let (x, y) = (idx % size, idx / size);
+
+                (0..bits).fold(0, | rank, bit | {
+                    let (bx, by) = ((x >> bit) & 1, (y >> bit) & 1);
+
+                    rank | ((((bx ^ by) << 1) | by) << (2 * (bits - 1 - bit)))
+                })
+            })
+            .collect();
+
+        ThresholdMap { size, ranks }
+    }
+
+    // Blue noise with 2^bits pixels on a side, from Ulichney's
+    // void-and-cluster method, "The void-and-cluster method for dither
+    // array generation" (1993). Each pixel is ranked by the order it's
+    // added in, always filling the largest gap left between the pixels
+    // already placed, as measured by a Gaussian blur that wraps around
+    // the edges of the tile.
+    fn blue_noise(bits: u32) -> ThresholdMap {
+        let size = 1 << bits;
+        let pixels = size * size;
+
+        const SIGMA: f64 = 1.5;
+
+        let kernel: Vec<f64> = (0..pixels)
+            .map(| idx | {
+                let wrap = | d: usize | d.min(size - d) as f64;
+                let (dx, dy) = (wrap(idx % size), wrap(idx / size));
+
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect();
+
+        let mut energy = vec![0.0; pixels];
+        let mut placed = vec![false; pixels];
+
+        let toggle = | energy: &mut Vec<f64>, placed: &mut Vec<bool>, idx: usize, on: bool | {
+            let (px, py) = (idx % size, idx / size);
+            let sign = if on { 1.0 } else { -1.0 };
+
+            placed[idx] = on;
+
+            for (jdx, e) in energy.iter_mut().enumerate() {
+                let dx = (jdx % size + size - px) % size;
+                let dy = (jdx / size + size - py) % size;
+
+                *e += sign * kernel[dy * size + dx];
+            }
+        };
+
+        // The placed pixel with the most energy around it, or the empty
+        // pixel with the least.
+        let tightest_cluster = | energy: &[f64], placed: &[bool] | {
+            (0..pixels).filter(| idx | placed[*idx])
+                .max_by(| a, b | energy[*a].total_cmp(&energy[*b])).unwrap()
+        };
+
+        let largest_void = | energy: &[f64], placed: &[bool] | {
+            (0..pixels).filter(| idx | !placed[*idx])
+                .min_by(| a, b | energy[*a].total_cmp(&energy[*b])).unwrap()
+        };
+
+        // Start from a sparse random pattern, and even it out by moving
+        // pixels from the tightest cluster to the largest void until the
+        // move would put the pixel straight back.
+        let mut rng = Rng::new(pixels as u64);
+        let initial = pixels / 10;
+
+        while placed.iter().filter(| p | **p).count() < initial {
+            let idx = (rng.next_u64() % pixels as u64) as usize;
+
+            if !placed[idx] {
+                toggle(&mut energy, &mut placed, idx, true);
+            }
+        }
+
+        loop {
+            let cluster = tightest_cluster(&energy, &placed);
+            toggle(&mut energy, &mut placed, cluster, false);
+
+            let void = largest_void(&energy, &placed);
+            toggle(&mut energy, &mut placed, void, true);
+
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; pixels];
+
+        // Rank the initial pixels by taking them away again, tightest
+        // cluster first.
+        {
+            let mut energy = energy.clone();
+            let mut placed = placed.clone();
+
+            for rank in (0..initial).rev() {
+                let cluster = tightest_cluster(&energy, &placed);
+
+                toggle(&mut energy, &mut placed, cluster, false);
+                ranks[cluster] = rank;
+            }
+        }
+
+        // Then everything else, filling the largest void each time.
+        for rank in initial..pixels {
+            let void = largest_void(&energy, &placed);
+
+            toggle(&mut energy, &mut placed, void, true);
+            ranks[void] = rank;
+        }
+
+        ThresholdMap { size, ranks }
+    }
+
+    // Each channel reads the tile at a different offset, so the
+    // channels don't all step up at the same pixels.
+    fn threshold(&self, x: u32, y: u32, channel: usize) -> f64 {
+        let offset = channel * self.size / 3 + channel;
+        let (tx, ty) = ((x as usize + offset) % self.size, (y as usize + 2 * offset) % self.size);
+
+        (self.ranks[ty * self.size + tx] as f64 + 0.5) / (self.size * self.size) as f64
+    }
+}
+
+// Converts a display image to integers for writing out, as RGBA or, if
+// the alpha isn't wanted, RGB. Dithering only applies to 8-bit images.
+pub fn quantize(image: &DisplayImage, depth: BitDepth, dither: Option<Dither>, alpha: bool) -> image::DynamicImage {
+    let (width, height) = image.dimensions();
+
+    match depth {
+        BitDepth::Eight => {
+            let map = dither.map(| dither | dither.threshold_map());
+
+            let quantized = image::ImageBuffer::from_fn(width, height, | x, y | {
+                let pixel = image.get_pixel(x, y);
+
+                let channel = | c: usize | {
+                    let threshold = map.as_ref().map_or(0.5, | map | map.threshold(x, y, c));
+
+                    quantize_u8(pixel[c] as f64, threshold)
+                };
+
+                image::Rgba([channel(0), channel(1), channel(2), quantize_u8(pixel[3] as f64, 0.5)])
+            });
+
+            if alpha {
+                image::DynamicImage::ImageRgba8(quantized)
+            } else {
+                image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(quantized).into_rgb8())
+            }
+        },
+
+        BitDepth::Sixteen => {
+            let quantized = image::ImageBuffer::from_fn(width, height, | x, y | {
+                image::Rgba(image.get_pixel(x, y).0.map(| c | quantize_u16(c as f64)))
+            });
+
+            if alpha {
+                image::DynamicImage::ImageRgba16(quantized)
+            } else {
+                image::DynamicImage::ImageRgb16(image::DynamicImage::ImageRgba16(quantized).into_rgb16())
+            }
+        }
+    }
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&chunk[4..]);
+
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+
+    chunk
+}
+
+// Chunks describing how a PNG is encoded: sRGB for plain sRGB, and
+// otherwise the chromaticities of the primaries, the gamma where the
+// transfer function is a plain power, and the coding independent code
+// points of ITU-T H.273 where there are ones for the color space.
+fn png_color_chunks(space: ColorSpace, transfer: Transfer) -> Vec<u8> {
+    let mut chunks = vec![];
+
+    let fixed = | value: f64 | ((value * 100000.0).round() as u32).to_be_bytes();
+
+    if space == ColorSpace::Srgb && transfer == Transfer::Srgb {
+        chunks.extend(png_chunk(b"sRGB", &[0]));
+    }
+
+    let p = space.primaries();
+
+    let chromaticities: Vec<u8> = [p.white, p.red, p.green, p.blue].iter()
+        .flat_map(| (x, y) | [fixed(*x), fixed(*y)])
+        .flatten()
+        .collect();
+
+    chunks.extend(png_chunk(b"cHRM", &chromaticities));
+
+    match transfer {
+        Transfer::Linear => chunks.extend(png_chunk(b"gAMA", &fixed(1.0))),
+        Transfer::Srgb => chunks.extend(png_chunk(b"gAMA", &fixed(1.0 / 2.2))),
+        Transfer::Rec709 => ()
+    }
+
+    let primaries = match space {
+        ColorSpace::Srgb => Some(1),
+        ColorSpace::Rec2020 => Some(9),
+        ColorSpace::DisplayP3 => Some(12),
+        ColorSpace::AcesCg => None
+    };
+
+    let transfer_code = match transfer {
+        Transfer::Linear => 8,
+        Transfer::Srgb => 13,
+        Transfer::Rec709 => 1
+    };
+
+    if let Some(primaries) = primaries {
+        // RGB, full range
+        chunks.extend(png_chunk(b"cICP", &[primaries, transfer_code, 0, 1]));
+    }
+
+    chunks
+}
+
+// Writes an image in the format given by the path's extension, tagging
+// PNG files with the color space and transfer function they're encoded
+// with.
+pub fn save(image: &image::DynamicImage, path: &str, space: ColorSpace, transfer: Transfer) -> io::Result<()> {
+    let is_png = Path::new(path).extension()
+        .is_some_and(| ext | ext.eq_ignore_ascii_case("png"));
+
+    if !is_png {
+        return image.save(path).map_err(io::Error::other);
+    }
+
+    let mut bytes = vec![];
+
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).map_err(io::Error::other)?;
+
+    // The signature and the header chunk come first, and color chunks
+    // have to go before the image data.
+    const HEADER_END: usize = 8 + 8 + 13 + 4;
+
+    let mut tagged = bytes[..HEADER_END].to_vec();
+    tagged.extend(png_color_chunks(space, transfer));
+    tagged.extend_from_slice(&bytes[HEADER_END..]);
+
+    fs::write(path, tagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Splits a run of chunks into their types and data, checking the
+    // length and CRC of each.
+    fn parse_chunks(mut bytes: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut chunks = vec![];
+
+        while !bytes.is_empty() {
+            let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+            let (body, rest) = bytes[4..].split_at(len + 4);
+            let crc = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(body);
+
+            assert_eq!(hasher.finalize(), crc);
+
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            bytes = &rest[4..];
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn chunks_end_with_their_crc() {
+        // The end chunk of every PNG file.
+        assert_eq!(png_chunk(b"IEND", &[]), [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+
+        assert_eq!(parse_chunks(&png_chunk(b"sRGB", &[0])), vec![(b"sRGB".to_vec(), vec![0])]);
+    }
+
+    #[test]
+    fn color_chunks_describe_the_encoding() {
+        let kinds = | space, transfer | -> Vec<Vec<u8>> {
+            parse_chunks(&png_color_chunks(space, transfer)).into_iter().map(| (kind, _) | kind).collect()
+        };
+
+        assert_eq!(kinds(ColorSpace::Srgb, Transfer::Srgb), [b"sRGB".to_vec(), b"cHRM".to_vec(), b"gAMA".to_vec(), b"cICP".to_vec()]);
+        assert_eq!(kinds(ColorSpace::AcesCg, Transfer::Linear), [b"cHRM".to_vec(), b"gAMA".to_vec()]);
+        assert_eq!(kinds(ColorSpace::Rec2020, Transfer::Rec709), [b"cHRM".to_vec(), b"cICP".to_vec()]);
+
+        let chunks = parse_chunks(&png_color_chunks(ColorSpace::DisplayP3, Transfer::Srgb));
+        let chromaticities = &chunks[0].1;
+
+        // White point x, then y, in units of 1/100000.
+        assert_eq!(chromaticities[..8], [0, 0, 0x7a, 0x26, 0, 0, 0x80, 0x84]);
+        assert_eq!(chunks[2].1, [12, 13, 0, 1]);
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Effects applied to the finished image, in linear color before it's
+// converted for display. Effects run in the order they're listed, each
+// on the result of the one before.
+
+use rayon::prelude::*;
+
+use crate::render::color::LinearColor;
+
+use crate::render::random::Rng;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PostEffect {
+    // Glow spreading out from parts of the image brighter than the
+    // threshold. The radius is a fraction of the image width.
+    Bloom { threshold: f64, intensity: f64, radius: f64 },
+
+    // Darkening towards the corners, by up to strength in the corners
+    // themselves.
+    Vignette { strength: f64 },
+
+    // Red and blue spread apart towards the edges of the image, by up
+    // to strength times the distance from the center.
+    ChromaticAberration { strength: f64 },
+
+    // Random variation in brightness from pixel to pixel, the same from
+    // run to run for a given seed.
+    Grain { amount: f64, seed: u32 },
+
+    // Unsharp masking, boosting the difference between each pixel and
+    // its blurred surroundings.
+    Sharpen { amount: f64, radius: f64 },
+}
+
+impl PostEffect {
+    // Parses an effect as its name, optionally followed by parameters,
+    // as in "bloom:threshold=1.5:intensity=0.2". Parameters left out
+    // take their defaults.
+    pub fn from_spec(spec: &str) -> Option<PostEffect> {
+        let mut parts = spec.trim().split(':');
+
+        let mut effect = match parts.next()?.to_lowercase().as_str() {
+            "bloom" => PostEffect::Bloom { threshold: 1.0, intensity: 0.3, radius: 0.02 },
+            "vignette" => PostEffect::Vignette { strength: 0.4 },
+            "chromatic" => PostEffect::ChromaticAberration { strength: 0.005 },
+            "grain" => PostEffect::Grain { amount: 0.05, seed: 0 },
+            "sharpen" => PostEffect::Sharpen { amount: 0.5, radius: 1.0 },
+            _ => return None
+        };
+
+        for param in parts {
+            let (key, value) = param.split_once('=')?;
+            let value: f64 = value.trim().parse().ok()?;
+
+            match (&mut effect, key.trim()) {
+                (PostEffect::Bloom { threshold, .. }, "threshold") => *threshold = value,
+                (PostEffect::Bloom { intensity, .. }, "intensity") => *intensity = value,
+                (PostEffect::Bloom { radius, .. }, "radius") => *radius = value,
+                (PostEffect::Vignette { strength }, "strength") => *strength = value,
+                (PostEffect::ChromaticAberration { strength }, "strength") => *strength = value,
+                (PostEffect::Grain { amount, .. }, "amount") => *amount = value,
+                (PostEffect::Grain { seed, .. }, "seed") => *seed = value as u32,
+                (PostEffect::Sharpen { amount, .. }, "amount") => *amount = value,
+                (PostEffect::Sharpen { radius, .. }, "radius") => *radius = value,
+                _ => return None
+            }
+        }
+
+        Some(effect)
+    }
+
+    pub fn apply(&self, width: usize, height: usize, colors: &mut [LinearColor]) {
+        match *self {
+            PostEffect::Bloom { threshold, intensity, radius } => {
+                let bright: Vec<LinearColor> = colors.iter()
+                    .map(| c | c.map(| v | (v - threshold).max(0.0)))
+                    .collect();
+
+                let glow = gaussian_blur(width, height, &bright, radius * width as f64);
+
+                for (color, glow) in colors.iter_mut().zip(glow.iter()) {
+                    *color += *glow * intensity;
+                }
+            },
+
+            PostEffect::Vignette { strength } => {
+                for_each_pixel(width, height, colors, | x, y, color | {
+                    let (dx, dy) = offset_from_center(width, height, x, y);
+
+                    *color * (1.0 - strength * (dx * dx + dy * dy) / 2.0).max(0.0)
+                });
+            },
+
+            PostEffect::ChromaticAberration { strength } => {
+                let source = colors.to_vec();
+
+                for_each_pixel(width, height, colors, | x, y, color | {
+                    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+                    let (px, py) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+
+                    let red = sample_bilinear(width, height, &source,
+                                              cx + px * (1.0 + strength), cy + py * (1.0 + strength));
+                    let blue = sample_bilinear(width, height, &source,
+                                               cx + px * (1.0 - strength), cy + py * (1.0 - strength));
+
+                    LinearColor::new(red[0], color[1], blue[2])
+                });
+            },
+
+            PostEffect::Grain { amount, seed } => {
+                for_each_pixel(width, height, colors, | x, y, color | {
+                    let noise = Rng::for_pixel(x as u32, y as u32, seed).next_f64() * 2.0 - 1.0;
+
+                    // Grain shows up most in the mid tones.
+                    let strength = amount * color.luminance().clamp(0.0, 1.0).sqrt();
+
+                    *color * (1.0 + strength * noise).max(0.0)
+                });
+            },
+
+            PostEffect::Sharpen { amount, radius } => {
+                let blurred = gaussian_blur(width, height, colors, radius);
+
+                for (color, blurred) in colors.iter_mut().zip(blurred.iter()) {
+                    for c in 0..3 {
+                        color[c] = (color[c] + amount * (color[c] - blurred[c])).max(0.0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn apply_effects(width: usize, height: usize, colors: &mut [LinearColor], effects: &[PostEffect]) {
+    for effect in effects.iter() {
+        effect.apply(width, height, colors);
+    }
+}
+
+fn for_each_pixel<F>(width: usize, height: usize, colors: &mut [LinearColor], update: F)
+where F: Fn(usize, usize, &LinearColor) -> LinearColor + Sync {
+
+    colors.par_chunks_mut(width.max(1)).enumerate().for_each(| (y, row) | {
+        for (x, color) in row.iter_mut().enumerate() {
+            if y < height {
+                *color = update(x, y, color);
+            }
+        }
+    });
+}
+
+// Offset of a pixel center from the image center, scaled so the
+// corners are at a distance of one along each axis.
+fn offset_from_center(width: usize, height: usize, x: usize, y: usize) -> (f64, f64) {
+    let (hw, hh) = (width as f64 / 2.0, height as f64 / 2.0);
+
+    ((x as f64 + 0.5 - hw) / hw, (y as f64 + 0.5 - hh) / hh)
+}
+
+// The color at a point, in pixel units, interpolated between the four
+// nearest pixel centers. Points off the image take the nearest edge.
+fn sample_bilinear(width: usize, height: usize, colors: &[LinearColor], x: f64, y: f64) -> LinearColor {
+    let (fx, fy) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (fx.floor(), fy.floor());
+    let (tx, ty) = (fx - x0, fy - y0);
+
+    let pixel = | px: f64, py: f64 | {
+        let px = (px as i64).clamp(0, width as i64 - 1) as usize;
+        let py = (py as i64).clamp(0, height as i64 - 1) as usize;
+
+        colors[py * width + px]
+    };
+
+    let top = pixel(x0, y0) * (1.0 - tx) + pixel(x0 + 1.0, y0) * tx;
+    let bottom = pixel(x0, y0 + 1.0) * (1.0 - tx) + pixel(x0 + 1.0, y0 + 1.0) * tx;
+
+    top * (1.0 - ty) + bottom * ty
+}
+
+// Separable Gaussian blur with the given standard deviation in pixels,
+// clamping to the edges of the image.
+fn gaussian_blur(width: usize, height: usize, colors: &[LinearColor], sigma: f64) -> Vec<LinearColor> {
+    if sigma < 0.1 {
+        return colors.to_vec();
+    }
+
+    let radius = (3.0 * sigma).ceil() as i64;
+
+    let kernel: Vec<f64> = (-radius..=radius)
+        .map(| ii | (-(ii * ii) as f64 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(| k | k / total).collect();
+
+    let blur_pass = | source: &[LinearColor], horizontal: bool | -> Vec<LinearColor> {
+        let mut result = vec![LinearColor::BLACK; source.len()];
+
+        result.par_chunks_mut(width.max(1)).enumerate().for_each(| (y, row) | {
+            for (x, out) in row.iter_mut().enumerate() {
+                let mut sum = LinearColor::BLACK;
+
+                for (k, weight) in kernel.iter().enumerate() {
+                    let offset = k as i64 - radius;
+
+                    let (sx, sy) = if horizontal {
+                        ((x as i64 + offset).clamp(0, width as i64 - 1), y as i64)
+                    } else {
+                        (x as i64, (y as i64 + offset).clamp(0, height as i64 - 1))
+                    };
+
+                    sum += source[sy as usize * width + sx as usize] * *weight;
+                }
+
+                *out = sum;
+            }
+        });
+
+        result
+    };
+
+    blur_pass(&blur_pass(colors, true), false)
+}
@@ -0,0 +1,303 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+
+use crate::render::{
+    RenderOptions,
+    Scene,
+    render_pass,
+    render_settings,
+};
+
+use crate::render::film::Film;
+use crate::render::colorspace::{ColorSpace, DisplayEncoding, Transfer};
+use crate::render::output::{quantize, save, BitDepth};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RTCKPT01";
+
+const MAX_NAME_LEN: usize = 1024;
+
+// Settings for rendering in repeated passes, each adding another set of
+// samples to every pixel, until one of the limits is reached or the
+// render is stopped. With no limits, rendering continues until stopped.
+pub struct ProgressiveOptions {
+    // Total number of passes to render, including any already in a
+    // resumed checkpoint.
+    pub passes: Option<u32>,
+
+    // Passes aren't started if they're expected to run past this.
+    pub time_budget: Option<Duration>,
+
+    // Files the accumulated render is saved to, as a checkpoint that
+    // can be resumed and as an image.
+    pub checkpoint: Option<PathBuf>,
+    pub preview: Option<PathBuf>,
+
+    // Previews are encoded the same way as the final image.
+    pub output_space: ColorSpace,
+    pub transfer: Transfer,
+
+    // Minimum time between saving checkpoints and previews. Both are
+    // always saved when rendering finishes.
+    pub save_interval: Duration,
+
+    // Picks up from the checkpoint file, if there is one.
+    pub resume: bool,
+}
+
+// The state of a progressive render: everything accumulated in the
+// passes rendered so far.
+pub struct Checkpoint {
+    pub scene_name: String,
+
+    // Every setting that changes what a pass renders, from the sampler
+    // to the working color space, since passes rendered with different
+    // ones can't be merged.
+    pub settings: String,
+
+    pub passes: u32,
+    pub film: Film,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn write_string<W: Write>(out: &mut W, string: &str) -> io::Result<()> {
+    out.write_all(&(string.len() as u32).to_le_bytes())?;
+    out.write_all(string.as_bytes())
+}
+
+fn read_string<R: Read>(input: &mut R, what: &str) -> io::Result<String> {
+    let len = read_u32(input)? as usize;
+
+    if len > MAX_NAME_LEN {
+        return Err(invalid_data(&format!("Checkpoint {} too long.", what)));
+    }
+
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+
+    String::from_utf8(bytes)
+        .map_err(| _ | invalid_data(&format!("Checkpoint {} is not UTF-8.", what)))
+}
+
+impl Checkpoint {
+    fn new(scene: &Scene, options: &RenderOptions, imgx: u32, imgy: u32) -> Checkpoint {
+        Checkpoint {
+            scene_name: String::from(scene.name),
+            settings: render_settings(scene, options),
+            passes: 0,
+            film: Film::new(imgx, imgy),
+        }
+    }
+
+    // Written to a temporary file first, so that being interrupted
+    // part way through never leaves a damaged checkpoint behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+
+        {
+            let mut out = BufWriter::new(File::create(&temp_path)?);
+
+            out.write_all(CHECKPOINT_MAGIC)?;
+            write_string(&mut out, &self.scene_name)?;
+            write_string(&mut out, &self.settings)?;
+            out.write_all(&self.passes.to_le_bytes())?;
+
+            self.film.write(&mut out)?;
+
+            out.flush()?;
+        }
+
+        fs::rename(&temp_path, path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(invalid_data("Not a checkpoint file."));
+        }
+
+        let scene_name = read_string(&mut input, "scene name")?;
+        let settings = read_string(&mut input, "settings")?;
+
+        let passes = read_u32(&mut input)?;
+        let film = Film::read(&mut input)?;
+
+        Ok(Checkpoint {
+            scene_name,
+            settings,
+            passes,
+            film,
+        })
+    }
+}
+
+fn save_progress(
+    checkpoint: &Checkpoint, scene: &Scene, options: &RenderOptions, progressive: &ProgressiveOptions
+) -> io::Result<()> {
+
+    let start = Instant::now();
+
+    if let Some(path) = &progressive.checkpoint {
+        checkpoint.save(path)?;
+    }
+
+    if let Some(path) = &progressive.preview {
+        let encoding = DisplayEncoding::new(scene.color_space, progressive.output_space, progressive.transfer);
+        let image = quantize(&checkpoint.film.to_display_image(&encoding, None), BitDepth::Eight, None, false);
+
+        save(&image, &path.to_string_lossy(), progressive.output_space, progressive.transfer)?;
+    }
+
+    options.record_phase("checkpoint", start.elapsed());
+
+    Ok(())
+}
+
+// Renders passes until the sample target or time budget is reached,
+// or the render is stopped through the render options. Passes that
+// are stopped part way through are thrown away, so every pixel in the
+// result has the same number of passes.
+pub fn render_progressive(
+    scene: &Scene,
+    imgx: u32,
+    imgy: u32,
+    options: &RenderOptions,
+    progressive: &ProgressiveOptions,
+) -> io::Result<Checkpoint> {
+
+    let mut checkpoint = match &progressive.checkpoint {
+        Some(path) if progressive.resume && path.exists() => {
+            let checkpoint = Checkpoint::load(path)?;
+
+            if checkpoint.scene_name != scene.name {
+                return Err(invalid_data("Checkpoint is for a different scene."));
+            }
+
+            if checkpoint.film.width() != imgx as usize || checkpoint.film.height() != imgy as usize {
+                return Err(invalid_data("Checkpoint is for a different image size."));
+            }
+
+            if checkpoint.settings != render_settings(scene, options) {
+                return Err(invalid_data(&format!("Checkpoint was rendered with different settings: {}",
+                                                 checkpoint.settings)));
+            }
+
+            checkpoint
+        },
+        _ => Checkpoint::new(scene, options, imgx, imgy)
+    };
+
+    let is_stopped = || options.stop.as_ref().is_some_and(| stop | stop.load(SeqCst));
+
+    let start = Instant::now();
+    let mut last_save = start;
+    let mut last_pass_time = Duration::from_secs(0);
+
+    loop {
+        if progressive.passes.is_some_and(| passes | checkpoint.passes >= passes) || is_stopped() {
+            break;
+        }
+
+        // Always get at least one pass, since an image with no samples
+        // at all is of no use to anyone.
+        if let Some(budget) = progressive.time_budget {
+            if checkpoint.passes > 0 && start.elapsed() + last_pass_time > budget {
+                break;
+            }
+        }
+
+        let pass_start = Instant::now();
+
+        let (film, complete) = render_pass(scene, imgx, imgy, options, checkpoint.passes);
+
+        if !complete {
+            break;
+        }
+
+        checkpoint.film.merge(&film);
+        checkpoint.passes += 1;
+
+        last_pass_time = pass_start.elapsed();
+
+        if last_save.elapsed() >= progressive.save_interval {
+            save_progress(&checkpoint, scene, options, progressive)?;
+            last_save = Instant::now();
+        }
+    }
+
+    save_progress(&checkpoint, scene, options, progressive)?;
+
+    Ok(checkpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::filter::Filter;
+    use crate::render::color::LinearColor;
+
+    #[test]
+    fn checkpoints_save_and_load() {
+        let mut film = Film::new(4, 3);
+        film.add_sample(1.5, 2.25, &LinearColor::new(0.25, 0.5, 1.0), 1.0, &Filter::Tent { radius: 1.0 });
+
+        let checkpoint = Checkpoint {
+            scene_name: String::from("Test Scene"),
+            settings: String::from("sampler Sobol"),
+            passes: 7,
+            film,
+        };
+
+        let path = std::env::temp_dir().join(format!("raytracer-test-{}.ckpt", std::process::id()));
+
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.scene_name, "Test Scene");
+        assert_eq!(loaded.settings, "sampler Sobol");
+        assert_eq!(loaded.passes, 7);
+        assert_eq!(loaded.film.pixel(1, 2), checkpoint.film.pixel(1, 2));
+        assert_eq!(loaded.film.total_samples(), 1);
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("raytracer-test-{}.other", std::process::id()));
+
+        fs::write(&path, b"RTCKPT99 and then some").unwrap();
+        let loaded = Checkpoint::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.err().map(| err | err.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// A small, deterministic pseudo-random generator. Each pixel seeds its
+// own generator from its coordinates, so renders come out the same
+// whether they're done in parallel or not.
+//
+// https://prng.di.unimi.it/splitmix64.c
+
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Progressive rendering takes a fresh set of samples for each pixel
+    // on every pass, so the pass is mixed into the seed too. Pass zero
+    // gives the same numbers as a single pass render.
+    pub fn for_pixel(x: u32, y: u32, pass: u32) -> Rng {
+        Rng::new((((y as u64) << 32) | x as u64) ^ (pass as u64).wrapping_mul(0xd1b54a32d192ed03))
+    }
+
+    // A new generator, seeded from this one, for an independent stream
+    // of numbers.
+    pub fn split(&mut self) -> Rng {
+        Rng::new(self.next_u64() ^ 0x5851f42d4c957f2d)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniformly distributed in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use crate::render::random::Rng;
+
+// Ways of choosing where rays pass through a pixel. All of them are
+// driven by the pixel's own random number generator, so a given pixel
+// always gets the same samples.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Sampler {
+    // Centers of a regular grid of subpixels.
+    Regular,
+
+    // One random point within each cell of a regular grid.
+    Jittered,
+
+    // The Halton sequence in bases 2 and 3, randomly offset per pixel.
+    Halton,
+
+    // The first two dimensions of the Sobol sequence, forming a (0,2)
+    // net when the sample count is a power of two, randomly scrambled
+    // per pixel.
+    Sobol,
+
+    // Mitchell's best candidate algorithm, which spreads samples out
+    // evenly without any regular structure.
+    BlueNoise,
+}
+
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+
+    let mut result = 0.0;
+    let mut scale = inv_base;
+
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inv_base;
+    }
+
+    result
+}
+
+// Generator matrices for the first two Sobol dimensions, applied to
+// the bits of index: the identity (giving the base 2 van der Corput
+// sequence), and the matrix for the primitive polynomial x + 1.
+fn sobol(mut index: u32) -> (u32, u32) {
+    let mut v0: u32 = 1 << 31;
+    let mut v1: u32 = 1 << 31;
+
+    let mut x = 0;
+    let mut y = 0;
+
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= v0;
+            y ^= v1;
+        }
+
+        index >>= 1;
+        v0 >>= 1;
+        v1 ^= v1 >> 1;
+    }
+
+    (x, y)
+}
+
+fn to_unit(bits: u32) -> f64 {
+    bits as f64 / (1u64 << 32) as f64
+}
+
+// Distance between points on the unit square wrapped into a torus, so
+// that samples near opposite edges count as close to each other.
+fn toroidal_distance2(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let dx = (a.0 - b.0).abs();
+    let dy = (a.1 - b.1).abs();
+
+    let dx = dx.min(1.0 - dx);
+    let dy = dy.min(1.0 - dy);
+
+    dx * dx + dy * dy
+}
+
+const BLUE_NOISE_CANDIDATES: usize = 16;
+
+impl Sampler {
+    pub fn from_name(name: &str) -> Option<Sampler> {
+        match name.to_lowercase().as_str() {
+            "regular" => Some(Sampler::Regular),
+            "jittered" => Some(Sampler::Jittered),
+            "halton" => Some(Sampler::Halton),
+            "sobol" => Some(Sampler::Sobol),
+            "bluenoise" | "blue-noise" => Some(Sampler::BlueNoise),
+            _ => None
+        }
+    }
+
+    // Like pixel_samples, but ordered so that every prefix of the list
+    // covers the whole pixel, for use when the samples may not all be
+    // taken.
+    pub fn progressive_samples(&self, per_axis: u32, rng: &mut Rng) -> Vec<(f64, f64)> {
+        let mut samples = self.pixel_samples(per_axis, rng);
+
+        if let Sampler::Regular | Sampler::Jittered = self {
+            // Fisher-Yates shuffle
+            for ii in (1..samples.len()).rev() {
+                let jj = (rng.next_u64() % (ii as u64 + 1)) as usize;
+                samples.swap(ii, jj);
+            }
+        }
+
+        samples
+    }
+
+    // Returns per_axis squared sample positions, as offsets within the
+    // pixel in [0, 1) on each axis.
+    pub fn pixel_samples(&self, per_axis: u32, rng: &mut Rng) -> Vec<(f64, f64)> {
+        let count = per_axis * per_axis;
+
+        match self {
+            Sampler::Regular | Sampler::Jittered => {
+                let cell = 1.0 / per_axis as f64;
+
+                (0..count).map(| ii | {
+                    let (jx, jy) = if *self == Sampler::Jittered {
+                        (rng.next_f64(), rng.next_f64())
+                    } else {
+                        (0.5, 0.5)
+                    };
+
+                    (((ii / per_axis) as f64 + jx) * cell,
+                     ((ii % per_axis) as f64 + jy) * cell)
+                }).collect()
+            },
+
+            Sampler::Halton => {
+                let (ox, oy) = (rng.next_f64(), rng.next_f64());
+
+                (0..count).map(| ii | {
+                    ((radical_inverse(2, ii) + ox).fract(),
+                     (radical_inverse(3, ii) + oy).fract())
+                }).collect()
+            },
+
+            Sampler::Sobol => {
+                let (sx, sy) = (rng.next_u64() as u32, rng.next_u64() as u32);
+
+                (0..count).map(| ii | {
+                    let (x, y) = sobol(ii);
+
+                    (to_unit(x ^ sx), to_unit(y ^ sy))
+                }).collect()
+            },
+
+            Sampler::BlueNoise => {
+                let mut samples: Vec<(f64, f64)> = Vec::with_capacity(count as usize);
+
+                for _ in 0..count {
+                    let best = (0..BLUE_NOISE_CANDIDATES)
+                        .map(| _ | (rng.next_f64(), rng.next_f64()))
+                        .map(| candidate | {
+                            let nearest = samples
+                                .iter()
+                                .map(| s | toroidal_distance2(*s, candidate))
+                                .fold(f64::INFINITY, f64::min);
+
+                            (candidate, nearest)
+                        })
+                        .fold(None, | best: Option<((f64, f64), f64)>, c | match best {
+                            Some(b) if b.1 >= c.1 => Some(b),
+                            _ => Some(c)
+                        });
+
+                    samples.extend(best.map(| (candidate, _) | candidate));
+                }
+
+                samples
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_SAMPLERS: [Sampler; 5] = [
+        Sampler::Regular,
+        Sampler::Jittered,
+        Sampler::Halton,
+        Sampler::Sobol,
+        Sampler::BlueNoise,
+    ];
+
+    #[test]
+    fn radical_inverse_mirrors_digits() {
+        assert_eq!(radical_inverse(2, 0), 0.0);
+        assert_eq!(radical_inverse(2, 1), 0.5);
+        assert_eq!(radical_inverse(2, 2), 0.25);
+        assert_eq!(radical_inverse(2, 3), 0.75);
+        assert!((radical_inverse(3, 1) - 1.0 / 3.0).abs() < 1e-12);
+        assert!((radical_inverse(3, 2) - 2.0 / 3.0).abs() < 1e-12);
+        assert!((radical_inverse(3, 3) - 1.0 / 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sobol_starts_with_known_points() {
+        let points: Vec<(f64, f64)> = (0..4).map(| ii | {
+            let (x, y) = sobol(ii);
+            (to_unit(x), to_unit(y))
+        }).collect();
+
+        assert_eq!(points, vec![(0.0, 0.0), (0.5, 0.5), (0.25, 0.75), (0.75, 0.25)]);
+    }
+
+    #[test]
+    fn sobol_is_a_0_2_net() {
+        // Every elementary interval of area 1/16 holds exactly one of
+        // the first 16 points.
+        let points: Vec<(u32, u32)> = (0..16).map(sobol).collect();
+
+        for a in 0..=4 {
+            let b = 4 - a;
+            let mut counts = vec![0; 16];
+
+            for (x, y) in points.iter() {
+                let cx = if a == 0 { 0 } else { x >> (32 - a) };
+                let cy = if b == 0 { 0 } else { y >> (32 - b) };
+
+                counts[(cx * (1 << b) + cy) as usize] += 1;
+            }
+
+            assert!(counts.iter().all(| c | *c == 1), "intervals 2^{} by 2^{}: {:?}", a, b, counts);
+        }
+    }
+
+    #[test]
+    fn regular_samples_cell_centers() {
+        let mut samples = Sampler::Regular.pixel_samples(2, &mut Rng::new(1));
+        samples.sort_by(| a, b | a.partial_cmp(b).unwrap());
+
+        assert_eq!(samples, vec![(0.25, 0.25), (0.25, 0.75), (0.75, 0.25), (0.75, 0.75)]);
+    }
+
+    #[test]
+    fn samples_fill_the_pixel_reproducibly() {
+        for sampler in ALL_SAMPLERS.iter() {
+            for per_axis in 1..5 {
+                let samples = sampler.pixel_samples(per_axis, &mut Rng::new(42));
+
+                assert_eq!(samples.len(), (per_axis * per_axis) as usize, "{:?}", sampler);
+                assert!(samples.iter().all(| (x, y) | (0.0..1.0).contains(x) && (0.0..1.0).contains(y)),
+                        "{:?} sampled outside the pixel", sampler);
+                assert_eq!(samples, sampler.pixel_samples(per_axis, &mut Rng::new(42)), "{:?}", sampler);
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_samples_reorder_the_same_points() {
+        for sampler in [Sampler::Regular, Sampler::Halton, Sampler::Sobol].iter() {
+            let mut plain = sampler.pixel_samples(4, &mut Rng::new(7));
+            let mut progressive = sampler.progressive_samples(4, &mut Rng::new(7));
+
+            plain.sort_by(| a, b | a.partial_cmp(b).unwrap());
+            progressive.sort_by(| a, b | a.partial_cmp(b).unwrap());
+
+            assert_eq!(plain, progressive, "{:?}", sampler);
+        }
+    }
+
+    #[test]
+    fn names_parse() {
+        assert_eq!(Sampler::from_name("Sobol"), Some(Sampler::Sobol));
+        assert_eq!(Sampler::from_name("blue-noise"), Some(Sampler::BlueNoise));
+        assert_eq!(Sampler::from_name("random"), None);
+    }
+}
@@ -8,19 +8,27 @@
 //
 // You must not remove this notice, or any other, from this software.
 
+use std::f64::consts::PI;
+
 use crate::render::{
-    Point,
     Surface,
     Hittable,
-    Vector,
+    Emitter,
     RayHit,
-    subp,
-    dotp,
-    ray_location,
-    normalizep,
+};
+
+use crate::render::geometry::{
+    Basis,
+    Matrix4,
+    Normal,
+    Point,
+    Ray,
+    Vector,
     EPSILON,
 };
 
+use crate::render::stats;
+
 pub struct Sphere {
     pub center: Point,
     pub r: f64,
@@ -28,62 +36,528 @@ pub struct Sphere {
 }
 
 pub struct Plane {
-    pub normal: Point,
+    pub normal: Normal,
+    pub p0: Point,
+    pub surface: Surface,
+
+    // Axes within the plane, used for its surface coordinates.
+    basis: Basis,
+}
+
+// A parallelogram with one corner at p0 and sides u and v. The
+// normal follows the right hand rule from u to v, which is also the
+// side that emits light when the surface is emissive.
+pub struct Rectangle {
     pub p0: Point,
+    pub u: Vector,
+    pub v: Vector,
+    pub surface: Surface,
+}
+
+pub struct TriangleMesh {
+    pub vertices: Vec<Point>,
+    pub triangles: Vec<[usize; 3]>,
     pub surface: Surface,
+
+    // Running total of triangle areas, used to pick triangles in
+    // proportion to their size when sampling the mesh as a light.
+    cumulative_area: Vec<f64>,
 }
 
 impl Hittable for Sphere {
-    fn hit_test(&self, ray: &Vector) -> Option<RayHit> {
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit> {
+        stats::record(| s | s.sphere_tests += 1);
+
         // Hit test algorithm taken from this website and translated to
         // Rust:
         //
         // https://viclw17.github.io/2018/07/16/raytracing-ray-sphere-intersection
 
-        let oc = subp(ray.start, self.center);
-        let a = dotp(ray.delta, ray.delta);
-        let b = 2.0 * dotp(oc, ray.delta);
-        let c = dotp(oc, oc) - self.r * self.r;
+        let oc = ray.start - self.center;
+        let a = ray.delta.length_squared();
+        let b = 2.0 * oc.dot(ray.delta);
+        let c = oc.length_squared() - self.r * self.r;
         let discriminant = b*b - 4.0*a*c;
 
         if discriminant < 0.0 {
+            return None;
+        }
+
+        // Take the nearest intersection in front of the ray, which is
+        // the far side of the sphere for rays that start on (or in) it.
+        let t0 = (-b - discriminant.sqrt()) / (2.0*a);
+        let t1 = (-b + discriminant.sqrt()) / (2.0*a);
+
+        let t = if t0 > EPSILON { t0 } else { t1 };
+
+        if t <= EPSILON {
             None
         } else {
-            let t = (-b - discriminant.sqrt()) / (2.0*a);
-            let hit_point = ray_location(ray, t);
+            let hit_point = ray.at(t);
+            let normal = (hit_point - self.center).to_normal()?;
 
+            // Rays from inside see the inner side of the surface, so the
+            // normal is turned to face them, as for the other shapes.
             Some(RayHit {
                 distance: t,
                 hit_point,
-                normal: normalizep(subp(hit_point, self.center)),
+                normal: normal.face_forward(ray.delta),
+                front_face: normal.dot(ray.delta) < 0.0,
                 surface: self.surface
             })
         }
     }
+
+    // Longitude and latitude, with the poles on the y axis.
+    fn uv(&self, hit: &RayHit) -> (f64, f64) {
+        let normal = (hit.hit_point - self.center).to_normal().unwrap_or(hit.normal);
+
+        (0.5 + normal.z.atan2(normal.x) / (2.0 * PI),
+         normal.y.clamp(-1.0, 1.0).acos() / PI)
+    }
+
+    fn emitter(&self) -> Option<&dyn Emitter> {
+        if self.surface.is_emissive() { Some(self) } else { None }
+    }
+}
+
+impl Emitter for Sphere {
+    fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.r * self.r
+    }
+
+    fn sample_point(&self, u: f64, v: f64) -> (Point, Normal) {
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+
+        let normal = Normal::new(r * phi.cos(), r * phi.sin(), z);
+
+        (self.center + normal * self.r, normal)
+    }
+
+    // Only the cap of the sphere facing from can light it, so sample
+    // the cone of directions that the sphere covers as seen from there,
+    // and take the nearest point along each one.
+    fn sample_point_from(&self, from: &Point, u: f64, v: f64) -> (Point, Normal, f64) {
+        let to_center = self.center - *from;
+        let d2 = to_center.length_squared();
+        let sin2_max = self.r * self.r / d2;
+
+        let axis = match to_center.to_normal() {
+            Some(axis) if sin2_max < 1.0 => axis,
+            _ => {
+                let (point, normal) = self.sample_point(u, v);
+
+                return (point, normal, self.area());
+            }
+        };
+
+        let cos_max = (1.0 - sin2_max).sqrt();
+        let solid_angle = 2.0 * PI * sin2_max / (1.0 + cos_max);
+
+        let cos_theta = 1.0 - u * sin2_max / (1.0 + cos_max);
+        let sin2_theta = (1.0 - cos_theta * cos_theta).max(0.0);
+        let phi = 2.0 * PI * v;
+
+        let direction = Basis::from_w(axis).to_world(sin2_theta.sqrt() * phi.cos(),
+                                                     sin2_theta.sqrt() * phi.sin(),
+                                                     cos_theta);
+
+        let d = d2.sqrt();
+        let t = d * cos_theta - (self.r * self.r - d2 * sin2_theta).max(0.0).sqrt();
+
+        let point = *from + direction * t;
+        let normal = (point - self.center).to_normal().unwrap_or(-axis);
+        let cos_light = -normal.dot(direction);
+
+        (point, normal, solid_angle * t * t / cos_light.max(EPSILON))
+    }
+}
+
+impl Plane {
+    pub fn new(normal: Normal, p0: Point, surface: Surface) -> Plane {
+        Plane {
+            normal,
+            p0,
+            surface,
+            basis: Basis::from_w(normal),
+        }
+    }
 }
 
 impl Hittable for Plane {
-    fn hit_test(&self, ray: &Vector) -> Option<RayHit> {
-        let denom = dotp(self.normal, ray.delta);
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit> {
+        stats::record(| s | s.plane_tests += 1);
+
+        let denom = self.normal.dot(ray.delta);
 
         if denom.abs() < EPSILON {
             None
         } else {
-            let p0l0 = subp(self.p0, ray.start);
-            let t = dotp(p0l0, self.normal) / denom;
+            let p0l0 = self.p0 - ray.start;
+            let t = p0l0.dot(self.normal) / denom;
 
             if t <= EPSILON {
                 None
             } else {
-                let hit_point = ray_location(ray, t);
-
                 Some(RayHit {
                     distance: t,
-                    hit_point,
+                    hit_point: ray.at(t),
                     normal: self.normal,
+                    front_face: denom < 0.0,
                     surface: self.surface
                 })
             }
         }
     }
+
+    // Planes go on forever, so their coordinates repeat every unit
+    // along a pair of axes within the plane.
+    fn uv(&self, hit: &RayHit) -> (f64, f64) {
+        let offset = hit.hit_point - self.p0;
+
+        (offset.dot(self.basis.u).rem_euclid(1.0), offset.dot(self.basis.v).rem_euclid(1.0))
+    }
+}
+
+impl Rectangle {
+    // Rectangles with parallel sides have no area and no normal.
+    fn normal(&self) -> Option<Normal> {
+        self.u.cross(self.v).to_normal()
+    }
+
+    // How far a point in the rectangle's plane is along each side.
+    fn coordinates(&self, point: Point) -> (f64, f64) {
+        let offset = point - self.p0;
+
+        (offset.dot(self.u) / self.u.length_squared(), offset.dot(self.v) / self.v.length_squared())
+    }
+}
+
+impl Hittable for Rectangle {
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit> {
+        stats::record(| s | s.rectangle_tests += 1);
+
+        let normal = self.normal()?;
+        let denom = normal.dot(ray.delta);
+
+        if denom.abs() < EPSILON {
+            return None;
+        }
+
+        let t = (self.p0 - ray.start).dot(normal) / denom;
+
+        if t <= EPSILON {
+            return None;
+        }
+
+        let hit_point = ray.at(t);
+        let (ut, vt) = self.coordinates(hit_point);
+
+        if !(0.0..=1.0).contains(&ut) || !(0.0..=1.0).contains(&vt) {
+            return None;
+        }
+
+        Some(RayHit {
+            distance: t,
+            hit_point,
+            normal: if denom > 0.0 { -normal } else { normal },
+            front_face: denom < 0.0,
+            surface: self.surface
+        })
+    }
+
+    fn uv(&self, hit: &RayHit) -> (f64, f64) {
+        self.coordinates(hit.hit_point)
+    }
+
+    fn emitter(&self) -> Option<&dyn Emitter> {
+        if self.surface.is_emissive() { Some(self) } else { None }
+    }
+}
+
+impl Emitter for Rectangle {
+    fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    fn area(&self) -> f64 {
+        self.u.cross(self.v).length()
+    }
+
+    fn sample_point(&self, u: f64, v: f64) -> (Point, Normal) {
+        // Only emitters with area are sampled, so the normal exists.
+        (self.p0 + (self.u * u + self.v * v), self.normal().unwrap_or_default())
+    }
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: Vec<Point>, triangles: Vec<[usize; 3]>, surface: Surface) -> TriangleMesh {
+        let mut total = 0.0;
+
+        let cumulative_area = triangles.iter().map(| tri | {
+            let [a, b, c] = *tri;
+
+            total += (vertices[b] - vertices[a]).cross(vertices[c] - vertices[a]).length() / 2.0;
+            total
+        }).collect();
+
+        TriangleMesh {
+            vertices,
+            triangles,
+            surface,
+            cumulative_area
+        }
+    }
+
+    // An axis aligned box spanning min to max, made of twelve triangles.
+    pub fn cuboid(min: Point, max: Point, surface: Surface) -> TriangleMesh {
+        let corner = | x: usize, y: usize, z: usize | Point::new(
+            if x == 0 { min.x } else { max.x },
+            if y == 0 { min.y } else { max.y },
+            if z == 0 { min.z } else { max.z },
+        );
+
+        let vertices = vec![
+            corner(0, 0, 0), corner(1, 0, 0), corner(1, 1, 0), corner(0, 1, 0),
+            corner(0, 0, 1), corner(1, 0, 1), corner(1, 1, 1), corner(0, 1, 1),
+        ];
+
+        let triangles = vec![
+            [0, 2, 1], [0, 3, 2], // -z
+            [4, 5, 6], [4, 6, 7], // +z
+            [0, 1, 5], [0, 5, 4], // -y
+            [3, 7, 6], [3, 6, 2], // +y
+            [0, 4, 7], [0, 7, 3], // -x
+            [1, 2, 6], [1, 6, 5], // +x
+        ];
+
+        TriangleMesh::new(vertices, triangles, surface)
+    }
+
+    // A triangular prism, with the triangle given by its corners at one
+    // end, swept along length to the other end. The triangles are wound
+    // so their normals face out, whichever way round the corners are
+    // given.
+    pub fn prism(corners: [Point; 3], length: Vector, surface: Surface) -> TriangleMesh {
+        let [a, b, c] = corners;
+
+        let (a, b, c) = if (b - a).cross(c - a).dot(length) > 0.0 {
+            (a, c, b)
+        } else {
+            (a, b, c)
+        };
+
+        let vertices = vec![a, b, c, a + length, b + length, c + length];
+
+        let triangles = vec![
+            [0, 1, 2], [3, 5, 4], // ends
+            [0, 3, 4], [0, 4, 1], // sides
+            [1, 4, 5], [1, 5, 2],
+            [2, 5, 3], [2, 3, 0],
+        ];
+
+        TriangleMesh::new(vertices, triangles, surface)
+    }
+
+    // The weights of a triangle's second and third vertices that make
+    // up point, if the point lies on the triangle.
+    fn barycentric(&self, tri: &[usize; 3], point: Point) -> Option<(f64, f64)> {
+        let [a, b, c] = *tri;
+        let v0 = self.vertices[a];
+
+        let edge1 = self.vertices[b] - v0;
+        let edge2 = self.vertices[c] - v0;
+
+        let normal = edge1.cross(edge2);
+        let area2 = normal.length_squared();
+
+        if area2 < EPSILON * EPSILON {
+            return None;
+        }
+
+        let offset = point - v0;
+
+        if offset.dot(normal).abs() > EPSILON * area2.sqrt() {
+            return None;
+        }
+
+        let u = offset.cross(edge2).dot(normal) / area2;
+        let v = edge1.cross(offset).dot(normal) / area2;
+
+        if u < -EPSILON || v < -EPSILON || u + v > 1.0 + EPSILON {
+            None
+        } else {
+            Some((u, v))
+        }
+    }
+
+    fn triangle_hit(&self, ray: &Ray, tri: &[usize; 3]) -> Option<RayHit> {
+        // Möller–Trumbore intersection:
+        //
+        // https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
+
+        let [a, b, c] = *tri;
+        let v0 = self.vertices[a];
+
+        let edge1 = self.vertices[b] - v0;
+        let edge2 = self.vertices[c] - v0;
+
+        let h = ray.delta.cross(edge2);
+        let det = edge1.dot(h);
+
+        if det.abs() < EPSILON * EPSILON {
+            return None;
+        }
+
+        let s = ray.start - v0;
+        let u = s.dot(h) / det;
+
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = ray.delta.dot(q) / det;
+
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(q) / det;
+
+        if t <= EPSILON {
+            return None;
+        }
+
+        let normal = edge1.cross(edge2).to_normal()?;
+        let front_face = normal.dot(ray.delta) <= 0.0;
+
+        Some(RayHit {
+            distance: t,
+            hit_point: ray.at(t),
+            normal: if front_face { normal } else { -normal },
+            front_face,
+            surface: self.surface
+        })
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit> {
+        stats::record(| s | s.triangle_tests += self.triangles.len() as u64);
+
+        self.triangles
+            .iter()
+            .fold(None, | last_hit, tri | {
+                let hit = self.triangle_hit(ray, tri);
+
+                if hit > last_hit {
+                    hit
+                } else {
+                    last_hit
+                }
+            })
+    }
+
+    // Barycentric coordinates within whichever triangle the hit point
+    // lies on.
+    fn uv(&self, hit: &RayHit) -> (f64, f64) {
+        self.triangles
+            .iter()
+            .find_map(| tri | self.barycentric(tri, hit.hit_point))
+            .unwrap_or((0.0, 0.0))
+    }
+
+    fn emitter(&self) -> Option<&dyn Emitter> {
+        if self.surface.is_emissive() && !self.triangles.is_empty() { Some(self) } else { None }
+    }
+}
+
+impl Emitter for TriangleMesh {
+    fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    fn area(&self) -> f64 {
+        *self.cumulative_area.last().unwrap_or(&0.0)
+    }
+
+    fn sample_point(&self, u: f64, v: f64) -> (Point, Normal) {
+        // Pick a triangle by area with u, then reuse the leftover
+        // fraction of u to place the point within that triangle.
+
+        let target = u * self.area();
+        let idx = self.cumulative_area
+            .iter()
+            .position(| total | *total > target)
+            .unwrap_or(self.triangles.len() - 1);
+
+        let start = if idx == 0 { 0.0 } else { self.cumulative_area[idx - 1] };
+        let span = self.cumulative_area[idx] - start;
+        let u = if span > 0.0 { ((target - start) / span).clamp(0.0, 1.0) } else { 0.0 };
+
+        let [a, b, c] = self.triangles[idx];
+        let v0 = self.vertices[a];
+        let edge1 = self.vertices[b] - v0;
+        let edge2 = self.vertices[c] - v0;
+
+        let su = u.sqrt();
+        let point = v0 + (edge1 * (su * (1.0 - v)) + edge2 * (su * v));
+
+        // Degenerate triangles have no area, so they're never picked.
+        (point, edge1.cross(edge2).to_normal().unwrap_or_default())
+    }
+}
+
+// Another shape moved, turned, or stretched by a transform. Rays are
+// taken into the shape's own space to be tested, and their hits brought
+// back out. Scaling changes the length of a ray's direction on the way
+// in, but a hit's parameter t along the ray is the same in both spaces,
+// so the distance it reports is still measured along the original ray.
+// Transformed shapes aren't sampled as area lights.
+pub struct Transformed {
+    object: Box<dyn Hittable + Send + Sync>,
+    to_world: Matrix4,
+    to_object: Matrix4,
+}
+
+impl Transformed {
+    // Returns None for transforms that can't be undone, such as scaling
+    // by zero.
+    pub fn new(object: Box<dyn Hittable + Send + Sync>, transform: Matrix4) -> Option<Transformed> {
+        Some(Transformed {
+            object,
+            to_world: transform,
+            to_object: transform.inverse()?,
+        })
+    }
+}
+
+impl Hittable for Transformed {
+    fn hit_test(&self, ray: &Ray) -> Option<RayHit> {
+        let local = Ray {
+            start: self.to_object.transform_point(ray.start),
+            delta: self.to_object.transform_vector(ray.delta)
+        };
+
+        self.object.hit_test(&local).map(| hit | RayHit {
+            hit_point: self.to_world.transform_point(hit.hit_point),
+            normal: self.to_object.transform_normal(hit.normal),
+            .. hit
+        })
+    }
+
+    fn uv(&self, hit: &RayHit) -> (f64, f64) {
+        self.object.uv(&RayHit {
+            hit_point: self.to_object.transform_point(hit.hit_point),
+            normal: self.to_world.transform_normal(hit.normal),
+            .. *hit
+        })
+    }
 }
@@ -0,0 +1,194 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Analytic daylight model from:
+//
+// A. J. Preetham, P. Shirley, B. Smits, "A Practical Analytic Model
+// for Daylight", SIGGRAPH 1999.
+//
+// The sky is +y up, with azimuth measured from +x towards +z. The
+// model covers the sky dome only - the sun itself is a separate
+// directional light, obtained from sun_light.
+
+use std::f64::consts::FRAC_PI_2;
+
+use crate::render::Light;
+
+use crate::render::geometry::{
+    Vector,
+    EPSILON,
+};
+
+use crate::render::color::{
+    LinearColor,
+    xyz_to_linear_color,
+};
+
+// Angular diameter of the sun as seen from the earth, in radians.
+pub const SUN_ANGULAR_DIAMETER: f64 = 0.0093;
+
+#[derive(Copy, Clone)]
+pub struct PhysicalSky {
+    // Angles in radians
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+
+    // Haziness of the atmosphere, from about 2 (very clear) to 10 (hazy).
+    pub turbidity: f64,
+
+    // Converts the model's luminance (in kcd/m^2) to scene radiance.
+    pub intensity: f64,
+
+    // Color seen looking down below the horizon.
+    pub ground: LinearColor,
+}
+
+struct Perez {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+}
+
+impl Perez {
+    fn f(&self, theta: f64, gamma: f64) -> f64 {
+        let cos_gamma = gamma.cos();
+
+        (1.0 + self.a * (self.b / theta.cos().max(EPSILON)).exp())
+            * (1.0 + self.c * (self.d * gamma).exp() + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+fn cubic(coeffs: [f64; 4], x: f64) -> f64 {
+    ((coeffs[0] * x + coeffs[1]) * x + coeffs[2]) * x + coeffs[3]
+}
+
+impl PhysicalSky {
+    // Unit vector pointing from the scene towards the sun.
+    pub fn sun_direction(&self) -> Vector {
+        let cos_elevation = self.sun_elevation.cos();
+
+        Vector::new(cos_elevation * self.sun_azimuth.cos(),
+                    self.sun_elevation.sin(),
+                    cos_elevation * self.sun_azimuth.sin())
+    }
+
+    // A directional light matching the sun's position, dimmed by the
+    // length of its path through the atmosphere.
+    pub fn sun_light(&self, intensity: f64) -> Light {
+        Light::Directional {
+            direction: -self.sun_direction(),
+            color: LinearColor::WHITE,
+            intensity: intensity * self.sun_transmittance(),
+            angular_diameter: SUN_ANGULAR_DIAMETER
+        }
+    }
+
+    fn sun_transmittance(&self) -> f64 {
+        if self.sun_elevation <= 0.0 {
+            return 0.0;
+        }
+
+        // Relative air mass (Kasten and Young) and the clear sky
+        // transmittance fit by Meinel and Meinel.
+        let zenith_degrees = (FRAC_PI_2 - self.sun_elevation).to_degrees();
+        let air_mass = 1.0 / ((FRAC_PI_2 - self.sun_elevation).cos()
+                              + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+
+        0.7_f64.powf(air_mass.powf(0.678))
+    }
+
+    fn theta_sun(&self) -> f64 {
+        FRAC_PI_2 - self.sun_elevation.clamp(0.0, FRAC_PI_2)
+    }
+
+    fn perez_luminance(&self) -> Perez {
+        let t = self.turbidity;
+
+        Perez {
+            a: 0.1787 * t - 1.4630,
+            b: -0.3554 * t + 0.4275,
+            c: -0.0227 * t + 5.3251,
+            d: 0.1206 * t - 2.5771,
+            e: -0.0670 * t + 0.3703,
+        }
+    }
+
+    fn perez_x(&self) -> Perez {
+        let t = self.turbidity;
+
+        Perez {
+            a: -0.0193 * t - 0.2592,
+            b: -0.0665 * t + 0.0008,
+            c: -0.0004 * t + 0.2125,
+            d: -0.0641 * t - 0.8989,
+            e: -0.0033 * t + 0.0452,
+        }
+    }
+
+    fn perez_y(&self) -> Perez {
+        let t = self.turbidity;
+
+        Perez {
+            a: -0.0167 * t - 0.2608,
+            b: -0.0950 * t + 0.0092,
+            c: -0.0079 * t + 0.2102,
+            d: -0.0441 * t - 1.6537,
+            e: -0.0109 * t + 0.0529,
+        }
+    }
+
+    // Luminance and chromaticity straight overhead.
+    fn zenith(&self) -> (f64, f64, f64) {
+        let t = self.turbidity;
+        let theta_s = self.theta_sun();
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f64::consts::PI - 2.0 * theta_s);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0], theta_s)
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394], theta_s)
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886], theta_s);
+
+        let y = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0], theta_s)
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516], theta_s)
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688], theta_s);
+
+        (luminance.max(0.0), x, y)
+    }
+
+    pub fn color(&self, direction: &Vector) -> LinearColor {
+        let direction = direction.normalize().unwrap_or(*direction);
+
+        if direction.y < 0.0 {
+            return self.ground;
+        }
+
+        // Angle from the zenith, and angle from the sun.
+        let theta = direction.y.clamp(0.0, 1.0).acos().min(FRAC_PI_2 - EPSILON);
+        let gamma = direction.dot(self.sun_direction()).clamp(-1.0, 1.0).acos();
+
+        let theta_s = self.theta_sun();
+        let (zenith_luminance, zenith_x, zenith_y) = self.zenith();
+
+        let relative = | perez: Perez | perez.f(theta, gamma) / perez.f(0.0, theta_s);
+
+        let luminance = zenith_luminance * relative(self.perez_luminance()) * self.intensity;
+        let x = zenith_x * relative(self.perez_x());
+        let y = zenith_y * relative(self.perez_y());
+
+        if y < EPSILON {
+            return LinearColor::BLACK;
+        }
+
+        xyz_to_linear_color(&[x / y * luminance, luminance, (1.0 - x - y) / y * luminance])
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Spectral rendering, where each camera ray carries a single
+// wavelength of light instead of red, green, and blue. Scene colors are
+// turned into smooth spectra and read off at the ray's wavelength, so
+// everything the ray picks up is one value. Averaging many rays over
+// the visible spectrum, each weighted by the CIE color matching
+// functions, gives back the color.
+//
+// What this buys is light that behaves differently at different
+// wavelengths, like glass bending blue light more than red.
+
+use std::cell::Cell;
+
+use crate::render::color::LinearColor;
+use crate::render::colorspace::{ColorSpace, transform, xyz_to_rgb};
+
+// The range of wavelengths sampled, in nanometers.
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 720.0;
+
+// The sodium D line, where refractive indices are usually quoted, and
+// the wavelength used for refraction when rendering in RGB.
+pub const REFERENCE_WAVELENGTH: f64 = 589.3;
+
+thread_local! {
+    static WAVELENGTH: Cell<Option<f64>> = const { Cell::new(None) };
+}
+
+// Sets the wavelength of the rays being traced on the current thread,
+// or None when rendering in RGB.
+pub fn set_wavelength(wavelength: Option<f64>) {
+    WAVELENGTH.with(| w | w.set(wavelength));
+}
+
+pub fn wavelength() -> Option<f64> {
+    WAVELENGTH.with(Cell::get)
+}
+
+// Maps a uniform random number in [0, 1) to a wavelength.
+pub fn sample_wavelength(u: f64) -> f64 {
+    MIN_WAVELENGTH + u * (MAX_WAVELENGTH - MIN_WAVELENGTH)
+}
+
+// How the refractive index of a material varies with wavelength.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Ior {
+    Constant(f64),
+
+    // Cauchy's equation, n = a + b / λ², with λ in micrometers.
+    Cauchy { a: f64, b: f64 },
+
+    // The Sellmeier equation, n² = 1 + Σ b λ² / (λ² - c), with λ in
+    // micrometers, as glass makers quote it.
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+// Borosilicate crown glass, the usual glass for lenses, which is close
+// enough to Cauchy's equation over the visible spectrum.
+pub const BK7: Ior = Ior::Cauchy { a: 1.5046, b: 0.00420 };
+
+// Dense flint glass, which spreads colors out more than twice as much
+// as BK7.
+pub const SF11: Ior = Ior::Sellmeier {
+    b: [1.73759695, 0.313747346, 1.89878101],
+    c: [0.013188707, 0.0623068142, 155.23629],
+};
+
+impl Ior {
+    // The refractive index at a wavelength in nanometers.
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let um = wavelength / 1000.0;
+
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy { a, b } => a + b / (um * um),
+            Ior::Sellmeier { b, c } => {
+                let l2 = um * um;
+
+                (1.0 + (0..3).map(| ii | b[ii] * l2 / (l2 - c[ii])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+
+// Basis spectra from Brian Smits, "An RGB-to-Spectrum Conversion for
+// Reflectances" (1999), in ten bins spanning the sampled range.
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// A basis spectrum at a wavelength, interpolated between the bin
+// centers.
+fn basis_value(basis: &[f64; 10], wavelength: f64) -> f64 {
+    let bins = basis.len();
+    let position = (wavelength - MIN_WAVELENGTH) / (MAX_WAVELENGTH - MIN_WAVELENGTH) * bins as f64 - 0.5;
+    let position = position.clamp(0.0, (bins - 1) as f64);
+
+    let bin = (position as usize).min(bins - 2);
+    let t = position - bin as f64;
+
+    basis[bin] * (1.0 - t) + basis[bin + 1] * t
+}
+
+// The value at a wavelength of a smooth spectrum with the given linear
+// sRGB color, built from as much white as the color has, then as much
+// cyan, magenta, or yellow, then red, green, or blue as is left.
+pub fn rgb_to_spectrum(color: &LinearColor, wavelength: f64) -> f64 {
+    let LinearColor { r, g, b } = *color;
+    let basis = | spectrum: &[f64; 10] | basis_value(spectrum, wavelength);
+
+    if r <= g && r <= b {
+        r * basis(&SMITS_WHITE) + if g <= b {
+            (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE) + if r <= b {
+            (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else {
+        b * basis(&SMITS_WHITE) + if r <= g {
+            (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+        } else {
+            (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+        }
+    }
+}
+
+// A Gaussian with different widths on either side of its peak.
+fn lobe(x: f64, mean: f64, below: f64, above: f64) -> f64 {
+    let t = (x - mean) / if x < mean { below } else { above };
+
+    (-0.5 * t * t).exp()
+}
+
+// The CIE 1931 color matching functions, from the multi-lobe fit in
+// Wyman, Sloan, and Shirley, "Simple Analytic Approximations to the CIE
+// XYZ Color Matching Functions" (2013).
+fn color_matching(wavelength: f64) -> [f64; 3] {
+    let l = wavelength;
+
+    [
+        1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7) - 0.065 * lobe(l, 501.1, 20.4, 26.2),
+        0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1),
+        1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8),
+    ]
+}
+
+// The color matching functions integrated over the sampled range, which
+// is the XYZ color of a flat spectrum.
+const FLAT_SPECTRUM_XYZ: [f64; 3] = [106.7607, 106.9119, 106.8253];
+
+// Converts one wavelength sample of radiance into a color in the given
+// space, such that averaging samples spread evenly over the spectrum
+// gives the color of the light. A flat spectrum comes out white in any
+// space, the way a color of (1, 1, 1) does when rendering in RGB.
+pub fn spectrum_to_rgb(value: f64, wavelength: f64, space: ColorSpace) -> LinearColor {
+    let matrix = xyz_to_rgb(space);
+
+    let rgb = transform(&matrix, &color_matching(wavelength));
+    let white = transform(&matrix, &FLAT_SPECTRUM_XYZ);
+
+    let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+
+    LinearColor::from_array([0, 1, 2].map(| c | value * range * rgb[c] / white[c]))
+}
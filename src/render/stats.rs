@@ -0,0 +1,183 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::time::Duration;
+
+// Counts of the work done while rendering, and how long each phase
+// took. Counters are kept per thread while rendering, so updating them
+// costs next to nothing, and are gathered up as each tile finishes.
+#[derive(Clone, Default, Debug)]
+pub struct RenderStats {
+    pub primary_rays: u64,
+    pub reflection_rays: u64,
+    pub refraction_rays: u64,
+    pub shadow_rays: u64,
+    pub occlusion_rays: u64,
+
+    // Intersection tests by shape. Meshes count each triangle tested.
+    pub sphere_tests: u64,
+    pub plane_tests: u64,
+    pub rectangle_tests: u64,
+    pub triangle_tests: u64,
+
+    // Deepest level of reflection reached, with camera rays at zero.
+    pub max_depth: u32,
+
+    // Wall clock time spent in each phase, in the order the phases
+    // were first seen.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+thread_local! {
+    static THREAD_STATS: RefCell<RenderStats> = RefCell::new(RenderStats::default());
+}
+
+// Updates the counters for the current thread.
+pub fn record<F: FnOnce(&mut RenderStats)>(update: F) {
+    THREAD_STATS.with(| stats | update(&mut stats.borrow_mut()));
+}
+
+// Looks at the counters for the current thread, leaving them in place.
+pub fn read<T, F: FnOnce(&RenderStats) -> T>(view: F) -> T {
+    THREAD_STATS.with(| stats | view(&stats.borrow()))
+}
+
+// Removes and returns everything counted on the current thread.
+pub fn take() -> RenderStats {
+    THREAD_STATS.with(| stats | stats.replace(RenderStats::default()))
+}
+
+fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+
+    for (ii, digit) in digits.chars().enumerate() {
+        if ii > 0 && (digits.len() - ii).is_multiple_of(3) {
+            formatted.push(',');
+        }
+
+        formatted.push(digit);
+    }
+
+    formatted
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { write!(escaped, "\\u{:04x}", c as u32).ok(); },
+            c => escaped.push(c)
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+impl RenderStats {
+    pub fn add(&mut self, other: &RenderStats) {
+        self.primary_rays += other.primary_rays;
+        self.reflection_rays += other.reflection_rays;
+        self.refraction_rays += other.refraction_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.occlusion_rays += other.occlusion_rays;
+
+        self.sphere_tests += other.sphere_tests;
+        self.plane_tests += other.plane_tests;
+        self.rectangle_tests += other.rectangle_tests;
+        self.triangle_tests += other.triangle_tests;
+
+        self.max_depth = self.max_depth.max(other.max_depth);
+
+        for (name, duration) in other.phases.iter() {
+            self.add_phase(name, *duration);
+        }
+    }
+
+    pub fn add_phase(&mut self, name: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(| (n, _) | *n == name) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((name, duration))
+        }
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays + self.reflection_rays + self.refraction_rays + self.shadow_rays + self.occlusion_rays
+    }
+
+    pub fn total_tests(&self) -> u64 {
+        self.sphere_tests + self.plane_tests + self.rectangle_tests + self.triangle_tests
+    }
+
+    fn counters(&self) -> [(&'static str, u64); 9] {
+        [
+            ("primary_rays", self.primary_rays),
+            ("reflection_rays", self.reflection_rays),
+            ("refraction_rays", self.refraction_rays),
+            ("shadow_rays", self.shadow_rays),
+            ("occlusion_rays", self.occlusion_rays),
+            ("sphere_tests", self.sphere_tests),
+            ("plane_tests", self.plane_tests),
+            ("rectangle_tests", self.rectangle_tests),
+            ("triangle_tests", self.triangle_tests),
+        ]
+    }
+
+    // A report for people to read, one counter or phase per line.
+    pub fn summary(&self, title: &str) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "Statistics for {}:", title).ok();
+
+        for (name, count) in self.counters().iter() {
+            writeln!(out, "  {:<20} {:>16}", name.replace('_', " "), format_count(*count)).ok();
+        }
+
+        writeln!(out, "  {:<20} {:>16}", "total rays", format_count(self.total_rays())).ok();
+        writeln!(out, "  {:<20} {:>16}", "total tests", format_count(self.total_tests())).ok();
+        writeln!(out, "  {:<20} {:>16}", "max depth", self.max_depth).ok();
+
+        for (name, duration) in self.phases.iter() {
+            writeln!(out, "  {:<20} {:>15.3}s", format!("{} time", name), duration.as_secs_f64()).ok();
+        }
+
+        out
+    }
+
+    // The same report as a JSON object, with times in seconds.
+    pub fn to_json(&self, title: &str) -> String {
+        let mut out = String::new();
+
+        write!(out, "{{\"scene\": {}", json_string(title)).ok();
+
+        for (name, count) in self.counters().iter() {
+            write!(out, ", \"{}\": {}", name, count).ok();
+        }
+
+        write!(out, ", \"max_depth\": {}, \"phases\": {{", self.max_depth).ok();
+
+        for (ii, (name, duration)) in self.phases.iter().enumerate() {
+            if ii > 0 {
+                out.push_str(", ");
+            }
+
+            write!(out, "{}: {}", json_string(name), duration.as_secs_f64()).ok();
+        }
+
+        out.push_str("}}");
+        out
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::cmp::Ordering;
+use std::time::Duration;
+
+// The order tiles are handed out to be rendered in. This only affects
+// how the image fills in and how well neighboring work shares caches,
+// not the final result.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TileOrder {
+    // Left to right, top to bottom.
+    Scanline,
+
+    // Outwards from the center of the image, where the subject
+    // usually is.
+    Spiral,
+
+    // Along a Hilbert curve, which keeps consecutive tiles close
+    // together.
+    Hilbert,
+}
+
+// A rectangle of the image, from (x0, y0) up to but not including
+// (x1, y1).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> u64 {
+        (self.x1 - self.x0) as u64 * (self.y1 - self.y0) as u64
+    }
+}
+
+// Position along a Hilbert curve filling an n by n grid, where n is a
+// power of two.
+// https://en.wikipedia.org/wiki/Hilbert_curve
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d: u64 = 0;
+    let mut s = n / 2;
+
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s /= 2;
+    }
+
+    d
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name.to_lowercase().as_str() {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None
+        }
+    }
+
+    // Splits an image into tiles of the given size (smaller along the
+    // right and bottom edges if need be), in rendering order.
+    pub fn tiles(&self, imgx: u32, imgy: u32, size: u32) -> Vec<Tile> {
+        let size = size.max(1);
+
+        let columns = imgx.div_ceil(size);
+        let rows = imgy.div_ceil(size);
+
+        let mut grid: Vec<(u32, u32)> = (0..rows)
+            .flat_map(| ty | (0..columns).map(move | tx | (tx, ty)))
+            .collect();
+
+        match self {
+            TileOrder::Scanline => {},
+
+            TileOrder::Spiral => {
+                let cx = (columns as f64 - 1.0) / 2.0;
+                let cy = (rows as f64 - 1.0) / 2.0;
+
+                // Rings of tiles around the center, each walked
+                // around by angle.
+                let key = | (tx, ty): &(u32, u32) | {
+                    let dx = *tx as f64 - cx;
+                    let dy = *ty as f64 - cy;
+
+                    (dx.abs().max(dy.abs()), dy.atan2(dx))
+                };
+
+                grid.sort_by(| a, b | key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+            },
+
+            TileOrder::Hilbert => {
+                let n = columns.max(rows).next_power_of_two();
+
+                grid.sort_by_key(| (tx, ty) | hilbert_index(n, *tx, *ty));
+            }
+        }
+
+        grid.into_iter().map(| (tx, ty) | Tile {
+            x0: tx * size,
+            y0: ty * size,
+            x1: ((tx + 1) * size).min(imgx),
+            y1: ((ty + 1) * size).min(imgy),
+        }).collect()
+    }
+}
+
+// Receives progress reports during rendering.
+pub type ProgressCallback = Box<dyn Fn(&Progress) + Sync + Send>;
+
+// How far along a render is, reported after each tile completes.
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    pub pixels_done: u64,
+    pub pixels_total: u64,
+
+    // Camera rays traced so far.
+    pub rays: u64,
+
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.pixels_total == 0 {
+            1.0
+        } else {
+            self.pixels_done as f64 / self.pixels_total as f64
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.tiles_done >= self.tiles_total
+    }
+
+    // Estimated time remaining, assuming the rest of the image renders
+    // at the same rate per pixel as what's been done so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction();
+
+        if fraction <= 0.0 {
+            None
+        } else {
+            Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+        }
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+
+        if seconds > 0.0 {
+            self.rays as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for order in ALL_ORDERS.iter() {
+            for &(imgx, imgy, size) in [(64, 64, 16), (100, 37, 16), (5, 90, 8), (1, 1, 32)].iter() {
+                let tiles = order.tiles(imgx, imgy, size);
+                let mut covered = vec![0; (imgx * imgy) as usize];
+
+                for tile in tiles.iter() {
+                    assert!(tile.x1 <= imgx && tile.y1 <= imgy, "{:?} {:?}", order, tile);
+
+                    for y in tile.y0..tile.y1 {
+                        for x in tile.x0..tile.x1 {
+                            covered[(y * imgx + x) as usize] += 1;
+                        }
+                    }
+                }
+
+                assert!(covered.iter().all(| c | *c == 1), "{:?} at {}x{}/{}", order, imgx, imgy, size);
+                assert_eq!(tiles.iter().map(Tile::pixel_count).sum::<u64>(), (imgx * imgy) as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_index_follows_the_curve() {
+        assert_eq!(hilbert_index(2, 0, 0), 0);
+        assert_eq!(hilbert_index(2, 0, 1), 1);
+        assert_eq!(hilbert_index(2, 1, 1), 2);
+        assert_eq!(hilbert_index(2, 1, 0), 3);
+
+        // Every cell of a larger grid gets its own index, and each step
+        // along the curve moves to a neighboring cell.
+        let n = 8;
+        let mut cells = vec![(0, 0); (n * n) as usize];
+
+        for y in 0..n {
+            for x in 0..n {
+                cells[hilbert_index(n, x, y) as usize] = (x, y);
+            }
+        }
+
+        for pair in cells.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let step = (a.0 as i64 - b.0 as i64).abs() + (a.1 as i64 - b.1 as i64).abs();
+
+            assert_eq!(step, 1, "{:?} to {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn spiral_starts_in_the_middle() {
+        let tiles = TileOrder::Spiral.tiles(48, 48, 16);
+
+        assert_eq!((tiles[0].x0, tiles[0].y0), (16, 16));
+    }
+
+    #[test]
+    fn names_parse() {
+        assert_eq!(TileOrder::from_name("Hilbert"), Some(TileOrder::Hilbert));
+        assert_eq!(TileOrder::from_name("random"), None);
+    }
+}
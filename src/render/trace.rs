@@ -0,0 +1,339 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+// Records the tree of rays traced for a pixel, for working out why a
+// pixel looks the way it does. The renderer reports each ray it casts
+// as it goes, so a trace follows exactly the same code path as a
+// normal render. Nothing is recorded unless a trace has been started on
+// the current thread.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+
+use crate::render::{Hittable, RayHit, Surface};
+use crate::render::geometry::{Normal, Point, Ray, Vector};
+use crate::render::color::LinearColor;
+use crate::render::stats::json_string;
+use crate::render::spectral::REFERENCE_WAVELENGTH;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RayKind {
+    Camera,
+    Reflection,
+    Shadow,
+    Occlusion,
+}
+
+impl RayKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RayKind::Camera => "camera",
+            RayKind::Reflection => "reflection",
+            RayKind::Shadow => "shadow",
+            RayKind::Occlusion => "occlusion"
+        }
+    }
+}
+
+// What a ray found, in the terms of the code that cast it.
+#[derive(Clone, Debug)]
+pub enum RayResult {
+    Color(LinearColor),
+    Transmittance(f64),
+    Visible(bool),
+}
+
+pub struct TraceHit {
+    // Index of the object in the scene's list of objects.
+    pub object: usize,
+
+    pub distance: f64,
+    pub point: Point,
+    pub normal: Normal,
+    pub uv: (f64, f64),
+    pub surface: Surface,
+}
+
+pub struct RayTrace {
+    pub kind: RayKind,
+    pub depth: u32,
+    pub origin: Point,
+    pub direction: Vector,
+
+    // How far along the ray anything counts, for rays that stop short
+    // at a light.
+    pub max_distance: Option<f64>,
+
+    pub hit: Option<TraceHit>,
+
+    // The parts of a shaded color, by where the light came from.
+    pub contributions: Vec<(&'static str, LinearColor)>,
+
+    pub result: Option<RayResult>,
+    pub children: Vec<RayTrace>,
+}
+
+// Rays that are still being traced, innermost last, and the camera
+// rays that are finished.
+struct Tracer {
+    open: Vec<RayTrace>,
+    finished: Vec<RayTrace>,
+}
+
+thread_local! {
+    static TRACER: RefCell<Option<Tracer>> = const { RefCell::new(None) };
+}
+
+// Starts recording rays on the current thread.
+pub fn start() {
+    TRACER.with(| tracer | {
+        *tracer.borrow_mut() = Some(Tracer {
+            open: vec![],
+            finished: vec![],
+        })
+    });
+}
+
+// Stops recording, returning the tree of each camera ray traced since
+// the trace was started.
+pub fn finish() -> Vec<RayTrace> {
+    TRACER.with(| tracer | {
+        tracer.borrow_mut().take().map_or(vec![], | tracer | tracer.finished)
+    })
+}
+
+fn with_tracer<F: FnOnce(&mut Tracer)>(update: F) {
+    TRACER.with(| tracer | {
+        if let Some(tracer) = tracer.borrow_mut().as_mut() {
+            update(tracer);
+        }
+    });
+}
+
+// Every begin_ray needs a matching end_ray, with any rays cast in
+// between recorded as its children.
+pub fn begin_ray(kind: RayKind, depth: u32, ray: &Ray, max_distance: Option<f64>) {
+    with_tracer(| tracer | {
+        tracer.open.push(RayTrace {
+            kind,
+            depth,
+            origin: ray.start,
+            direction: ray.delta,
+            max_distance,
+            hit: None,
+            contributions: vec![],
+            result: None,
+            children: vec![],
+        })
+    });
+}
+
+pub fn record_hit(object: usize, shape: &dyn Hittable, hit: &RayHit) {
+    with_tracer(| tracer | {
+        if let Some(ray) = tracer.open.last_mut() {
+            ray.hit = Some(TraceHit {
+                object,
+                distance: hit.distance,
+                point: hit.hit_point,
+                normal: hit.normal,
+                uv: shape.uv(hit),
+                surface: hit.surface,
+            });
+        }
+    });
+}
+
+pub fn record_contribution(name: &'static str, color: &LinearColor) {
+    with_tracer(| tracer | {
+        if let Some(ray) = tracer.open.last_mut() {
+            ray.contributions.push((name, *color));
+        }
+    });
+}
+
+pub fn end_ray(result: RayResult) {
+    with_tracer(| tracer | {
+        if let Some(mut ray) = tracer.open.pop() {
+            ray.result = Some(result);
+
+            match tracer.open.last_mut() {
+                Some(parent) => parent.children.push(ray),
+                None => tracer.finished.push(ray)
+            }
+        }
+    });
+}
+
+fn format_point(p: &[f64; 3]) -> String {
+    format!("({:.4}, {:.4}, {:.4})", p[0], p[1], p[2])
+}
+
+fn json_point(p: &[f64; 3]) -> String {
+    format!("[{}, {}, {}]", p[0], p[1], p[2])
+}
+
+fn format_color(c: &LinearColor) -> String {
+    format_point(&c.to_array())
+}
+
+fn json_color(c: &LinearColor) -> String {
+    json_point(&c.to_array())
+}
+
+impl RayResult {
+    fn to_text(&self) -> String {
+        match self {
+            RayResult::Color(color) => format!("color {}", format_color(color)),
+            RayResult::Transmittance(t) => format!("transmittance {:.4}", t),
+            RayResult::Visible(visible) => String::from(if *visible { "visible" } else { "blocked" })
+        }
+    }
+
+    fn to_json(&self) -> String {
+        match self {
+            RayResult::Color(color) => format!("\"color\": {}", json_color(color)),
+            RayResult::Transmittance(t) => format!("\"transmittance\": {}", t),
+            RayResult::Visible(visible) => format!("\"visible\": {}", visible)
+        }
+    }
+}
+
+impl RayTrace {
+    // An indented outline of the ray and everything it spawned.
+    pub fn to_text(&self, indent: usize) -> String {
+        let mut out = String::new();
+        let pad = "  ".repeat(indent);
+
+        write!(out, "{}{} ray, depth {}, from {} along {}",
+               pad, self.kind.name(), self.depth, format_point(&self.origin.to_array()), format_point(&self.direction.to_array())).ok();
+
+        if let Some(distance) = self.max_distance {
+            write!(out, " up to {:.4}", distance).ok();
+        }
+
+        out.push('\n');
+
+        match &self.hit {
+            Some(hit) => {
+                writeln!(out, "{}  hit object {} at distance {:.4}, point {}, normal {}, uv ({:.4}, {:.4})",
+                         pad, hit.object, hit.distance, format_point(&hit.point.to_array()),
+                         format_point(&hit.normal.to_array()), hit.uv.0, hit.uv.1).ok();
+
+                let s = &hit.surface;
+
+                writeln!(out, "{}  surface color {}, ambient {}, light {}, specular {}, reflection {} x {}, \
+                               emission {} x {}, transmission {}, ior {:.4}",
+                         pad, format_color(&s.color), s.ambient, s.light, s.specular,
+                         format_color(&s.reflection_color), s.reflection,
+                         format_color(&s.emission), s.emission_strength,
+                         s.transmission, s.ior.at(REFERENCE_WAVELENGTH)).ok();
+            },
+            None => { writeln!(out, "{}  missed", pad).ok(); }
+        }
+
+        for (name, color) in self.contributions.iter() {
+            writeln!(out, "{}  {} {}", pad, name, format_color(color)).ok();
+        }
+
+        for child in self.children.iter() {
+            out.push_str(&child.to_text(indent + 1));
+        }
+
+        if let Some(result) = &self.result {
+            writeln!(out, "{}  => {}", pad, result.to_text()).ok();
+        }
+
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+
+        write!(out, "{{\"kind\": {}, \"depth\": {}, \"origin\": {}, \"direction\": {}",
+               json_string(self.kind.name()), self.depth, json_point(&self.origin.to_array()), json_point(&self.direction.to_array())).ok();
+
+        if let Some(distance) = self.max_distance {
+            if distance.is_finite() {
+                write!(out, ", \"max_distance\": {}", distance).ok();
+            }
+        }
+
+        if let Some(hit) = &self.hit {
+            let s = &hit.surface;
+
+            write!(out, ", \"hit\": {{\"object\": {}, \"distance\": {}, \"point\": {}, \"normal\": {}, \"uv\": [{}, {}], ",
+                   hit.object, hit.distance, json_point(&hit.point.to_array()), json_point(&hit.normal.to_array()), hit.uv.0, hit.uv.1).ok();
+
+            write!(out, "\"surface\": {{\"color\": {}, \"ambient\": {}, \"light\": {}, \"specular\": {}, \
+                         \"checked\": {}, \"reflection\": {}, \"reflection_color\": {}, \"emission\": {}, \
+                         \"emission_strength\": {}, \"transmission\": {}, \"ior\": {}}}}}",
+                   json_color(&s.color), s.ambient, s.light, s.specular, s.checked, s.reflection,
+                   json_color(&s.reflection_color),
+                   json_color(&s.emission), s.emission_strength, s.transmission, s.ior.at(REFERENCE_WAVELENGTH)).ok();
+        }
+
+        if !self.contributions.is_empty() {
+            let contributions: Vec<String> = self.contributions.iter()
+                .map(| (name, color) | format!("{}: {}", json_string(name), json_color(color)))
+                .collect();
+
+            write!(out, ", \"contributions\": {{{}}}", contributions.join(", ")).ok();
+        }
+
+        if let Some(result) = &self.result {
+            write!(out, ", {}", result.to_json()).ok();
+        }
+
+        if !self.children.is_empty() {
+            let children: Vec<String> = self.children.iter().map(RayTrace::to_json).collect();
+
+            write!(out, ", \"children\": [{}]", children.join(", ")).ok();
+        }
+
+        out.push('}');
+        out
+    }
+}
+
+// Everything traced for one pixel: each sample's position within the
+// pixel and the tree of rays behind it, and the pixel's final color
+// after filtering. The JSON gives that color in hex too, encoded as
+// sRGB, for pasting into other tools.
+pub struct PixelTrace {
+    pub x: u32,
+    pub y: u32,
+    pub samples: Vec<((f64, f64), RayTrace)>,
+    pub color: LinearColor,
+}
+
+impl PixelTrace {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "Pixel ({}, {}): {} samples, color {}",
+                 self.x, self.y, self.samples.len(), format_color(&self.color)).ok();
+
+        for (ii, ((sx, sy), ray)) in self.samples.iter().enumerate() {
+            writeln!(out, "Sample {} at ({:.4}, {:.4}):", ii, sx, sy).ok();
+            out.push_str(&ray.to_text(1));
+        }
+
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let samples: Vec<String> = self.samples.iter()
+            .map(| ((sx, sy), ray) | format!("{{\"position\": [{}, {}], \"ray\": {}}}", sx, sy, ray.to_json()))
+            .collect();
+
+        format!("{{\"x\": {}, \"y\": {}, \"color\": {}, \"hex\": {}, \"samples\": [{}]}}",
+                self.x, self.y, json_color(&self.color), json_string(&self.color.to_hex()), samples.join(", "))
+    }
+}
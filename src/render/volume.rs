@@ -0,0 +1,291 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+use crate::render::{
+    Hittable,
+    Scene,
+    light_vector,
+};
+
+use crate::render::geometry::{
+    Point,
+    Ray,
+    EPSILON,
+};
+
+use crate::render::color::{
+    LinearColor,
+};
+
+use crate::render::random::Rng;
+
+use crate::render::voxel::VoxelGrid;
+
+// Safety net for boundaries that keep reporting hits, so tracing
+// through a volume always terminates.
+const MAX_CROSSINGS: usize = 64;
+
+// Exponential fog filling the whole scene. Everything is blended
+// towards the fog color with distance from the viewer.
+#[derive(Copy, Clone)]
+pub struct Fog {
+    pub density: f64,
+    pub color: LinearColor,
+}
+
+// How the density of a volume varies from point to point. The
+// absorption and scattering coefficients are scaled by the density.
+pub enum Density {
+    Uniform,
+    Grid(VoxelGrid),
+}
+
+// A medium filling the inside of a closed shape. The boundary only
+// marks out the volume; it isn't drawn as a surface.
+pub struct Volume {
+    pub boundary: Box<dyn Hittable + Sync + Send>,
+    pub density: Density,
+
+    // Coefficients per unit distance at unit density
+    pub absorption: f64,
+    pub scattering: f64,
+
+    // Henyey-Greenstein asymmetry: negative values scatter light
+    // back towards where it came from, positive values forward, and
+    // zero scatters equally in all directions.
+    pub anisotropy: f64,
+
+    // Color of the light scattered towards the viewer
+    pub color: LinearColor,
+}
+
+// https://www.pbr-book.org/3ed-2018/Volume_Scattering/Phase_Functions
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+impl Volume {
+    fn extinction(&self) -> f64 {
+        self.absorption + self.scattering
+    }
+
+    fn density_at(&self, point: &Point) -> f64 {
+        match &self.density {
+            Density::Uniform => 1.0,
+            Density::Grid(grid) => grid.density(point)
+        }
+    }
+
+    // An upper bound on extinction anywhere in the volume, used to
+    // take steps through heterogeneous media.
+    fn majorant(&self) -> f64 {
+        match &self.density {
+            Density::Uniform => self.extinction(),
+            Density::Grid(grid) => self.extinction() * grid.max_density()
+        }
+    }
+
+    // Samples the distance to the next tentative collision, using the
+    // majorant as a stand-in for the real extinction.
+    fn step(majorant: f64, rng: &mut Rng) -> f64 {
+        -(1.0 - rng.next_f64()).ln() / majorant
+    }
+
+    // The stretches of the ray, out to max_distance, that are inside
+    // the volume. Crossings of the boundary are counted so that rays
+    // starting inside the shape are handled too.
+    fn segments(&self, ray: &Ray, max_distance: f64) -> Vec<(f64, f64)> {
+        let mut crossings = vec![];
+        let mut start = ray.start;
+        let mut traveled = 0.0;
+
+        while crossings.len() < MAX_CROSSINGS {
+            match self.boundary.hit_test(&Ray { start, delta: ray.delta }) {
+                Some(hit) => {
+                    traveled += hit.distance;
+                    crossings.push(traveled);
+                    start = hit.hit_point;
+                },
+                None => break
+            }
+        }
+
+        if crossings.len() % 2 == 1 {
+            crossings.insert(0, 0.0);
+        }
+
+        crossings
+            .chunks(2)
+            .map(| span | (span[0], span[1].min(max_distance)))
+            .filter(| (t0, t1) | t1 > t0)
+            .collect()
+    }
+
+    fn segment_transmittance(&self, ray: &Ray, t0: f64, t1: f64, rng: &mut Rng) -> f64 {
+        match self.density {
+            Density::Uniform => (-self.extinction() * (t1 - t0)).exp(),
+
+            // Ratio tracking: https://jannovak.info/publications/RRT/index.html
+            Density::Grid(_) => {
+                let majorant = self.majorant();
+
+                if majorant < EPSILON {
+                    return 1.0;
+                }
+
+                let mut t = t0;
+                let mut transmittance = 1.0;
+
+                loop {
+                    t += Volume::step(majorant, rng);
+
+                    if t >= t1 {
+                        break transmittance;
+                    }
+
+                    let extinction = self.extinction() * self.density_at(&ray.at(t));
+
+                    transmittance *= 1.0 - extinction / majorant;
+                }
+            }
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, max_distance: f64, rng: &mut Rng) -> f64 {
+        self.segments(ray, max_distance)
+            .iter()
+            .map(| (t0, t1) | self.segment_transmittance(ray, *t0, *t1, rng))
+            .product()
+    }
+
+    fn scattered_from(&self, ray: &Ray, point: &Point, scene: &Scene, rng: &mut Rng) -> f64 {
+        scene.lights
+            .iter()
+            .filter_map(| light | light_vector(point, light, scene, rng))
+            .map(| (delta, intensity) | {
+                intensity * henyey_greenstein(delta.dot(-ray.delta), self.anisotropy)
+            })
+            .sum()
+    }
+
+    // Light from the scene's lights scattered once within the segment
+    // towards the start of the ray, attenuated back to the start of
+    // the segment. Distances are sampled in proportion to
+    // transmittance, which keeps the estimate well behaved in dense
+    // media.
+    fn in_scattered(&self, ray: &Ray, t0: f64, t1: f64, scene: &Scene, rng: &mut Rng) -> LinearColor {
+        let extinction = self.extinction();
+
+        if self.scattering < EPSILON || self.majorant() < EPSILON {
+            return LinearColor::BLACK;
+        }
+
+        let albedo = self.scattering / extinction;
+
+        let total: f64 = match self.density {
+            Density::Uniform => {
+                let absorbed = 1.0 - (-extinction * (t1 - t0)).exp();
+
+                (0..scene.light_samples).map(| _ | {
+                    let t = t0 - (1.0 - rng.next_f64() * absorbed).ln() / extinction;
+
+                    self.scattered_from(ray, &ray.at(t), scene, rng) * albedo * absorbed
+                }).sum()
+            },
+
+            // Delta tracking, to find where light scatters: step
+            // through tentative collisions, accepting each as real in
+            // proportion to the local density.
+            Density::Grid(_) => {
+                let majorant = self.majorant();
+
+                (0..scene.light_samples).map(| _ | {
+                    let mut t = t0;
+
+                    loop {
+                        t += Volume::step(majorant, rng);
+
+                        if t >= t1 {
+                            break 0.0;
+                        }
+
+                        let point = ray.at(t);
+
+                        if rng.next_f64() * majorant < extinction * self.density_at(&point) {
+                            break self.scattered_from(ray, &point, scene, rng) * albedo;
+                        }
+                    }
+                }).sum()
+            }
+        };
+
+        scene.working_color(&self.color) * (total / scene.light_samples.max(1) as f64)
+    }
+}
+
+// Fraction of light that makes it along the ray through every volume
+// in the scene, out to the given distance.
+pub fn transmittance(ray: &Ray, distance: f64, scene: &Scene, rng: &mut Rng) -> f64 {
+    scene.volumes
+        .iter()
+        .map(| volume | volume.transmittance(ray, distance, rng))
+        .product()
+}
+
+// Applies the effect of the scene's media to light of the given color
+// arriving at the start of the ray from the given distance along it.
+pub fn attenuate(ray: &Ray, distance: f64, color: LinearColor, scene: &Scene, rng: &mut Rng) -> LinearColor {
+    let mut segments: Vec<(&Volume, f64, f64)> = scene.volumes
+        .iter()
+        .flat_map(| volume | {
+            volume.segments(ray, distance)
+                .into_iter()
+                .map(move | (t0, t1) | (volume, t0, t1))
+        })
+        .collect();
+
+    // Composite from the far end of the ray back towards its start,
+    // with fog filling the gaps between (and the inside of) volumes.
+    segments.sort_by(| a, b | b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    let mut color = color;
+    let mut far = distance;
+
+    for (volume, t0, t1) in segments {
+        let t1 = t1.min(far);
+
+        color = through_fog(&color, far - t1, scene);
+        color = through_fog(&color, t1 - t0, scene);
+
+        let segment_transmittance = volume.segment_transmittance(ray, t0, t1, rng);
+
+        color = color * segment_transmittance + volume.in_scattered(ray, t0, t1, scene, rng);
+
+        far = t0;
+    }
+
+    through_fog(&color, far, scene)
+}
+
+fn through_fog(color: &LinearColor, length: f64, scene: &Scene) -> LinearColor {
+    match scene.fog {
+        Some(fog) if length > 0.0 => {
+            let fog_transmittance = (-fog.density * length).exp();
+
+            *color * fog_transmittance + scene.working_color(&fog.color) * (1.0 - fog_transmittance)
+        },
+        _ => *color
+    }
+}
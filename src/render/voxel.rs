@@ -0,0 +1,189 @@
+// Copyright (c) Mike Schaeffer. All rights reserved.
+//
+// The use and distribution terms for this software are covered by the
+// Eclipse Public License 2.0 (https://opensource.org/licenses/EPL-2.0)
+// which can be found in the file LICENSE at the root of this distribution.
+// By using this software in any fashion, you are agreeing to be bound by
+// the terms of this license.
+//
+// You must not remove this notice, or any other, from this software.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::render::geometry::Point;
+
+use crate::render::random::Rng;
+
+// Densities sampled on a regular grid spanning an axis aligned box,
+// and trilinearly interpolated in between. Density is zero outside
+// the box.
+pub struct VoxelGrid {
+    pub dims: [usize; 3],
+    pub min: Point,
+    pub max: Point,
+    data: Vec<f64>,
+    max_density: f64,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl VoxelGrid {
+    // Takes densities ordered with x varying fastest, then y, then z.
+    pub fn new(dims: [usize; 3], min: Point, max: Point, data: Vec<f64>) -> VoxelGrid {
+        assert_eq!(data.len(), dims[0] * dims[1] * dims[2], "Voxel data does not match grid dimensions.");
+
+        let max_density = data.iter().fold(0.0_f64, | m, d | m.max(*d));
+
+        VoxelGrid {
+            dims,
+            min,
+            max,
+            data,
+            max_density,
+        }
+    }
+
+    // Samples a density function at the center of each voxel.
+    pub fn from_fn<F: Fn(Point) -> f64>(dims: [usize; 3], min: Point, max: Point, density: F) -> VoxelGrid {
+        let size = max - min;
+        let mut data = Vec::with_capacity(dims[0] * dims[1] * dims[2]);
+
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    data.push(density(Point::new(
+                        min.x + size.x * (x as f64 + 0.5) / dims[0] as f64,
+                        min.y + size.y * (y as f64 + 0.5) / dims[1] as f64,
+                        min.z + size.z * (z as f64 + 0.5) / dims[2] as f64,
+                    )).max(0.0));
+                }
+            }
+        }
+
+        VoxelGrid::new(dims, min, max, data)
+    }
+
+    // Loads a raw voxel file: the x, y, and z dimensions as
+    // little-endian u32 values, followed by one little-endian f32
+    // density per voxel, with x varying fastest, then y, then z. The
+    // grid is stretched to fill the box from min to max.
+    pub fn load_raw<P: AsRef<Path>>(path: P, min: Point, max: Point) -> io::Result<VoxelGrid> {
+        let bytes = fs::read(path)?;
+
+        if bytes.len() < 12 {
+            return Err(invalid_data("Voxel file too short for header."));
+        }
+
+        let dims = [read_u32(&bytes, 0) as usize, read_u32(&bytes, 4) as usize, read_u32(&bytes, 8) as usize];
+
+        if dims.contains(&0) {
+            return Err(invalid_data("Voxel file has a zero dimension."));
+        }
+
+        let count = dims[0].checked_mul(dims[1])
+            .and_then(| c | c.checked_mul(dims[2]))
+            .ok_or_else(|| invalid_data("Voxel file dimensions are too large."))?;
+
+        if count.checked_mul(4).and_then(| size | size.checked_add(12)) != Some(bytes.len()) {
+            return Err(invalid_data("Voxel file size does not match its dimensions."));
+        }
+
+        let data = (0..count)
+            .map(| ii | f32::from_bits(read_u32(&bytes, 12 + ii * 4)) as f64)
+            .map(| d | if d.is_finite() { d.max(0.0) } else { 0.0 })
+            .collect();
+
+        Ok(VoxelGrid::new(dims, min, max, data))
+    }
+
+    pub fn max_density(&self) -> f64 {
+        self.max_density
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.data[(z * self.dims[1] + y) * self.dims[0] + x]
+    }
+
+    pub fn density(&self, point: &Point) -> f64 {
+        let mut idx = [0; 3];
+        let mut frac = [0.0; 3];
+
+        for axis in 0..3 {
+            let t = (point[axis] - self.min[axis]) / (self.max[axis] - self.min[axis]);
+
+            if !(0.0..=1.0).contains(&t) {
+                return 0.0;
+            }
+
+            // Voxel values sit at cell centers, so clamp at the edges.
+            let pos = (t * self.dims[axis] as f64 - 0.5).clamp(0.0, (self.dims[axis] - 1) as f64);
+            idx[axis] = (pos as usize).min(self.dims[axis].saturating_sub(2));
+            frac[axis] = pos - idx[axis] as f64;
+        }
+
+        let next = | axis: usize | (idx[axis] + 1).min(self.dims[axis] - 1);
+
+        let lerp = | a: f64, b: f64, t: f64 | a + (b - a) * t;
+
+        let [x0, y0, z0] = idx;
+        let (x1, y1, z1) = (next(0), next(1), next(2));
+        let [fx, fy, fz] = frac;
+
+        lerp(lerp(lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), fx),
+                  lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), fx), fy),
+             lerp(lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), fx),
+                  lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), fx), fy), fz)
+    }
+}
+
+fn lattice_value(x: i64, y: i64, z: i64, seed: u64) -> f64 {
+    let hash = (x as u64).wrapping_mul(0x8da6b343)
+        ^ (y as u64).wrapping_mul(0xd8163841)
+        ^ (z as u64).wrapping_mul(0xcb1ab31f)
+        ^ seed;
+
+    Rng::new(hash).next_f64()
+}
+
+// Smoothly interpolated random values on the integer lattice, in [0, 1).
+pub fn value_noise(point: &Point, seed: u64) -> f64 {
+    let cell = [point.x.floor(), point.y.floor(), point.z.floor()];
+
+    let fade = | t: f64 | t * t * (3.0 - 2.0 * t);
+    let lerp = | a: f64, b: f64, t: f64 | a + (b - a) * t;
+
+    let [fx, fy, fz] = [fade(point.x - cell[0]), fade(point.y - cell[1]), fade(point.z - cell[2])];
+    let [x, y, z] = [cell[0] as i64, cell[1] as i64, cell[2] as i64];
+
+    let v = | dx: i64, dy: i64, dz: i64 | lattice_value(x + dx, y + dy, z + dz, seed);
+
+    lerp(lerp(lerp(v(0, 0, 0), v(1, 0, 0), fx), lerp(v(0, 1, 0), v(1, 1, 0), fx), fy),
+         lerp(lerp(v(0, 0, 1), v(1, 0, 1), fx), lerp(v(0, 1, 1), v(1, 1, 1), fx), fy), fz)
+}
+
+// Fractal sum of several octaves of value noise, in [0, 1).
+pub fn fractal_noise(point: &Point, octaves: u32, seed: u64) -> f64 {
+    let mut total = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    let mut norm = 0.0;
+
+    for octave in 0..octaves {
+        let p = Point::new(point.x * frequency, point.y * frequency, point.z * frequency);
+
+        total += amplitude * value_noise(&p, seed.wrapping_add(octave as u64));
+        norm += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    if norm > 0.0 { total / norm } else { 0.0 }
+}
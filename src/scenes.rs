@@ -8,12 +8,57 @@
 //
 // You must not remove this notice, or any other, from this software.
 
+use std::env;
+
 use crate::render::{
     Camera,
     Scene,
     Light,
+    DEFAULT_AMBIENT_OCCLUSION,
+    AdaptiveSampling,
     Hittable,
     Surface,
+    NO_ATTENUATION,
+    INVERSE_SQUARE,
+};
+
+use crate::render::geometry::{
+    Matrix4,
+    Normal,
+    Point,
+    Vector,
+};
+
+use crate::render::environment::{
+    Environment,
+    EnvironmentMap,
+};
+
+use crate::render::sky::PhysicalSky;
+
+use crate::render::sampler::Sampler;
+
+use crate::render::filter::Filter;
+
+use crate::render::postprocess::PostEffect;
+
+use crate::render::colorspace::ColorSpace;
+
+use crate::render::spectral::{
+    Ior,
+    BK7,
+    SF11,
+};
+
+use crate::render::volume::{
+    Fog,
+    Volume,
+    Density,
+};
+
+use crate::render::voxel::{
+    VoxelGrid,
+    fractal_noise,
 };
 
 use crate::render::color::{
@@ -23,18 +68,37 @@ use crate::render::color::{
 use crate::render::shapes::{
     Sphere,
     Plane,
+    Rectangle,
+    Transformed,
+    TriangleMesh,
 };
 
 const REFLECT_LIMIT: u32 = 2;
 const OVERSAMPLE: u32 = 2;
+const SAMPLER: Sampler = Sampler::Regular;
+const COLOR_SPACE: ColorSpace = ColorSpace::Srgb;
+
+const FILTER: Filter = Filter::Box { radius: 0.5 };
+
+const ADAPTIVE: Option<AdaptiveSampling> = None;
+const LIGHT_SAMPLES: u32 = 16;
 
 const DEFAULT_CAMERA: Camera = Camera {
-    location: [0.0, 10.0, 0.0],
-    point_at: [0.0, 0.0, 0.0],
-    u: [10.0, 0.0, 0.0],
-    v: [0.0, 0.0, -10.0]
+    location: Point::new(0.0, 10.0, 0.0),
+    point_at: Point::new(0.0, 0.0, 0.0),
+    u: Vector::new(10.0, 0.0, 0.0),
+    v: Vector::new(0.0, 0.0, -10.0)
 };
 
+const fn point_light(location: Point) -> Light {
+    Light::Point {
+        location,
+        color: LinearColor::WHITE,
+        intensity: 1.0,
+        attenuation: NO_ATTENUATION
+    }
+}
+
 #[allow(dead_code)]
 const AMBIENT: f64 = 0.2_f64;
 
@@ -55,7 +119,12 @@ const fn surface_glossy(c: LinearColor) -> Surface {
         specular: 0.5,
         light: LIGHT,
         checked: false,
-        reflection: 0.0
+        reflection: 0.0,
+        reflection_color: LinearColor::WHITE,
+        emission: LinearColor::BLACK,
+        emission_strength: 0.0,
+        transmission: 0.0,
+        ior: Ior::Constant(1.0)
     }
 }
 
@@ -68,37 +137,66 @@ const fn reflective(s: Surface) -> Surface {
 }
 
 #[allow(dead_code)]
-const SURFACE_RED: Surface = surface_glossy([1.0, 0.0, 0.0]);
+const fn emissive(s: Surface, c: LinearColor, strength: f64) -> Surface {
+    Surface {
+        emission: c,
+        emission_strength: strength,
+        .. s
+    }
+}
+
+// Clear glass, letting through what it doesn't reflect.
+#[allow(dead_code)]
+const fn glass(ior: Ior) -> Surface {
+    Surface {
+        color: LinearColor::WHITE,
+        ambient: 0.0,
+        specular: 0.8,
+        light: 0.0,
+        reflection: 0.05,
+        transmission: 0.95,
+        ior,
+        .. SURFACE_BLACK
+    }
+}
 
 #[allow(dead_code)]
-const SURFACE_GREEN: Surface = surface_glossy([0.0, 1.0, 0.0]);
+const SURFACE_RED: Surface = surface_glossy(LinearColor::new(1.0, 0.0, 0.0));
 
 #[allow(dead_code)]
-const SURFACE_BLUE: Surface = surface_glossy([0.0, 0.0, 1.0]);
+const SURFACE_GREEN: Surface = surface_glossy(LinearColor::new(0.0, 1.0, 0.0));
 
 #[allow(dead_code)]
-const SURFACE_PURPLE: Surface = surface_glossy([1.0, 0.0, 1.0]);
+const SURFACE_BLUE: Surface = surface_glossy(LinearColor::new(0.0, 0.0, 1.0));
 
 #[allow(dead_code)]
-const SURFACE_ORANGE: Surface = surface_glossy([1.0, 0.5, 0.0]);
+const SURFACE_PURPLE: Surface = surface_glossy(LinearColor::new(1.0, 0.0, 1.0));
 
 #[allow(dead_code)]
-const SURFACE_YELLOW: Surface = surface_glossy([1.0, 1.0, 0.0]);
+const SURFACE_ORANGE: Surface = surface_glossy(LinearColor::new(1.0, 0.5, 0.0));
 
 #[allow(dead_code)]
-const SURFACE_WHITE: Surface = surface_glossy([1.0, 1.0, 1.0]);
+const SURFACE_YELLOW: Surface = surface_glossy(LinearColor::new(1.0, 1.0, 0.0));
 
 #[allow(dead_code)]
-const SURFACE_BLACK: Surface = surface_glossy([0.0, 0.0, 0.0]);
+const SURFACE_WHITE: Surface = surface_glossy(LinearColor::WHITE);
+
+#[allow(dead_code)]
+const SURFACE_BLACK: Surface = surface_glossy(LinearColor::BLACK);
 
 #[allow(dead_code)]
 const SURFACE_WHITE_C: Surface = Surface {
-    color: [0.2, 0.2, 0.2],
+    color: LinearColor::gray(0.2),
     ambient: AMBIENT,
     specular: SPECULAR,
     light: LIGHT,
     checked: true,
-    reflection: 0.5
+    reflection: 0.5,
+    reflection_color: LinearColor::WHITE,
+    emission: LinearColor::BLACK,
+    emission_strength: 0.0,
+    transmission: 0.0,
+    ior: Ior::Constant(1.0)
 };
 
 #[allow(dead_code)]
@@ -106,56 +204,68 @@ pub fn scene_sphere_occlusion_test() -> Scene {
     Scene {
         name: "Occlusion Test",
         camera: DEFAULT_CAMERA,
-        background: [0.0, 0.0, 0.0],
-        light: Light {
-            location: [5.0, 5.0, 5.0]
-        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(5.0, 5.0, 5.0))],
         objects: vec![
             Box::new(Sphere {
-                center: [1.5, 2.0, 0.0],
+                center: Point::new(1.5, 2.0, 0.0),
                 r: 0.7,
                 surface: SURFACE_ORANGE
             }),
             Box::new(Sphere {
-                center: [3.0, 0.0, 0.0],
+                center: Point::new(3.0, 0.0, 0.0),
                 r: 1.0,
                 surface: SURFACE_RED
             }),
             Box::new(Sphere {
-                center: [-3.0, 0.0, 0.0],
+                center: Point::new(-3.0, 0.0, 0.0),
                 r: 1.0,
                 surface: SURFACE_BLUE
             }),
             Box::new(Sphere {
-                center: [0.0, 0.0, 0.0],
+                center: Point::new(0.0, 0.0, 0.0),
                 r: 1.0,
                 surface: SURFACE_GREEN
             }),
             Box::new(Sphere {
-                center: [0.0, -4.0, 0.0],
+                center: Point::new(0.0, -4.0, 0.0),
                 r: 3.0,
                 surface: SURFACE_YELLOW
             }),
             Box::new(Sphere { // foreground sphere at back at list - proper occlusion required to make this visible
-                center: [-1.5, 2.0, 0.0],
+                center: Point::new(-1.5, 2.0, 0.0),
                 r: 0.7,
                 surface: SURFACE_PURPLE
             }),
         ],
         reflect_limit: REFLECT_LIMIT,
         oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
     }
 }
 
 #[allow(dead_code)]
 fn test_surface(light: f64, specular: f64) -> Surface {
     Surface {
-        color: [1.0, 0.0, 0.0],
+        color: LinearColor::new(1.0, 0.0, 0.0),
         ambient: AMBIENT,
         specular,
         light,
         checked: false,
-        reflection: 0.0
+        reflection: 0.0,
+        reflection_color: LinearColor::WHITE,
+        emission: LinearColor::BLACK,
+        emission_strength: 0.0,
+        transmission: 0.0,
+        ior: Ior::Constant(1.0)
     }
 }
 
@@ -165,21 +275,28 @@ pub fn scene_sphere_surface_test() -> Scene {
     Scene {
         name: "Surface Finish Test",
         camera: DEFAULT_CAMERA,
-        background: [0.0, 0.0, 0.0],
-        light: Light {
-            location: [5.0, 5.0, 5.0]
-        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(5.0, 5.0, 5.0))],
         objects: (0..25).map(| x | Box::new(Sphere {
-            center: [
+            center: Point::new(
                 0.0 + ((x % 5) - 2) as f64,
                 0.0,
-                0.0 + ((x / 5) - 2) as f64,
-            ],
+                0.0 + ((x / 5) - 2) as f64
+            ),
             r: 0.4,
             surface: test_surface((x % 5) as f64 / 5.0, (x / 5) as f64 / 5.0)
         }) as Box<dyn Hittable + Send + Sync>).collect::<Vec<_>>(),
         reflect_limit: REFLECT_LIMIT,
         oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
     }
 }
 
@@ -188,35 +305,30 @@ pub fn scene_one_sphere() -> Scene {
     Scene {
         name: "Single Sphere, Reflective Planes",
         camera: DEFAULT_CAMERA,
-        background: [0.0, 0.0, 0.0],
-        light: Light {
-            location: [10.0, 10.0, 10.0]
-        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(10.0, 10.0, 10.0))],
         objects: vec![
             Box::new(Sphere {
-                center: [0.0, 0.0, 0.0],
+                center: Point::new(0.0, 0.0, 0.0),
                 r: 1.0,
                 surface: SURFACE_ORANGE
             }),
 
-            Box::new(Plane {
-                normal: [1.0, 0.0, 0.0],
-                p0: [-2.0, 0.0, 0.0],
-                surface: SURFACE_WHITE_C
-            }),
-            Box::new(Plane {
-                normal: [0.0, 1.0, 0.0],
-                p0: [0.0, -2.0, 0.0],
-                surface: SURFACE_WHITE_C
-            }),
-            Box::new(Plane {
-                normal: [0.0, 0.0, 1.0],
-                p0: [0.0, 0.0, -2.0],
-                surface: SURFACE_WHITE_C
-            }),
+            Box::new(Plane::new(Normal::new(1.0, 0.0, 0.0), Point::new(-2.0, 0.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -2.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Plane::new(Normal::new(0.0, 0.0, 1.0), Point::new(0.0, 0.0, -2.0), SURFACE_WHITE_C)),
         ],
         reflect_limit: REFLECT_LIMIT,
         oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
     }
 }
 
@@ -225,34 +337,41 @@ pub fn scene_axis_spheres() -> Scene {
     Scene {
         name: "Axis Spheres",
         camera: DEFAULT_CAMERA,
-        background: [0.0, 0.0, 0.0],
-        light: Light {
-            location: [10.0, 10.0, 10.0]
-        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(10.0, 10.0, 10.0))],
         objects: vec![
             Box::new(Sphere {
-                center: [0.0, 0.0, 0.0],
+                center: Point::new(0.0, 0.0, 0.0),
                 r: 1.0,
                 surface: SURFACE_WHITE
             }),
             Box::new(Sphere {
-                center: [3.0, 0.0, 0.0],
+                center: Point::new(3.0, 0.0, 0.0),
                 r: 0.25,
                 surface: SURFACE_RED
             }),
             Box::new(Sphere {
-                center: [0.0, 3.0, 0.0],
+                center: Point::new(0.0, 3.0, 0.0),
                 r: 0.25,
                 surface: SURFACE_GREEN
             }),
             Box::new(Sphere {
-                center: [0.0, 0.0, 3.0],
+                center: Point::new(0.0, 0.0, 3.0),
                 r: 0.25,
                 surface: SURFACE_BLUE
             }),
         ],
         reflect_limit: REFLECT_LIMIT,
         oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
     }
 }
 
@@ -262,24 +381,494 @@ pub fn scene_ball_on_plane() -> Scene {
     Scene {
         name: "Ball on Plane",
         camera: DEFAULT_CAMERA,
-        background: [0.0, 0.0, 0.0],
-        light: Light {
-            location: [10.0, 10.0, 10.0]
-        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(10.0, 10.0, 10.0))],
         objects: vec![
             Box::new(Sphere {
-                center: [0.0, -2.0, -1.0],
+                center: Point::new(0.0, -2.0, -1.0),
                 r: 0.66,
                 surface: SURFACE_BLUE
             }),
-            Box::new(Plane {
-                normal: [0.0, 0.0, 1.0],
-                p0: [0.0, 0.0, -2.0],
-                surface: SURFACE_WHITE_C
+            Box::new(Plane::new(Normal::new(0.0, 0.0, 1.0), Point::new(0.0, 0.0, -2.0), SURFACE_WHITE_C)),
+        ],
+        reflect_limit: REFLECT_LIMIT,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
+    }
+}
+
+#[allow(dead_code)]
+pub fn scene_area_lights() -> Scene {
+    Scene {
+        name: "Area Lights",
+        camera: DEFAULT_CAMERA,
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(10.0, 10.0, 10.0))],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Sphere {
+                center: Point::new(-1.5, 0.0, 1.0),
+                r: 1.0,
+                surface: SURFACE_BLUE
+            }),
+            Box::new(Sphere { // glowing sphere doubling as a light
+                center: Point::new(1.5, 0.5, 1.5),
+                r: 0.4,
+                surface: emissive(SURFACE_YELLOW, LinearColor::new(1.0, 1.0, 0.6), 4.0)
+            }),
+            Box::new(Rectangle { // light panel standing upright, facing the scene
+                p0: Point::new(-4.0, -1.0, -3.0),
+                u: Vector::new(0.0, 2.0, 0.0),
+                v: Vector::new(0.0, 0.0, 2.0),
+                surface: emissive(SURFACE_WHITE, LinearColor::WHITE, 2.0)
+            }),
+            Box::new(TriangleMesh::new( // square pyramid
+                vec![
+                    Point::new(1.0, -1.0, -2.5),
+                    Point::new(3.0, -1.0, -2.5),
+                    Point::new(3.0, -1.0, -0.5),
+                    Point::new(1.0, -1.0, -0.5),
+                    Point::new(2.0, 1.0, -1.5),
+                ],
+                vec![[0, 1, 4], [1, 2, 4], [2, 3, 4], [3, 0, 4]],
+                SURFACE_GREEN
+            )),
+        ],
+        reflect_limit: REFLECT_LIMIT,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: Some(DEFAULT_AMBIENT_OCCLUSION),
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
+    }
+}
+
+// A brick lying on the floor with its center at the given point,
+// turned by angle radians about the vertical.
+fn brick(center: Point, angle: f64, surface: Surface) -> Transformed {
+    let transform = Matrix4::translation(center.to_vector())
+        * Matrix4::rotation(Vector::new(0.0, 1.0, 0.0), angle)
+        * Matrix4::scaling(Vector::new(1.6, 0.8, 0.8));
+
+    let unit_cube = TriangleMesh::cuboid(Point::new(-0.5, -0.5, -0.5), Point::new(0.5, 0.5, 0.5), surface);
+
+    Transformed::new(Box::new(unit_cube), transform).expect("Brick transform is invertible")
+}
+
+// A color given in sRGB hex, as color pickers show them.
+fn hex(color: &str) -> LinearColor {
+    LinearColor::from_hex(color).expect("Invalid hex color")
+}
+
+#[allow(dead_code)]
+pub fn scene_light_types() -> Scene {
+    Scene {
+        name: "Light Types",
+        camera: DEFAULT_CAMERA,
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![
+            Light::Directional { // low sun from the left
+                direction: Vector::new(1.0, -1.0, 0.3),
+                color: hex("#ffe7cb"),
+                intensity: 0.4,
+                angular_diameter: 0.1
+            },
+            Light::Spot {
+                location: Point::new(2.0, 6.0, 2.0),
+                direction: Vector::new(0.0, -1.0, 0.0),
+                color: LinearColor::WHITE,
+                intensity: 1.0,
+                attenuation: NO_ATTENUATION,
+                cone_angle: 0.35,
+                falloff_angle: 0.1
+            },
+            Light::Point {
+                location: Point::new(-3.0, 1.0, -3.0),
+                color: hex("#cbdaff"),
+                intensity: 6.0,
+                attenuation: INVERSE_SQUARE
+            },
+        ],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Sphere {
+                center: Point::new(2.0, 0.0, 2.0),
+                r: 1.0,
+                surface: SURFACE_ORANGE
+            }),
+            Box::new(Sphere {
+                center: Point::new(-2.0, 0.0, 0.0),
+                r: 1.0,
+                surface: SURFACE_GOLD
+            }),
+            Box::new(brick(Point::new(-1.5, -0.6, 2.8), 0.6, SURFACE_BLUE)),
+        ],
+        reflect_limit: REFLECT_LIMIT,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
+    }
+}
+
+// A polished metal, which tints what it reflects with its own color.
+#[allow(dead_code)]
+const fn metal(c: LinearColor) -> Surface {
+    Surface {
+        color: c,
+        ambient: 0.0,
+        specular: 0.8,
+        light: 0.1,
+        reflection: 0.8,
+        reflection_color: c,
+        .. SURFACE_BLACK
+    }
+}
+
+#[allow(dead_code)]
+const SURFACE_GOLD: Surface = metal(LinearColor::new(1.0, 0.71, 0.29));
+
+#[allow(dead_code)]
+const SURFACE_MIRROR: Surface = Surface {
+    color: LinearColor::BLACK,
+    ambient: 0.0,
+    specular: 0.5,
+    light: 0.0,
+    checked: false,
+    reflection: 1.0,
+    reflection_color: LinearColor::WHITE,
+    emission: LinearColor::BLACK,
+    emission_strength: 0.0,
+    transmission: 0.0,
+    ior: Ior::Constant(1.0)
+};
+
+#[allow(dead_code)]
+const SKY_GRADIENT: Environment = Environment::Gradient {
+    up: Vector::new(0.0, 1.0, 0.0),
+    zenith: LinearColor::new(0.15, 0.3, 0.8),
+    horizon: LinearColor::new(0.8, 0.85, 0.9),
+    ground: LinearColor::new(0.2, 0.15, 0.1),
+};
+
+fn environment_lit_scene(name: &'static str, environment: Environment) -> Scene {
+    Scene {
+        name,
+        camera: Camera {
+            location: Point::new(0.0, 2.0, 10.0),
+            point_at: Point::new(0.0, 0.0, 0.0),
+            u: Vector::new(10.0, 0.0, 0.0),
+            v: Vector::new(0.0, -10.0, 0.0)
+        },
+        environment,
+        fog: None,
+        volumes: vec![],
+        lights: vec![],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Sphere {
+                center: Point::new(-1.5, 0.0, 0.0),
+                r: 1.0,
+                surface: SURFACE_MIRROR
+            }),
+            Box::new(Sphere {
+                center: Point::new(1.5, 0.0, 0.0),
+                r: 1.0,
+                surface: SURFACE_WHITE
             }),
         ],
         reflect_limit: REFLECT_LIMIT,
         oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
     }
 }
 
+#[allow(dead_code)]
+pub fn scene_gradient_sky() -> Scene {
+    environment_lit_scene("Gradient Sky", SKY_GRADIENT)
+}
+
+// Lit by the equirectangular .hdr image named by the ENVIRONMENT_MAP
+// environment variable, falling back to the gradient sky.
+#[allow(dead_code)]
+pub fn scene_environment_map() -> Scene {
+    let environment = match env::var("ENVIRONMENT_MAP") {
+        Ok(path) => match EnvironmentMap::load(&path, 1.0) {
+            Ok(map) => Environment::Map(map),
+            Err(err) => {
+                println!("Cannot load environment map {}: {:?}", path, err);
+                SKY_GRADIENT
+            }
+        },
+        Err(_) => SKY_GRADIENT
+    };
+
+    environment_lit_scene("Environment Map", environment)
+}
+
+fn env_f64(name: &str, default: f64) -> f64 {
+    match env::var(name) {
+        Ok(val) => val.parse().unwrap_or(default),
+        Err(_) => default
+    }
+}
+
+// Outdoor daylight, with the sun placed by the SUN_ELEVATION and
+// SUN_AZIMUTH environment variables (in degrees) and the haziness of
+// the air set by TURBIDITY.
+#[allow(dead_code)]
+pub fn scene_physical_sky() -> Scene {
+    let sky = PhysicalSky {
+        sun_elevation: env_f64("SUN_ELEVATION", 30.0).to_radians(),
+        sun_azimuth: env_f64("SUN_AZIMUTH", 60.0).to_radians(),
+        turbidity: env_f64("TURBIDITY", 3.0),
+        intensity: 0.05,
+        ground: LinearColor::gray(0.05),
+    };
+
+    Scene {
+        lights: vec![sky.sun_light(1.0)],
+        .. environment_lit_scene("Physical Sky", Environment::Sky(sky))
+    }
+}
+
+// A spot light shining down through a cloud of haze, with a ball
+// casting a shaft of shadow through it, all seen through light fog.
+#[allow(dead_code)]
+pub fn scene_light_shafts() -> Scene {
+    Scene {
+        name: "Light Shafts",
+        camera: Camera {
+            location: Point::new(0.0, 1.0, 10.0),
+            point_at: Point::new(0.0, 1.0, 0.0),
+            u: Vector::new(10.0, 0.0, 0.0),
+            v: Vector::new(0.0, -10.0, 0.0)
+        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: Some(Fog {
+            density: 0.02,
+            color: LinearColor::new(0.05, 0.05, 0.06)
+        }),
+        volumes: vec![
+            Volume {
+                boundary: Box::new(Sphere {
+                    center: Point::new(0.0, 1.5, 0.0),
+                    r: 3.5,
+                    surface: SURFACE_WHITE
+                }),
+                density: Density::Uniform,
+                absorption: 0.02,
+                scattering: 0.15,
+                anisotropy: 0.3,
+                color: LinearColor::WHITE
+            },
+        ],
+        lights: vec![
+            Light::Spot {
+                location: Point::new(0.0, 6.0, 0.0),
+                direction: Vector::new(0.0, -1.0, 0.0),
+                color: LinearColor::WHITE,
+                intensity: 8.0,
+                attenuation: NO_ATTENUATION,
+                cone_angle: 0.5,
+                falloff_angle: 0.1
+            },
+        ],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(Sphere {
+                center: Point::new(0.5, 3.0, 0.0),
+                r: 0.5,
+                surface: SURFACE_RED
+            }),
+        ],
+        reflect_limit: REFLECT_LIMIT,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![
+            PostEffect::Bloom { threshold: 0.8, intensity: 0.25, radius: 0.015 },
+            PostEffect::Vignette { strength: 0.3 },
+        ],
+        color_space: COLOR_SPACE,
+    }
+}
+
+// A flint glass prism standing in front of a row of thin white lights,
+// which it spreads out into spectra when rendered with SPECTRAL=y.
+#[allow(dead_code)]
+pub fn scene_dispersion() -> Scene {
+    let bar = | x: f64 | -> Box<dyn Hittable + Sync + Send> {
+        Box::new(Rectangle {
+            p0: Point::new(x, -1.0, -4.0),
+            u: Vector::new(0.06, 0.0, 0.0),
+            v: Vector::new(0.0, 6.0, 0.0),
+            surface: emissive(SURFACE_BLACK, LinearColor::WHITE, 3.0)
+        })
+    };
+
+    Scene {
+        name: "Dispersion",
+        camera: Camera {
+            location: Point::new(0.0, 1.5, 10.0),
+            point_at: Point::new(0.0, 1.0, 0.0),
+            u: Vector::new(8.0, 0.0, 0.0),
+            v: Vector::new(0.0, -8.0, 0.0)
+        },
+        environment: Environment::Constant(LinearColor::BLACK),
+        fog: None,
+        volumes: vec![],
+        lights: vec![point_light(Point::new(3.0, 6.0, 6.0))],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+            Box::new(TriangleMesh::prism(
+                [Point::new(-1.2, -1.0, -0.6), Point::new(1.2, -1.0, -0.6), Point::new(0.0, -1.0, 1.4)],
+                Vector::new(0.0, 3.0, 0.0),
+                glass(SF11))),
+            Box::new(Sphere {
+                center: Point::new(2.6, -0.3, 2.0),
+                r: 0.7,
+                surface: glass(BK7)
+            }),
+            bar(-4.0),
+            bar(-3.0),
+            bar(-2.0),
+            bar(-1.0),
+            bar(0.0),
+            bar(1.0),
+            bar(2.0),
+            bar(3.0),
+        ],
+        reflect_limit: 4,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: 2,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
+    }
+}
+
+// A cloud made from fractal noise fading out towards the edges of its
+// box, or the raw voxel file named by the VOXEL_FILE environment
+// variable, if there is one.
+#[allow(dead_code)]
+pub fn scene_voxel_cloud() -> Scene {
+    let min = Point::new(-3.0, -0.5, -2.0);
+    let max = Point::new(3.0, 3.5, 2.0);
+
+    let noise_cloud = || VoxelGrid::from_fn([64, 48, 48], min, max, | p | {
+        let dx = p.x / 3.0;
+        let dy = (p.y - 1.5) / 2.0;
+        let dz = p.z / 2.0;
+        let falloff = 1.0 - (dx * dx + dy * dy + dz * dz).sqrt();
+
+        (fractal_noise(&Point::new(p.x * 1.5, p.y * 1.5, p.z * 1.5), 5, 7) * 2.0 - 1.0 + falloff).max(0.0)
+    });
+
+    let grid = match env::var("VOXEL_FILE") {
+        Ok(path) => match VoxelGrid::load_raw(&path, min, max) {
+            Ok(grid) => grid,
+            Err(err) => {
+                println!("Cannot load voxel file {}: {:?}", path, err);
+                noise_cloud()
+            }
+        },
+        Err(_) => noise_cloud()
+    };
+
+    Scene {
+        name: "Voxel Cloud",
+        camera: Camera {
+            location: Point::new(0.0, 1.5, 10.0),
+            point_at: Point::new(0.0, 1.0, 0.0),
+            u: Vector::new(10.0, 0.0, 0.0),
+            v: Vector::new(0.0, -10.0, 0.0)
+        },
+        environment: SKY_GRADIENT,
+        fog: None,
+        volumes: vec![
+            Volume {
+                boundary: Box::new(TriangleMesh::cuboid(min, max, SURFACE_WHITE)),
+                density: Density::Grid(grid),
+                absorption: 0.1,
+                scattering: 1.5,
+                anisotropy: 0.5,
+                color: LinearColor::WHITE
+            },
+        ],
+        lights: vec![
+            Light::Directional {
+                direction: Vector::new(-0.5, -1.0, -0.3),
+                color: LinearColor::WHITE,
+                intensity: 8.0,
+                angular_diameter: 0.0
+            },
+        ],
+        objects: vec![
+            Box::new(Plane::new(Normal::new(0.0, 1.0, 0.0), Point::new(0.0, -1.0, 0.0), SURFACE_WHITE_C)),
+        ],
+        reflect_limit: REFLECT_LIMIT,
+        oversample: OVERSAMPLE,
+        sampler: SAMPLER,
+        filter: FILTER,
+        adaptive: ADAPTIVE,
+        light_samples: LIGHT_SAMPLES,
+        ambient_occlusion: None,
+        post_effects: vec![],
+        color_space: COLOR_SPACE,
+    }
+}
+
+// Builds the scene with the given name, so that separate processes
+// rendering parts of the same image can each have their own copy.
+pub fn scene_by_name(name: &str) -> Option<Scene> {
+    let scene: fn() -> Scene = match name {
+        "Occlusion Test" => scene_sphere_occlusion_test,
+        "Surface Finish Test" => scene_sphere_surface_test,
+        "Single Sphere, Reflective Planes" => scene_one_sphere,
+        "Axis Spheres" => scene_axis_spheres,
+        "Ball on Plane" => scene_ball_on_plane,
+        "Area Lights" => scene_area_lights,
+        "Light Types" => scene_light_types,
+        "Gradient Sky" => scene_gradient_sky,
+        "Environment Map" => scene_environment_map,
+        "Physical Sky" => scene_physical_sky,
+        "Light Shafts" => scene_light_shafts,
+        "Voxel Cloud" => scene_voxel_cloud,
+        "Dispersion" => scene_dispersion,
+        _ => return None
+    };
+
+    Some(scene())
+}